@@ -0,0 +1,290 @@
+//! Runs a stream through its whole lifecycle against a live cluster, using
+//! only the crate's public API: it creates a mint, streams 60 tokens over 60
+//! seconds, withdraws twice, cancels and prints how the tokens ended up.
+//!
+//! Deploy the program, e.g. to a local `solana-test-validator` with
+//! `--bpf-program <PROGRAM_ID> target/deploy/streamflow_timelock.so`, then
+//!
+//! ```sh
+//! cargo run --example full_lifecycle -- <RPC_URL> <KEYPAIR_PATH> <PROGRAM_ID>
+//! ```
+//!
+//! The keypair pays for everything and is the stream sender.
+use std::{env, str::FromStr, thread::sleep, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    account::from_account,
+    commitment_config::CommitmentConfig,
+    instruction::Instruction,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    signature::Signer,
+    signer::keypair::{read_keypair_file, Keypair},
+    system_instruction,
+    sysvar::{self, clock::Clock},
+    transaction::Transaction,
+};
+use spl_associated_token_account::create_associated_token_account;
+
+use streamflow_timelock::instructions::{
+    cancel_stream_ix, create_stream_ix, withdraw_stream_ix, CancelStreamKeys, CreateStreamKeys,
+    WithdrawStreamKeys,
+};
+use streamflow_timelock::state::{StreamInstruction, TokenStreamData};
+use streamflow_timelock::utils::{derive_stream_accounts, encode_base10};
+
+const DECIMALS: u8 = 6;
+const DEPOSIT: u64 = 60_000_000;
+
+/// Where the deposit went, in base units
+pub struct Reconciliation {
+    pub deposited: u64,
+    pub withdrawn: [u64; 2],
+    pub paid_on_cancel: u64,
+    pub refunded: u64,
+    pub recipient_balance: u64,
+    pub escrow_balance: u64,
+}
+
+impl Reconciliation {
+    /// Whether every deposited token is accounted for
+    pub fn balances(&self) -> bool {
+        let paid = self.withdrawn[0] + self.withdrawn[1] + self.paid_on_cancel;
+        paid == self.recipient_balance
+            && paid + self.refunded == self.deposited
+            && self.escrow_balance == 0
+    }
+
+    pub fn print(&self) {
+        let rows = [
+            ("Deposited", self.deposited),
+            ("First withdrawal", self.withdrawn[0]),
+            ("Second withdrawal", self.withdrawn[1]),
+            ("Paid out on cancel", self.paid_on_cancel),
+            ("Refunded to sender", self.refunded),
+            ("Recipient balance", self.recipient_balance),
+            ("Left in escrow", self.escrow_balance),
+        ];
+
+        println!("{:<20} {:>14}", "", "Tokens");
+        for (name, amount) in rows {
+            println!(
+                "{:<20} {:>14}",
+                name,
+                encode_base10(amount, DECIMALS.into())
+            );
+        }
+    }
+}
+
+/// Create a stream from `payer` to a fresh recipient and take it through
+/// two withdrawals and a cancel.
+pub fn run(rpc_url: &str, payer: &Keypair, program_id: &Pubkey) -> Result<Reconciliation> {
+    let client = RpcClient::new_with_commitment(rpc_url.to_string(), CommitmentConfig::confirmed());
+    let recipient = Keypair::new();
+    let mint = Keypair::new();
+    let metadata = Keypair::new();
+
+    let accounts = derive_stream_accounts(
+        &metadata.pubkey(),
+        &payer.pubkey(),
+        &recipient.pubkey(),
+        &mint.pubkey(),
+        program_id,
+    );
+
+    println!("Creating mint {}", mint.pubkey());
+    let mint_rent = client.get_minimum_balance_for_rent_exemption(spl_token::state::Mint::LEN)?;
+    send(
+        &client,
+        payer,
+        &[
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint.pubkey(),
+                mint_rent,
+                spl_token::state::Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            spl_token::instruction::initialize_mint(
+                &spl_token::id(),
+                &mint.pubkey(),
+                &payer.pubkey(),
+                None,
+                DECIMALS,
+            )?,
+            create_associated_token_account(&payer.pubkey(), &payer.pubkey(), &mint.pubkey()),
+            spl_token::instruction::mint_to(
+                &spl_token::id(),
+                &mint.pubkey(),
+                &accounts.sender_tokens,
+                &payer.pubkey(),
+                &[],
+                DEPOSIT,
+            )?,
+        ],
+        &[&mint],
+    )?;
+
+    // Leave the transaction some time to land before the start
+    let now = chain_time(&client)?;
+    let params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 70,
+        deposited_amount: DEPOSIT,
+        total_amount: DEPOSIT,
+        period: 1,
+        stream_name: "Full lifecycle".to_string(),
+        ..Default::default()
+    };
+
+    println!("Creating stream {}", metadata.pubkey());
+    let ix = create_stream_ix(
+        program_id,
+        &params,
+        &CreateStreamKeys {
+            sender: payer.pubkey(),
+            sender_tokens: accounts.sender_tokens,
+            recipient: recipient.pubkey(),
+            recipient_tokens: accounts.recipient_tokens,
+            metadata: metadata.pubkey(),
+            mint: mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    send(&client, payer, &[ix], &[&metadata])?;
+
+    let withdraw_keys = WithdrawStreamKeys {
+        withdraw_authority: recipient.pubkey(),
+        sender: payer.pubkey(),
+        recipient: recipient.pubkey(),
+        recipient_tokens: accounts.recipient_tokens,
+        metadata: metadata.pubkey(),
+        mint: mint.pubkey(),
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    let mut withdrawn = [0; 2];
+    for (i, target) in [DEPOSIT / 4, DEPOSIT / 2].iter().enumerate() {
+        let available = wait_for_available(&client, &metadata.pubkey(), *target)?;
+        let before = token_balance(&client, &accounts.recipient_tokens)?;
+
+        println!("Withdrawing {}", encode_base10(available, DECIMALS.into()));
+        let ix = withdraw_stream_ix(program_id, available, &withdraw_keys);
+        send(&client, payer, &[ix], &[&recipient])?;
+
+        withdrawn[i] = token_balance(&client, &accounts.recipient_tokens)? - before;
+    }
+
+    let recipient_before = token_balance(&client, &accounts.recipient_tokens)?;
+    let sender_before = token_balance(&client, &accounts.sender_tokens)?;
+
+    println!("Cancelling");
+    let ix = cancel_stream_ix(
+        program_id,
+        &CancelStreamKeys {
+            cancel_authority: payer.pubkey(),
+            sender: payer.pubkey(),
+            sender_tokens: accounts.sender_tokens,
+            recipient: recipient.pubkey(),
+            recipient_tokens: accounts.recipient_tokens,
+            metadata: metadata.pubkey(),
+            mint: mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    send(&client, payer, &[ix], &[])?;
+
+    let recipient_balance = token_balance(&client, &accounts.recipient_tokens)?;
+    let escrow_balance = match client.get_account(&accounts.escrow_tokens) {
+        Ok(_) => token_balance(&client, &accounts.escrow_tokens)?,
+        // Closed on cancel
+        Err(_) => 0,
+    };
+
+    Ok(Reconciliation {
+        deposited: DEPOSIT,
+        withdrawn,
+        paid_on_cancel: recipient_balance - recipient_before,
+        refunded: token_balance(&client, &accounts.sender_tokens)? - sender_before,
+        recipient_balance,
+        escrow_balance,
+    })
+}
+
+/// Poll the stream until at least `target` is available for withdrawal,
+/// returning what is.
+fn wait_for_available(client: &RpcClient, metadata: &Pubkey, target: u64) -> Result<u64> {
+    loop {
+        let stream = TokenStreamData::unpack(&client.get_account_data(metadata)?)?;
+        let available = stream.available(chain_time(client)?);
+        println!(
+            "Available for withdrawal: {}",
+            encode_base10(available, DECIMALS.into())
+        );
+
+        if available >= target {
+            return Ok(available);
+        }
+        sleep(Duration::from_secs(2));
+    }
+}
+
+/// The cluster's clock, which the program vests by
+fn chain_time(client: &RpcClient) -> Result<u64> {
+    let clock: Clock = from_account(&client.get_account(&sysvar::clock::id())?)
+        .ok_or_else(|| anyhow!("Invalid clock sysvar"))?;
+    Ok(clock.unix_timestamp as u64)
+}
+
+fn token_balance(client: &RpcClient, token_account: &Pubkey) -> Result<u64> {
+    let data = client.get_account_data(token_account)?;
+    Ok(spl_token::state::Account::unpack(&data)?.amount)
+}
+
+fn send(
+    client: &RpcClient,
+    payer: &Keypair,
+    instructions: &[Instruction],
+    signers: &[&Keypair],
+) -> Result<()> {
+    let mut all_signers = vec![payer];
+    all_signers.extend_from_slice(signers);
+
+    let (recent_blockhash, _) = client.get_recent_blockhash()?;
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&payer.pubkey()),
+        &all_signers,
+        recent_blockhash,
+    );
+    client.send_and_confirm_transaction(&transaction)?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.len() != 3 {
+        bail!("Usage: full_lifecycle <RPC_URL> <KEYPAIR_PATH> <PROGRAM_ID>");
+    }
+
+    let payer = read_keypair_file(&args[1]).map_err(|e| anyhow!("{}", e))?;
+    let program_id = Pubkey::from_str(&args[2])?;
+
+    let reconciliation = run(&args[0], &payer, &program_id)?;
+    reconciliation.print();
+
+    if !reconciliation.balances() {
+        bail!("Tokens don't add up");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,75 @@
+// Fuzz the vesting math with random schedules and timestamps.
+//
+// The input is read as little-endian u64s, missing ones being zero:
+// created_at, start_time, end_time, deposited_amount, total_amount, period,
+// cliff, cliff_amount, release_rate and two timestamps to compare.
+// Schedules the create instruction would reject are skipped.
+//
+// The seeds in corpus/vesting are the schedules from the state unit tests.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use streamflow_timelock::state::{StreamInstruction, TokenStreamData};
+use streamflow_timelock::utils::duration_sanity;
+
+fuzz_target!(|data: &[u8]| {
+    let mut words = data
+        .chunks(8)
+        .map(|c| {
+            let mut word = [0u8; 8];
+            word[..c.len()].copy_from_slice(c);
+            u64::from_le_bytes(word)
+        })
+        .chain(std::iter::repeat(0));
+    let mut next = || words.next().unwrap();
+
+    let created_at = next();
+    let metadata = TokenStreamData {
+        created_at,
+        ix: StreamInstruction {
+            start_time: next(),
+            end_time: next(),
+            deposited_amount: next(),
+            total_amount: next(),
+            period: next(),
+            cliff: next(),
+            cliff_amount: next(),
+            release_rate: next(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let ix = &metadata.ix;
+    if ix.validate(created_at).is_err()
+        || !duration_sanity(created_at, ix.start_time, ix.end_time, ix.cliff)
+    {
+        return;
+    }
+
+    let (a, b) = (next(), next());
+    let (early, late) = (a.min(b), a.max(b));
+
+    let available_early = metadata.available(early);
+    let available_late = metadata.available(late);
+
+    assert!(available_early <= available_late, "available decreased");
+    assert!(
+        available_late <= ix.deposited_amount,
+        "available exceeds deposit"
+    );
+
+    for now in [early, late] {
+        if now < metadata.effective_start() {
+            assert_eq!(metadata.available(now), 0, "available before start");
+        }
+        // Recurring streams ignore the end time and run until the deposit is out
+        if now >= ix.end_time && ix.release_rate == 0 {
+            assert_eq!(
+                metadata.available(now),
+                ix.deposited_amount,
+                "not fully available at the end"
+            );
+        }
+    }
+});
@@ -0,0 +1,525 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The vesting math of streamflow-timelock streams, on plain integers and
+//! without `std`, so it runs anywhere the amounts need showing, e.g. on a
+//! hardware wallet. The program's `TokenStreamData` delegates to it.
+#![cfg_attr(not(test), no_std)]
+
+use core::convert::TryFrom;
+
+/// Errors of the schedule math, converted to program errors by the program.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MathError {
+    /// The new end time is before the current one
+    CannotShortenStream,
+    /// The new end time is too far after the start
+    InvalidTimestamps,
+    /// The amounts don't fit a `u64`
+    InvalidAmounts,
+    /// Nothing is streamed after the cliff, so there's no rate to keep
+    NoRateToKeep,
+    /// The period is zero
+    InvalidPeriod,
+    /// The stream runs for longer than `MAX_DURATION` after its start
+    DurationTooLong,
+}
+
+/// Longest allowed duration of a stream, from start to end
+pub const MAX_DURATION: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Check that a stream starting at `start_time` ends (or runs out of funds)
+/// at most `MAX_DURATION` later, at `end_time`.
+pub fn validate_end_time(start_time: u64, end_time: u64) -> Result<(), MathError> {
+    if end_time > start_time.saturating_add(MAX_DURATION) {
+        return Err(MathError::DurationTooLong);
+    }
+
+    Ok(())
+}
+
+/// The parts of a stream its vesting depends on, as stored in its metadata.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Schedule {
+    /// Timestamp when the stream was created
+    pub created_at: u64,
+    /// Timestamp when the tokens start vesting
+    pub start_time: u64,
+    /// Timestamp when all tokens are fully vested
+    pub end_time: u64,
+    /// Amount of funds deposited
+    pub deposited_amount: u64,
+    /// Total amount of funds the stream is for
+    pub total_amount: u64,
+    /// Time step (period) in seconds per which the vesting occurs
+    pub period: u64,
+    /// Vesting contract "cliff" timestamp, 0 for none
+    pub cliff: u64,
+    /// Amount unlocked at the "cliff" timestamp
+    pub cliff_amount: u64,
+    /// Amount released every period, 0 for linear streams
+    pub release_rate: u64,
+}
+
+impl Schedule {
+    /// Timestamp from which the stream starts unlocking periods: the cliff
+    /// when there is one, otherwise the start time, but never before the
+    /// stream was created so nothing vests retroactively.
+    pub fn effective_start(&self) -> u64 {
+        let start = if self.cliff > 0 {
+            self.cliff
+        } else {
+            self.start_time
+        };
+
+        start.max(self.created_at)
+    }
+
+    /// Total amount unlocked at `now`, capped at the deposited amount so a
+    /// final partial period releases the remainder.
+    pub fn vested(&self, now: u64) -> u64 {
+        if self.effective_start() > now {
+            return 0;
+        }
+
+        // Ignore end date when recurring
+        if now >= self.end_time && self.release_rate == 0 {
+            return self.deposited_amount;
+        }
+
+        let periods_passed = self.periods_passed(now);
+        let streamed = if self.release_rate > 0 {
+            periods_passed.saturating_mul(self.release_rate)
+        } else {
+            // What's streamed after the cliff over the whole periods passed,
+            // before the end, which is after the effective start here. Metadata
+            // written before validation may have a cliff amount above the total.
+            let elapsed = periods_passed * self.period;
+            let streamed = self.total_amount.saturating_sub(self.cliff_amount) as u128
+                * elapsed as u128
+                / (self.end_time - self.effective_start()) as u128;
+            streamed as u64
+        };
+
+        streamed
+            .saturating_add(self.cliff_amount)
+            .min(self.deposited_amount)
+    }
+
+    /// Number of whole periods passed since the effective start at `now`.
+    pub fn periods_passed(&self, now: u64) -> u64 {
+        let cliff = self.effective_start();
+
+        if now < cliff {
+            return 0;
+        }
+
+        (now - cliff) / self.period
+    }
+
+    /// Timestamp of the last period boundary at or before `now`, i.e. up to
+    /// which point funds are unlocked. Before the effective start that's the
+    /// start itself, and past the end of a non-recurring stream it's `end_time`.
+    pub fn period_boundary(&self, now: u64) -> u64 {
+        if now >= self.end_time && self.release_rate == 0 {
+            return self.end_time;
+        }
+
+        self.effective_start() + self.periods_passed(now) * self.period
+    }
+
+    /// Timestamp when the stream can be closed by anyone: `end_time` when
+    /// the deposit covers the total, otherwise when the funds run out.
+    pub fn closable(&self) -> u64 {
+        let cliff_time = self.effective_start();
+
+        // Deposit smaller then cliff amount, cancelable at cliff
+        if self.deposited_amount < self.cliff_amount {
+            return cliff_time;
+        }
+
+        // Nothing streamed after the cliff, or no time left to stream it in,
+        // everything unlocks by the end or the cliff, whichever comes last
+        let (amount, seconds) = match self.rate() {
+            Ok((amount, seconds)) if amount > 0 => (amount, seconds),
+            _ => return self.end_time.max(cliff_time),
+        };
+
+        // Seconds till account runs out of available funds at the exact rate,
+        // which may be less than a token a second, +1 as ceil (integer)
+        let seconds_left =
+            (self.deposited_amount - self.cliff_amount) as u128 * seconds as u128 / amount as u128;
+        let seconds_left = u64::try_from(seconds_left)
+            .unwrap_or(u64::MAX)
+            .saturating_add(1);
+        let closable_at = cliff_time.saturating_add(seconds_left);
+
+        // closable_at time, ignore end time when recurring
+        if closable_at > self.end_time && self.release_rate == 0 {
+            self.end_time
+        } else {
+            closable_at
+        }
+    }
+
+    /// The total a stream moved to `end_time` needs to keep releasing the
+    /// same amount per period. Recurring streams need enough for every
+    /// period started before `end_time`, linear ones have their total
+    /// scaled up along with the duration (rounded down).
+    pub fn total_to_extend(&self, end_time: u64) -> Result<u64, MathError> {
+        if end_time < self.end_time {
+            return Err(MathError::CannotShortenStream);
+        }
+
+        validate_end_time(self.start_time, end_time)?;
+
+        let start = self.effective_start();
+        if self.release_rate > 0 {
+            let periods = end_time.saturating_sub(start).div_ceil(self.period);
+            return Ok(periods
+                .saturating_mul(self.release_rate)
+                .saturating_add(self.cliff_amount));
+        }
+
+        if self.end_time == start {
+            return Err(MathError::NoRateToKeep);
+        }
+
+        let streamed = self.total_amount.saturating_sub(self.cliff_amount) as u128
+            * (end_time - start) as u128
+            / (self.end_time - start) as u128;
+        u64::try_from(streamed + self.cliff_amount as u128).map_err(|_| MathError::InvalidAmounts)
+    }
+
+    /// Amount released per second after the cliff, rounded down: the release
+    /// rate over the period for recurring streams, what's streamed after the
+    /// cliff over the seconds it takes for linear ones. Add
+    /// `rate_per_second_remainder` to get the exact rate.
+    pub fn rate_per_second(&self) -> Result<u64, MathError> {
+        let (amount, seconds) = self.rate()?;
+        Ok(amount / seconds)
+    }
+
+    /// What `rate_per_second` floors away, in the same units as the amount:
+    /// the exact rate is `rate_per_second() + remainder / seconds`, with
+    /// `seconds` being the period for recurring streams and the time from
+    /// the effective start to the end for linear ones.
+    pub fn rate_per_second_remainder(&self) -> Result<u64, MathError> {
+        let (amount, seconds) = self.rate()?;
+        Ok(amount % seconds)
+    }
+
+    fn rate(&self) -> Result<(u64, u64), MathError> {
+        if self.period == 0 {
+            return Err(MathError::InvalidPeriod);
+        }
+
+        if self.release_rate > 0 {
+            return Ok((self.release_rate, self.period));
+        }
+
+        let start = self.effective_start();
+        if self.end_time <= start {
+            return Err(MathError::NoRateToKeep);
+        }
+
+        Ok((
+            self.total_amount.saturating_sub(self.cliff_amount),
+            self.end_time - start,
+        ))
+    }
+
+    /// `amount`'s share of `x`, in proportion to the deposit, rounded down.
+    pub fn share_of(&self, x: u64, amount: u64) -> u64 {
+        (x as u128 * amount as u128 / self.deposited_amount as u128) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_end_time, MathError, Schedule, MAX_DURATION};
+
+    fn linear() -> Schedule {
+        Schedule {
+            start_time: 100,
+            end_time: 1100,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            period: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_vested() {
+        let schedule = linear();
+        assert_eq!(schedule.vested(99), 0);
+        assert_eq!(schedule.vested(199), 0);
+        assert_eq!(schedule.vested(200), 100);
+        assert_eq!(schedule.vested(650), 500);
+        assert_eq!(schedule.vested(1100), 1000);
+        assert_eq!(schedule.vested(u64::MAX), 1000);
+
+        // Underfunded, capped at the deposit
+        let schedule = Schedule {
+            deposited_amount: 300,
+            ..linear()
+        };
+        assert_eq!(schedule.vested(500), 300);
+        assert_eq!(schedule.closable(), 401);
+
+        // Recurring, the end is ignored and the remainder released last
+        let schedule = Schedule {
+            deposited_amount: 250,
+            release_rate: 100,
+            ..linear()
+        };
+        assert_eq!(schedule.vested(300), 200);
+        assert_eq!(schedule.vested(400), 250);
+        assert_eq!(schedule.vested(5000), 250);
+        assert_eq!(schedule.closable(), 351);
+
+        // Funded past the u64 horizon, saturates instead of overflowing
+        let schedule = Schedule {
+            deposited_amount: u64::MAX,
+            total_amount: u64::MAX,
+            period: 1,
+            release_rate: 1,
+            ..linear()
+        };
+        assert_eq!(schedule.closable(), u64::MAX);
+    }
+
+    #[test]
+    fn test_vested_integer_math() {
+        // Exact where an f64 of the total would round it up a token
+        let schedule = Schedule {
+            start_time: 0,
+            end_time: 300,
+            deposited_amount: 3 * (1 << 53) + 3,
+            total_amount: 3 * (1 << 53) + 3,
+            period: 100,
+            ..Default::default()
+        };
+        assert_eq!(schedule.vested(100), (1 << 53) + 1);
+        assert_eq!(schedule.vested(299), 2 * ((1 << 53) + 1));
+
+        // Metadata from before validation, with a cliff amount above the
+        // total, only ever vests the cliff amount, capped at the deposit
+        let schedule = Schedule {
+            cliff: 200,
+            cliff_amount: 2000,
+            ..linear()
+        };
+        assert_eq!(schedule.vested(199), 0);
+        assert_eq!(schedule.vested(500), 1000);
+        assert_eq!(schedule.total_to_extend(1600), Ok(2000));
+
+        // Recurring streams never look at the end, even before the start
+        let schedule = Schedule {
+            created_at: 2000,
+            release_rate: 100,
+            ..linear()
+        };
+        assert_eq!(schedule.vested(2100), 100);
+    }
+
+    #[test]
+    fn test_closable_below_a_token_a_second() {
+        // 1 token every 10 seconds, runs out after 5 periods
+        let recurring = Schedule {
+            deposited_amount: 5,
+            total_amount: 5,
+            period: 10,
+            release_rate: 1,
+            ..linear()
+        };
+        assert_eq!(recurring.rate_per_second(), Ok(0));
+        assert_eq!(recurring.closable(), 151);
+
+        // 100 tokens over 1000 seconds, half of it deposited
+        let linear_slow = Schedule {
+            deposited_amount: 50,
+            total_amount: 100,
+            ..linear()
+        };
+        assert_eq!(linear_slow.rate_per_second(), Ok(0));
+        assert_eq!(linear_slow.closable(), 601);
+        let funded = Schedule {
+            deposited_amount: 100,
+            ..linear_slow
+        };
+        assert_eq!(funded.closable(), 1100);
+
+        // Nothing streamed after the cliff
+        let cliff_only = Schedule {
+            cliff: 300,
+            cliff_amount: 1000,
+            ..linear()
+        };
+        assert_eq!(cliff_only.closable(), 1100);
+
+        // Created after the end, nothing vests before creation
+        let late = Schedule {
+            created_at: 2000,
+            ..linear()
+        };
+        assert_eq!(late.closable(), 2000);
+    }
+
+    #[test]
+    fn test_validate_end_time() {
+        assert_eq!(validate_end_time(100, 100 + MAX_DURATION), Ok(()));
+        assert_eq!(
+            validate_end_time(100, 101 + MAX_DURATION),
+            Err(MathError::DurationTooLong)
+        );
+        assert_eq!(validate_end_time(u64::MAX - 1, u64::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_cliff_and_lock() {
+        let schedule = Schedule {
+            cliff: 300,
+            cliff_amount: 200,
+            ..linear()
+        };
+        assert_eq!(schedule.effective_start(), 300);
+        assert_eq!(schedule.vested(299), 0);
+        assert_eq!(schedule.vested(300), 200);
+        assert_eq!(schedule.vested(400), 300);
+        assert_eq!(schedule.period_boundary(450), 400);
+
+        let lock = Schedule {
+            cliff: 1100,
+            cliff_amount: 1000,
+            ..linear()
+        };
+        assert_eq!(lock.vested(1099), 0);
+        assert_eq!(lock.vested(1100), 1000);
+        assert_eq!(lock.closable(), 1100);
+    }
+
+    #[test]
+    fn test_created_after_start() {
+        let schedule = Schedule {
+            created_at: 150,
+            ..linear()
+        };
+        assert_eq!(schedule.effective_start(), 150);
+        assert_eq!(schedule.periods_passed(249), 0);
+        assert_eq!(schedule.periods_passed(250), 1);
+        assert_eq!(schedule.period_boundary(1000), 950);
+        assert_eq!(schedule.period_boundary(2000), 1100);
+    }
+
+    #[test]
+    fn test_total_to_extend() {
+        let schedule = linear();
+        assert_eq!(schedule.total_to_extend(1600), Ok(1500));
+        assert_eq!(
+            schedule.total_to_extend(1099),
+            Err(MathError::CannotShortenStream)
+        );
+        assert_eq!(
+            schedule.total_to_extend(101 + MAX_DURATION),
+            Err(MathError::DurationTooLong)
+        );
+
+        let recurring = Schedule {
+            release_rate: 30,
+            cliff_amount: 10,
+            ..linear()
+        };
+        // 16 periods, the last one started
+        assert_eq!(recurring.total_to_extend(1650), Ok(16 * 30 + 10));
+
+        // Near the end of time, the period count doesn't overflow
+        let late = Schedule {
+            start_time: u64::MAX - 1000,
+            end_time: u64::MAX - 500,
+            ..recurring
+        };
+        assert_eq!(late.total_to_extend(u64::MAX), Ok(10 * 30 + 10));
+
+        let lock = Schedule {
+            cliff: 1100,
+            cliff_amount: 1000,
+            ..linear()
+        };
+        assert_eq!(lock.total_to_extend(1600), Err(MathError::NoRateToKeep));
+    }
+
+    #[test]
+    fn test_rate_per_second() {
+        // 1000 over 1000 seconds
+        let schedule = linear();
+        assert_eq!(schedule.rate_per_second(), Ok(1));
+        assert_eq!(schedule.rate_per_second_remainder(), Ok(0));
+
+        // 30 every 100 seconds, 0 whole tokens a second
+        let recurring = Schedule {
+            release_rate: 30,
+            ..linear()
+        };
+        assert_eq!(recurring.rate_per_second(), Ok(0));
+        assert_eq!(recurring.rate_per_second_remainder(), Ok(30));
+
+        // 250 every 100 seconds, 2.5 a second
+        let recurring = Schedule {
+            release_rate: 250,
+            ..linear()
+        };
+        assert_eq!(recurring.rate_per_second(), Ok(2));
+        assert_eq!(recurring.rate_per_second_remainder(), Ok(50));
+
+        // 800 after the cliff over 800 seconds
+        let cliff = Schedule {
+            cliff: 300,
+            cliff_amount: 200,
+            total_amount: 1003,
+            ..linear()
+        };
+        assert_eq!(cliff.rate_per_second(), Ok(1));
+        assert_eq!(cliff.rate_per_second_remainder(), Ok(3));
+
+        let lock = Schedule {
+            cliff: 1100,
+            cliff_amount: 1000,
+            ..linear()
+        };
+        assert_eq!(lock.rate_per_second(), Err(MathError::NoRateToKeep));
+
+        let zero = Schedule {
+            period: 0,
+            release_rate: 30,
+            ..linear()
+        };
+        assert_eq!(zero.rate_per_second(), Err(MathError::InvalidPeriod));
+        assert_eq!(
+            zero.rate_per_second_remainder(),
+            Err(MathError::InvalidPeriod)
+        );
+    }
+
+    #[test]
+    fn test_share_of() {
+        let schedule = linear();
+        assert_eq!(schedule.share_of(1000, 300), 300);
+        assert_eq!(schedule.share_of(7, 300), 2);
+        assert_eq!(schedule.share_of(u64::MAX, 1000), u64::MAX);
+    }
+}
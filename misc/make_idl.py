@@ -28,7 +28,8 @@ from subprocess import run
 files = ["src/state.rs"]
 skips = [
     "InitializeAccounts", "WithdrawAccounts", "CancelAccounts",
-    "TransferAccounts", "TopUpAccounts"
+    "TransferAccounts", "TopUpAccounts", "LabelsAccounts",
+    "UpdatePermissionsAccounts"
 ]
 structs = {}
 
@@ -66,6 +67,10 @@ def lookup_layout(t, n):
         return f"['{n}', 'u8'],"
     if t == "String":
         return f"['{n}', 'string'],"
+    if t == "Vec<Pubkey>":
+        return f"['{n}', [[32]]],"
+    if t == "Vec<String>":
+        return f"['{n}', ['string']],"
 
     return None
 
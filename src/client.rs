@@ -0,0 +1,425 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Off-chain helpers fetching and decoding stream metadata, built with the
+//! `rpc-client` feature and never for BPF.
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    client_error::ClientError,
+    rpc_client::RpcClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::RpcFilterType,
+};
+use solana_sdk::{account::Account, pubkey::Pubkey};
+use thiserror::Error;
+
+use crate::{
+    query::StreamFilter,
+    state::{TokenStreamData, STRM_MAGIC},
+};
+
+#[derive(Error, Debug)]
+pub enum FetchError {
+    #[error("Account not found")]
+    NotFound,
+
+    #[error("Account is not a stream of this program")]
+    NotAStream,
+
+    #[error("Unsupported metadata version {0}")]
+    UnsupportedVersion(u64),
+
+    #[error(transparent)]
+    Rpc(#[from] ClientError),
+}
+
+/// Where streams are fetched from. Implemented for `RpcClient`, and small
+/// enough to implement over anything else holding accounts, e.g. in tests.
+pub trait AccountSource {
+    /// The account at `pubkey`, if there is one.
+    fn account(&self, pubkey: &Pubkey) -> Result<Option<Account>, FetchError>;
+
+    /// Accounts owned by `program_id` passing all of `filters`.
+    fn program_accounts(
+        &self,
+        program_id: &Pubkey,
+        filters: Vec<RpcFilterType>,
+    ) -> Result<Vec<(Pubkey, Account)>, FetchError>;
+}
+
+impl AccountSource for RpcClient {
+    fn account(&self, pubkey: &Pubkey) -> Result<Option<Account>, FetchError> {
+        Ok(self
+            .get_account_with_commitment(pubkey, self.commitment())?
+            .value)
+    }
+
+    fn program_accounts(
+        &self,
+        program_id: &Pubkey,
+        filters: Vec<RpcFilterType>,
+    ) -> Result<Vec<(Pubkey, Account)>, FetchError> {
+        let config = RpcProgramAccountsConfig {
+            filters: Some(filters),
+            account_config: RpcAccountInfoConfig {
+                encoding: Some(UiAccountEncoding::Base64),
+                ..Default::default()
+            },
+            with_context: None,
+        };
+
+        Ok(self.get_program_accounts_with_config(program_id, config)?)
+    }
+}
+
+/// Decode a fetched account as stream metadata of `program_id`. Streams
+/// of an older layout than the current one are `UnsupportedVersion`.
+pub fn decode_stream(
+    program_id: &Pubkey,
+    account: &Account,
+) -> Result<TokenStreamData, FetchError> {
+    // Registries, labels and the like are told apart by their size
+    if account.owner != *program_id {
+        return Err(FetchError::NotAStream);
+    }
+
+    let stream = TokenStreamData::unpack(&account.data).map_err(|_| FetchError::NotAStream)?;
+    if stream.magic != STRM_MAGIC {
+        return Err(FetchError::UnsupportedVersion(stream.magic));
+    }
+
+    Ok(stream)
+}
+
+/// Fetch the stream with its metadata at `metadata`.
+pub fn fetch_stream(
+    source: &impl AccountSource,
+    program_id: &Pubkey,
+    metadata: &Pubkey,
+) -> Result<TokenStreamData, FetchError> {
+    let account = source.account(metadata)?.ok_or(FetchError::NotFound)?;
+    decode_stream(program_id, &account)
+}
+
+/// Fetch every stream sent by `sender`, keyed by their metadata address.
+pub fn fetch_streams_by_sender(
+    source: &impl AccountSource,
+    program_id: &Pubkey,
+    sender: &Pubkey,
+) -> Result<Vec<(Pubkey, TokenStreamData)>, FetchError> {
+    fetch_streams_matching(source, program_id, &StreamFilter::new().sender(*sender))
+}
+
+/// Fetch every stream to `recipient`, keyed by their metadata address.
+pub fn fetch_streams_by_recipient(
+    source: &impl AccountSource,
+    program_id: &Pubkey,
+    recipient: &Pubkey,
+) -> Result<Vec<(Pubkey, TokenStreamData)>, FetchError> {
+    fetch_streams_matching(
+        source,
+        program_id,
+        &StreamFilter::new().recipient(*recipient),
+    )
+}
+
+/// Fetch every stream matching `filter`, keyed by their metadata address.
+/// Other program accounts (registries, counters, labels) that happen to
+/// pass the memcmp filters are skipped, and so are streams of an older
+/// layout, which `fetch_stream` reports as `UnsupportedVersion`.
+pub fn fetch_streams_matching(
+    source: &impl AccountSource,
+    program_id: &Pubkey,
+    filter: &StreamFilter,
+) -> Result<Vec<(Pubkey, TokenStreamData)>, FetchError> {
+    let mut streams = vec![];
+    for (pubkey, account) in source.program_accounts(program_id, filter.rpc_filters())? {
+        match decode_stream(program_id, &account) {
+            Ok(stream) if filter.matches(&stream) => streams.push((pubkey, stream)),
+            Ok(_) => continue,
+            Err(FetchError::NotAStream | FetchError::UnsupportedVersion(_)) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use borsh::BorshSerialize;
+
+    use super::*;
+    use crate::state::{
+        StreamInstruction, StreamLabels, StreamRegistry, LABELS_SIZE, LEGACY_VERSION,
+        METADATA_MIN_SIZE, REGISTRY_SIZE, SENDER_OFFSET,
+    };
+
+    #[derive(Default)]
+    struct Accounts(HashMap<Pubkey, Account>);
+
+    impl AccountSource for Accounts {
+        fn account(&self, pubkey: &Pubkey) -> Result<Option<Account>, FetchError> {
+            Ok(self.0.get(pubkey).cloned())
+        }
+
+        fn program_accounts(
+            &self,
+            program_id: &Pubkey,
+            filters: Vec<RpcFilterType>,
+        ) -> Result<Vec<(Pubkey, Account)>, FetchError> {
+            Ok(self
+                .0
+                .iter()
+                .filter(|(_, a)| {
+                    a.owner == *program_id
+                        && filters.iter().all(|f| match f {
+                            RpcFilterType::Memcmp(m) => m.bytes_match(&a.data),
+                            RpcFilterType::DataSize(size) => a.data.len() as u64 == *size,
+                        })
+                })
+                .map(|(k, a)| (*k, a.clone()))
+                .collect())
+        }
+    }
+
+    impl Accounts {
+        fn insert(&mut self, owner: &Pubkey, data: Vec<u8>) -> Pubkey {
+            let pubkey = Pubkey::new_unique();
+            let account = Account {
+                owner: *owner,
+                data,
+                ..Default::default()
+            };
+            self.0.insert(pubkey, account);
+            pubkey
+        }
+    }
+
+    fn stream(sender: &Pubkey, recipient: &Pubkey) -> TokenStreamData {
+        TokenStreamData {
+            magic: STRM_MAGIC,
+            sender: *sender,
+            recipient: *recipient,
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 200,
+                deposited_amount: 100,
+                total_amount: 100,
+                period: 1,
+                stream_name: "Fetched".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fetch_stream() {
+        let program_id = Pubkey::new_unique();
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut accounts = Accounts::default();
+
+        let expected = stream(&alice, &bob);
+        let metadata = accounts.insert(&program_id, expected.try_to_vec().unwrap());
+        assert_eq!(
+            fetch_stream(&accounts, &program_id, &metadata).unwrap(),
+            expected
+        );
+
+        assert!(matches!(
+            fetch_stream(&accounts, &program_id, &Pubkey::new_unique()),
+            Err(FetchError::NotFound)
+        ));
+
+        let foreign = accounts.insert(&Pubkey::new_unique(), expected.try_to_vec().unwrap());
+        assert!(matches!(
+            fetch_stream(&accounts, &program_id, &foreign),
+            Err(FetchError::NotAStream)
+        ));
+
+        let short = accounts.insert(&program_id, vec![0; METADATA_MIN_SIZE - 1]);
+        assert!(matches!(
+            fetch_stream(&accounts, &program_id, &short),
+            Err(FetchError::NotAStream)
+        ));
+
+        let mut older = stream(&alice, &bob);
+        older.magic = LEGACY_VERSION - 1;
+        let older = accounts.insert(&program_id, older.try_to_vec().unwrap());
+        assert!(matches!(
+            fetch_stream(&accounts, &program_id, &older),
+            Err(FetchError::UnsupportedVersion(v)) if v == LEGACY_VERSION - 1
+        ));
+
+        let mut unknown = stream(&alice, &bob);
+        unknown.magic = LEGACY_VERSION + 1;
+        let unknown = accounts.insert(&program_id, unknown.try_to_vec().unwrap());
+        assert!(matches!(
+            fetch_stream(&accounts, &program_id, &unknown),
+            Err(FetchError::NotAStream)
+        ));
+
+        // Legacy streams with the current layout read as current ones
+        let mut legacy = stream(&alice, &bob);
+        legacy.magic = LEGACY_VERSION;
+        let legacy = accounts.insert(&program_id, legacy.try_to_vec().unwrap());
+        assert_eq!(
+            fetch_stream(&accounts, &program_id, &legacy).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_fetch_skips_other_accounts() {
+        let program_id = Pubkey::new_unique();
+        let (alice, bob) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let mut accounts = Accounts::default();
+
+        let open = accounts.insert(&program_id, stream(&alice, &bob).try_to_vec().unwrap());
+        let mut older = stream(&alice, &bob);
+        older.magic = LEGACY_VERSION - 1;
+        accounts.insert(&program_id, older.try_to_vec().unwrap());
+
+        // A registry and labels as the program writes them into their PDAs
+        let mut registry = vec![0; REGISTRY_SIZE];
+        StreamRegistry {
+            streams: vec![open],
+        }
+        .pack(&mut registry)
+        .unwrap();
+        let registry = accounts.insert(&program_id, registry);
+        let mut labels = vec![0; LABELS_SIZE];
+        StreamLabels::default().pack(&mut labels).unwrap();
+        let labels = accounts.insert(&program_id, labels);
+        let mut set_labels = vec![0; LABELS_SIZE];
+        StreamLabels {
+            keys: vec!["invoice".to_string()],
+            values: vec!["42".to_string()],
+        }
+        .pack(&mut set_labels)
+        .unwrap();
+        let set_labels = accounts.insert(&program_id, set_labels);
+
+        for key in [registry, labels, set_labels] {
+            assert!(matches!(
+                fetch_stream(&accounts, &program_id, &key),
+                Err(FetchError::NotAStream)
+            ));
+        }
+
+        // No memcmp at all, every program account is decoded
+        let streams =
+            fetch_streams_matching(&accounts, &program_id, &StreamFilter::new().canceled(false))
+                .unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].0, open);
+    }
+
+    #[test]
+    fn test_fetch_streams_by_key() {
+        let program_id = Pubkey::new_unique();
+        let (alice, bob, carol) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut accounts = Accounts::default();
+
+        let to_bob = accounts.insert(&program_id, stream(&alice, &bob).try_to_vec().unwrap());
+        let to_carol = accounts.insert(&program_id, stream(&alice, &carol).try_to_vec().unwrap());
+        accounts.insert(&program_id, stream(&bob, &carol).try_to_vec().unwrap());
+        // Matches the filter but isn't a stream
+        let mut data = vec![0; SENDER_OFFSET + 32];
+        data[SENDER_OFFSET..].copy_from_slice(alice.as_ref());
+        accounts.insert(&program_id, data);
+
+        let mut sent: Vec<Pubkey> = fetch_streams_by_sender(&accounts, &program_id, &alice)
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        sent.sort();
+        let mut expected = vec![to_bob, to_carol];
+        expected.sort();
+        assert_eq!(sent, expected);
+
+        let received = fetch_streams_by_recipient(&accounts, &program_id, &bob).unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, to_bob);
+        assert_eq!(received[0].1.sender, alice);
+
+        assert!(fetch_streams_by_recipient(&accounts, &program_id, &alice)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_fetch_streams_matching() {
+        let program_id = Pubkey::new_unique();
+        let (alice, bob, mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let mut accounts = Accounts::default();
+
+        let mut open = stream(&alice, &bob);
+        open.mint = mint;
+        let open = accounts.insert(&program_id, open.try_to_vec().unwrap());
+        let mut canceled = stream(&alice, &bob);
+        canceled.mint = mint;
+        canceled.canceled_at = 150;
+        let canceled = accounts.insert(&program_id, canceled.try_to_vec().unwrap());
+        // Same parties, other mint
+        accounts.insert(&program_id, stream(&alice, &bob).try_to_vec().unwrap());
+        // Same mint, stored before the magic was
+        let mut legacy = stream(&alice, &bob);
+        legacy.mint = mint;
+        legacy.magic = 0;
+        let legacy = accounts.insert(&program_id, legacy.try_to_vec().unwrap());
+
+        let keys = |filter: StreamFilter| {
+            let mut keys: Vec<Pubkey> = fetch_streams_matching(&accounts, &program_id, &filter)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect();
+            keys.sort();
+            keys
+        };
+        let sorted = |mut keys: Vec<Pubkey>| {
+            keys.sort();
+            keys
+        };
+
+        let to_bob = StreamFilter::new().recipient(bob).mint(mint);
+        assert_eq!(keys(to_bob.clone()), sorted(vec![open, canceled, legacy]));
+        assert_eq!(
+            keys(to_bob.clone().magic(STRM_MAGIC)),
+            sorted(vec![open, canceled])
+        );
+        assert_eq!(keys(to_bob.clone().magic(0)), vec![legacy]);
+        assert_eq!(
+            keys(to_bob.clone().magic(STRM_MAGIC).canceled(false)),
+            vec![open]
+        );
+        assert_eq!(keys(to_bob.canceled(true)), vec![canceled]);
+        assert!(keys(StreamFilter::new().sender(bob)).is_empty());
+    }
+}
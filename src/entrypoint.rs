@@ -24,17 +24,34 @@ use solana_program::{
 use std::convert::TryInto;
 
 use crate::state::{
-    CancelAccounts, InitializeAccounts, StreamInstruction, TopUpAccounts, TransferAccounts,
-    WithdrawAccounts,
+    CancelAccounts, InitializeAccounts, LabelsAccounts, SplitAccounts, StreamInstruction,
+    StreamLabels, StreamOptions, StreamPermissions, TopUpAccounts, TransferAccounts,
+    UpdatePermissionsAccounts, WithdrawAccounts,
+};
+use crate::token::{
+    cancel, cancel_and_close, clear_labels, collect, create, extend_stream, set_labels,
+    split_stream, topup_stream, transfer_recipient, update_permissions, withdraw,
 };
-use crate::token::{cancel, create, topup_stream, transfer_recipient, withdraw};
 
 entrypoint!(process_instruction);
 pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> ProgramResult {
     let ai = &mut acc.iter();
+    let (tag, ix) = ix
+        .split_first()
+        .ok_or(ProgramError::InvalidInstructionData)?;
+
+    match *tag {
+        0 | 11 => {
+            let mut data = ix;
+            let si = StreamInstruction::deserialize(&mut data)?;
+            let options = match *tag {
+                11 => StreamOptions::deserialize(&mut data)?,
+                _ => StreamOptions::default(),
+            };
+            let memo = unpack_memo(data)?;
+            let (acc, memo_program) = split_memo_program(acc, &memo)?;
+            let ai = &mut acc.iter();
 
-    match ix[0] {
-        0 => {
             let ia = InitializeAccounts {
                 sender: next_account_info(ai)?.clone(),
                 sender_tokens: next_account_info(ai)?.clone(),
@@ -47,13 +64,22 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 token_program: next_account_info(ai)?.clone(),
                 associated_token_program: next_account_info(ai)?.clone(),
                 system_program: next_account_info(ai)?.clone(),
+                registry: next_account_info(ai).ok().cloned(),
+                sender_counter: next_account_info(ai).ok().cloned(),
+                mint_stats: next_account_info(ai).ok().cloned(),
+                memo_program,
             };
 
-            let si = StreamInstruction::try_from_slice(&ix[1..])?;
-
-            return create(pid, ia, si);
+            return create(pid, ia, si, options, &memo);
         }
-        1 => {
+        1 | 12 => {
+            let (amount, memo) = match *tag {
+                12 => (0, unpack_memo(ix)?),
+                _ => unpack_amount_memo(ix)?,
+            };
+            let (acc, memo_program) = split_memo_program(acc, &memo)?;
+            let ai = &mut acc.iter();
+
             let wa = WithdrawAccounts {
                 withdraw_authority: next_account_info(ai)?.clone(),
                 sender: next_account_info(ai)?.clone(),
@@ -63,14 +89,27 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 escrow_tokens: next_account_info(ai)?.clone(),
                 mint: next_account_info(ai)?.clone(),
                 token_program: next_account_info(ai)?.clone(),
+                labels: match *tag {
+                    12 => Some(next_account_info(ai)?.clone()),
+                    _ => None,
+                },
+                registry: next_account_info(ai).ok().cloned(),
+                sender_counter: next_account_info(ai).ok().cloned(),
+                mint_stats: next_account_info(ai).ok().cloned(),
+                memo_program,
             };
 
-            let amnt = u64::from_le_bytes(ix[1..].try_into().unwrap());
-
-            return withdraw(pid, wa, amnt);
+            if *tag == 12 {
+                return collect(pid, wa, &memo);
+            }
+            return withdraw(pid, wa, amount, &memo);
         }
 
-        2 => {
+        2 | 10 => {
+            let memo = unpack_memo(ix)?;
+            let (acc, memo_program) = split_memo_program(acc, &memo)?;
+            let ai = &mut acc.iter();
+
             let ca = CancelAccounts {
                 cancel_authority: next_account_info(ai)?.clone(),
                 sender: next_account_info(ai)?.clone(),
@@ -81,9 +120,20 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 escrow_tokens: next_account_info(ai)?.clone(),
                 mint: next_account_info(ai)?.clone(),
                 token_program: next_account_info(ai)?.clone(),
+                labels: match *tag {
+                    10 => Some(next_account_info(ai)?.clone()),
+                    _ => None,
+                },
+                registry: next_account_info(ai).ok().cloned(),
+                sender_counter: next_account_info(ai).ok().cloned(),
+                mint_stats: next_account_info(ai).ok().cloned(),
+                memo_program,
             };
 
-            return cancel(pid, ca);
+            if *tag == 10 {
+                return cancel_and_close(pid, ca, &memo);
+            }
+            return cancel(pid, ca, &memo);
         }
         3 => {
             let ta = TransferAccounts {
@@ -102,6 +152,54 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
             return transfer_recipient(pid, ta);
         }
         4 => {
+            let (amount, memo) = unpack_amount_memo(ix)?;
+            let (acc, memo_program) = split_memo_program(acc, &memo)?;
+            let ai = &mut acc.iter();
+
+            let ta = TopUpAccounts {
+                sender: next_account_info(ai)?.clone(),
+                sender_tokens: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                escrow_tokens: next_account_info(ai)?.clone(),
+                mint: next_account_info(ai)?.clone(),
+                token_program: next_account_info(ai)?.clone(),
+                memo_program,
+            };
+
+            return topup_stream(pid, ta, amount, &memo);
+        }
+        5 => {
+            let la = LabelsAccounts {
+                sender: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                labels: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+            };
+            let labels = StreamLabels::try_from_slice(ix)?;
+
+            return set_labels(pid, la, labels);
+        }
+        6 => {
+            let la = LabelsAccounts {
+                sender: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                labels: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+            };
+
+            return clear_labels(pid, la);
+        }
+        7 => {
+            let ua = UpdatePermissionsAccounts {
+                sender: next_account_info(ai)?.clone(),
+                recipient: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+            };
+            let permissions = StreamPermissions::try_from_slice(ix)?;
+
+            return update_permissions(pid, ua, permissions);
+        }
+        8 => {
             let ta = TopUpAccounts {
                 sender: next_account_info(ai)?.clone(),
                 sender_tokens: next_account_info(ai)?.clone(),
@@ -109,13 +207,78 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 escrow_tokens: next_account_info(ai)?.clone(),
                 mint: next_account_info(ai)?.clone(),
                 token_program: next_account_info(ai)?.clone(),
+                memo_program: None,
+            };
+            let end_time = unpack_u64(ix)?;
+
+            return extend_stream(pid, ta, end_time);
+        }
+        9 => {
+            let sa = SplitAccounts {
+                recipient: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                escrow_tokens: next_account_info(ai)?.clone(),
+                new_recipient: next_account_info(ai)?.clone(),
+                new_recipient_tokens: next_account_info(ai)?.clone(),
+                new_metadata: next_account_info(ai)?.clone(),
+                new_escrow_tokens: next_account_info(ai)?.clone(),
+                mint: next_account_info(ai)?.clone(),
+                rent: next_account_info(ai)?.clone(),
+                token_program: next_account_info(ai)?.clone(),
+                associated_token_program: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+                registry: next_account_info(ai).ok().cloned(),
+                sender_counter: next_account_info(ai).ok().cloned(),
+                mint_stats: next_account_info(ai).ok().cloned(),
             };
-            let amount = u64::from_le_bytes(ix[1..].try_into().unwrap());
+            let amount = unpack_u64(ix)?;
 
-            return topup_stream(pid, ta, amount);
+            return split_stream(pid, sa, amount);
         }
         _ => {}
     }
 
     Err(ProgramError::InvalidInstructionData)
 }
+
+/// Read the little-endian u64 amount leading the instruction payload,
+/// followed by the optional memo
+fn unpack_amount_memo(ix: &[u8]) -> Result<(u64, Vec<u8>), ProgramError> {
+    if ix.len() < 8 {
+        return Err(ProgramError::InvalidInstructionData);
+    }
+
+    let (amount, memo) = ix.split_at(8);
+    Ok((unpack_u64(amount)?, unpack_memo(memo)?))
+}
+
+/// Read the little-endian u64 that's the whole instruction payload
+fn unpack_u64(ix: &[u8]) -> Result<u64, ProgramError> {
+    let bytes = ix
+        .try_into()
+        .map_err(|_| ProgramError::InvalidInstructionData)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Read the optional borsh-encoded memo, left out by older clients
+fn unpack_memo(ix: &[u8]) -> Result<Vec<u8>, ProgramError> {
+    if ix.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Vec::<u8>::try_from_slice(ix).map_err(|_| ProgramError::InvalidInstructionData)
+}
+
+/// Take the memo program off the end of the accounts when there's a memo,
+/// so it doesn't get mistaken for one of the optional accounts before it
+fn split_memo_program<'a, 'b>(
+    acc: &'b [AccountInfo<'a>],
+    memo: &[u8],
+) -> Result<(&'b [AccountInfo<'a>], Option<AccountInfo<'a>>), ProgramError> {
+    if memo.is_empty() {
+        return Ok((acc, None));
+    }
+
+    let (memo_program, acc) = acc.split_last().ok_or(ProgramError::NotEnoughAccountKeys)?;
+    Ok((acc, Some(memo_program.clone())))
+}
@@ -1,8 +1,9 @@
 use solana_program::msg;
 use solana_program::program_error::ProgramError;
+use streamflow_timelock_math::MathError;
 use thiserror::Error;
 
-#[derive(Error, Debug, Copy, Clone)]
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum StreamFlowError {
     #[error("Accounts not writable!")]
     AccountsNotWritable,
@@ -18,6 +19,96 @@ pub enum StreamFlowError {
 
     #[error("Stream closed")]
     StreamClosed,
+
+    #[error("Invalid account!")]
+    InvalidAccount,
+
+    #[error("Stream has not started yet")]
+    StreamNotStarted,
+
+    #[error("Metadata account is not owned by the program!")]
+    InvalidMetadataOwner,
+
+    #[error("Metadata account is not of a metadata size!")]
+    InvalidMetadataSize,
+
+    #[error("Account is not stream metadata of a supported version!")]
+    UnsupportedVersion,
+
+    #[error("Metadata has the layout of an older program version!")]
+    VersionMismatch,
+
+    #[error("Period must be between one second and ten years!")]
+    InvalidPeriod,
+
+    #[error("Stream start or end time is out of bounds!")]
+    InvalidTimestamps,
+
+    #[error("Stream amounts are out of bounds!")]
+    InvalidAmounts,
+
+    #[error("Insufficient tokens in sender's token account!")]
+    InsufficientFunds,
+
+    #[error("Withdrawn amount exceeds the deposited amount!")]
+    WithdrawnExceedsDeposited,
+
+    #[error("Stream canceled before it was created!")]
+    InvalidCancelTime,
+
+    #[error("Cliff must be between the stream start and end time!")]
+    InvalidCliff,
+
+    #[error("Labels must be at most 8 key/value pairs and 512 bytes!")]
+    InvalidLabels,
+
+    #[error("Memo must be valid UTF-8 of at most 256 bytes!")]
+    InvalidMemo,
+
+    #[error("Stream deposit can't be zero!")]
+    ZeroDeposit,
+
+    #[error("Stream end time can't be moved earlier!")]
+    CannotShortenStream,
+
+    #[error("Escrow account exists with an unexpected mint, authority, delegate or balance!")]
+    EscrowAccountCompromised,
+
+    #[error("Account is not rent-exempt!")]
+    NotRentExempt,
+
+    #[error("Escrow account is frozen by the mint's freeze authority!")]
+    EscrowFrozen,
+
+    #[error("Escrow account is not the stream's escrow!")]
+    EscrowMismatch,
+
+    #[error("Sender token account is not the stream's!")]
+    SenderTokensMismatch,
+
+    #[error("Recipient token account is not the stream's!")]
+    RecipientTokensMismatch,
+
+    #[error("Sender token account is frozen or has a delegate!")]
+    InvalidSenderAccount,
+
+    #[error("Stream runs for longer than the maximum duration!")]
+    DurationTooLong,
+
+    #[error("Vested tokens must be withdrawn before splitting the stream!")]
+    VestedNotWithdrawn,
+
+    #[error("Stream funds can't be withdrawn before withdrawable_after!")]
+    WithdrawLocked,
+
+    #[error("Lock contracts must unlock their whole total at once at their end!")]
+    InvalidLock,
+
+    #[error("Lock contracts must be fully funded at creation!")]
+    LockUnderfunded,
+
+    #[error("Only the sender can top up this stream!")]
+    ExternalTopupNotAllowed,
 }
 
 impl From<StreamFlowError> for ProgramError {
@@ -26,3 +117,18 @@ impl From<StreamFlowError> for ProgramError {
         ProgramError::Custom(e as u32)
     }
 }
+
+/// Program error for an error of the vesting math crate.
+pub fn math_error(e: MathError) -> ProgramError {
+    match e {
+        MathError::CannotShortenStream => StreamFlowError::CannotShortenStream.into(),
+        MathError::InvalidTimestamps => StreamFlowError::InvalidTimestamps.into(),
+        MathError::InvalidAmounts => StreamFlowError::InvalidAmounts.into(),
+        MathError::InvalidPeriod => StreamFlowError::InvalidPeriod.into(),
+        MathError::DurationTooLong => StreamFlowError::DurationTooLong.into(),
+        MathError::NoRateToKeep => {
+            msg!("Error: Nothing is streamed after the cliff, there's no rate to keep");
+            ProgramError::InvalidArgument
+        }
+    }
+}
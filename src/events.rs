@@ -0,0 +1,63 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Structured events, logged with `sol_log_data` for indexers to decode
+//! instead of parsing `msg!` lines. Each one is a borsh-encoded
+//! `StreamEvent`, whose leading variant index tells them apart.
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{entrypoint::ProgramResult, log::sol_log_data, pubkey::Pubkey};
+
+use crate::state::StreamPermissions;
+
+/// An event of a stream, keyed by its `metadata` account. New variants are
+/// only ever appended, so indexers can keep decoding older ones.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub enum StreamEvent {
+    /// `amount` was withdrawn to the recipient, on behalf of `initiator`,
+    /// the recipient or a sender pushing the funds to them
+    Withdrawn {
+        metadata: Pubkey,
+        initiator: Pubkey,
+        amount: u64,
+    },
+    /// The cancel and transfer permissions of the stream are now `permissions`
+    PermissionsUpdated {
+        metadata: Pubkey,
+        permissions: StreamPermissions,
+    },
+    /// `sender` created a stream of `mint` to `recipient`, depositing
+    /// `deposited_amount`, to be read with `mint_decimals` decimals
+    StreamCreated {
+        metadata: Pubkey,
+        sender: Pubkey,
+        recipient: Pubkey,
+        mint: Pubkey,
+        mint_decimals: u8,
+        deposited_amount: u64,
+    },
+    /// `funder`, the sender or anyone the stream allows, deposited `amount`
+    ToppedUp {
+        metadata: Pubkey,
+        funder: Pubkey,
+        amount: u64,
+    },
+}
+
+/// Log `event` as program data.
+pub fn emit(event: &StreamEvent) -> ProgramResult {
+    sol_log_data(&[&event.try_to_vec()?]);
+    Ok(())
+}
@@ -0,0 +1,467 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use borsh::BorshSerialize;
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+
+use spl_associated_token_account::get_associated_token_address;
+
+use crate::state::{StreamInstruction, StreamLabels, StreamOptions, StreamPermissions};
+use crate::utils::find_labels_account;
+
+/// Client-side keys for the create instruction.
+/// The escrow account is derived from `metadata`.
+#[derive(Debug, Clone)]
+pub struct CreateStreamKeys {
+    /// The stream sender, signer and fee payer
+    pub sender: Pubkey,
+    /// The sender's token account the deposit is taken from
+    pub sender_tokens: Pubkey,
+    /// The stream recipient
+    pub recipient: Pubkey,
+    /// The recipient's associated token account
+    pub recipient_tokens: Pubkey,
+    /// The fresh account that will hold the stream metadata, must sign
+    pub metadata: Pubkey,
+    /// The SPL token mint
+    pub mint: Pubkey,
+    /// Optional recipient stream registry PDA
+    pub registry: Option<Pubkey>,
+    /// Optional sender stream counter PDA
+    pub sender_counter: Option<Pubkey>,
+    /// Optional mint stats PDA
+    pub mint_stats: Option<Pubkey>,
+}
+
+/// Build the create instruction with the accounts in the order the
+/// entrypoint expects them.
+pub fn create_stream_ix(
+    program_id: &Pubkey,
+    params: &StreamInstruction,
+    keys: &CreateStreamKeys,
+) -> Result<Instruction, ProgramError> {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[keys.metadata.as_ref()], program_id);
+
+    let mut data = vec![0];
+    params.serialize(&mut data)?;
+
+    let mut accounts = vec![
+        AccountMeta::new(keys.sender, true),
+        AccountMeta::new(keys.sender_tokens, false),
+        AccountMeta::new(keys.recipient, false),
+        AccountMeta::new(keys.recipient_tokens, false),
+        AccountMeta::new(keys.metadata, true),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(keys.mint, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    push_optional_accounts(
+        &mut accounts,
+        &[keys.registry, keys.sender_counter, keys.mint_stats],
+    );
+
+    Ok(Instruction::new_with_bytes(*program_id, &data, accounts))
+}
+
+/// Build the create instruction like `create_stream_ix`, also passing the
+/// stream `options` that don't fit the `StreamInstruction`.
+pub fn create_stream_with_options_ix(
+    program_id: &Pubkey,
+    params: &StreamInstruction,
+    options: &StreamOptions,
+    keys: &CreateStreamKeys,
+) -> Result<Instruction, ProgramError> {
+    let mut ix = create_stream_ix(program_id, params, keys)?;
+    ix.data[0] = 11;
+    options.serialize(&mut ix.data)?;
+    Ok(ix)
+}
+
+/// Build the create instruction for a lock contract of `amount`, which
+/// unlocks all at once at `unlock_time`, see `StreamInstruction::lock`.
+pub fn create_lock_ix(
+    program_id: &Pubkey,
+    start_time: u64,
+    unlock_time: u64,
+    amount: u64,
+    keys: &CreateStreamKeys,
+) -> Result<Instruction, ProgramError> {
+    create_stream_with_options_ix(
+        program_id,
+        &StreamInstruction::lock(start_time, unlock_time, amount),
+        &StreamOptions::lock(),
+        keys,
+    )
+}
+
+/// Client-side keys for the withdraw instruction.
+/// The escrow account is derived from `metadata`.
+#[derive(Debug, Clone)]
+pub struct WithdrawStreamKeys {
+    /// The signer, the recipient or anyone when the withdrawal is public
+    pub withdraw_authority: Pubkey,
+    /// The stream sender, receives the escrow rent on the final withdrawal
+    pub sender: Pubkey,
+    /// The stream recipient
+    pub recipient: Pubkey,
+    /// The token account the funds go to
+    pub recipient_tokens: Pubkey,
+    /// The account holding the stream metadata
+    pub metadata: Pubkey,
+    /// The SPL token mint
+    pub mint: Pubkey,
+    /// Optional recipient stream registry PDA
+    pub registry: Option<Pubkey>,
+    /// Optional sender stream counter PDA
+    pub sender_counter: Option<Pubkey>,
+    /// Optional mint stats PDA
+    pub mint_stats: Option<Pubkey>,
+}
+
+/// Build the withdraw instruction for `amount`, 0 meaning everything
+/// available, with the accounts in the order the entrypoint expects them.
+pub fn withdraw_stream_ix(
+    program_id: &Pubkey,
+    amount: u64,
+    keys: &WithdrawStreamKeys,
+) -> Instruction {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[keys.metadata.as_ref()], program_id);
+
+    let mut data = vec![1];
+    data.extend_from_slice(&amount.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(keys.withdraw_authority, true),
+        AccountMeta::new(keys.sender, false),
+        AccountMeta::new(keys.recipient, false),
+        AccountMeta::new(keys.recipient_tokens, false),
+        AccountMeta::new(keys.metadata, false),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(keys.mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+
+    push_optional_accounts(
+        &mut accounts,
+        &[keys.registry, keys.sender_counter, keys.mint_stats],
+    );
+
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Build the collect instruction, withdrawing everything available like
+/// `withdraw_stream_ix` and, if that completes the stream, also closing its
+/// metadata and labels accounts, the rent going to the sender. The labels
+/// account is always passed, whether or not it exists.
+pub fn collect_stream_ix(program_id: &Pubkey, keys: &WithdrawStreamKeys) -> Instruction {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[keys.metadata.as_ref()], program_id);
+    let (labels, _) = find_labels_account(&keys.metadata, program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(keys.withdraw_authority, true),
+        AccountMeta::new(keys.sender, false),
+        AccountMeta::new(keys.recipient, false),
+        AccountMeta::new(keys.recipient_tokens, false),
+        AccountMeta::new(keys.metadata, false),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(keys.mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new(labels, false),
+    ];
+
+    push_optional_accounts(
+        &mut accounts,
+        &[keys.registry, keys.sender_counter, keys.mint_stats],
+    );
+
+    Instruction::new_with_bytes(*program_id, &[12], accounts)
+}
+
+/// Client-side keys for the cancel instruction.
+/// The escrow account is derived from `metadata`.
+#[derive(Debug, Clone)]
+pub struct CancelStreamKeys {
+    /// The signer, the sender or anyone once the stream is closable
+    pub cancel_authority: Pubkey,
+    /// The stream sender, gets the unvested funds and the escrow rent back
+    pub sender: Pubkey,
+    /// The sender's token account the unvested funds return to
+    pub sender_tokens: Pubkey,
+    /// The stream recipient
+    pub recipient: Pubkey,
+    /// The recipient's associated token account
+    pub recipient_tokens: Pubkey,
+    /// The account holding the stream metadata
+    pub metadata: Pubkey,
+    /// The SPL token mint
+    pub mint: Pubkey,
+    /// Optional recipient stream registry PDA
+    pub registry: Option<Pubkey>,
+    /// Optional sender stream counter PDA
+    pub sender_counter: Option<Pubkey>,
+    /// Optional mint stats PDA
+    pub mint_stats: Option<Pubkey>,
+}
+
+/// Build the cancel instruction with the accounts in the order the
+/// entrypoint expects them.
+pub fn cancel_stream_ix(program_id: &Pubkey, keys: &CancelStreamKeys) -> Instruction {
+    cancel_ix(program_id, 2, keys)
+}
+
+/// Build the instruction cancelling the stream like `cancel_stream_ix` and
+/// also closing its metadata and labels accounts, the rent going to the
+/// sender. The labels account is always passed, whether or not it exists.
+pub fn cancel_and_close_stream_ix(program_id: &Pubkey, keys: &CancelStreamKeys) -> Instruction {
+    cancel_ix(program_id, 10, keys)
+}
+
+fn cancel_ix(program_id: &Pubkey, tag: u8, keys: &CancelStreamKeys) -> Instruction {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[keys.metadata.as_ref()], program_id);
+
+    let mut accounts = vec![
+        AccountMeta::new(keys.cancel_authority, true),
+        AccountMeta::new(keys.sender, false),
+        AccountMeta::new(keys.sender_tokens, false),
+        AccountMeta::new(keys.recipient, false),
+        AccountMeta::new(keys.recipient_tokens, false),
+        AccountMeta::new(keys.metadata, false),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(keys.mint, false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+    ];
+
+    if tag == 10 {
+        let (labels, _) = find_labels_account(&keys.metadata, program_id);
+        accounts.push(AccountMeta::new(labels, false));
+    }
+
+    push_optional_accounts(
+        &mut accounts,
+        &[keys.registry, keys.sender_counter, keys.mint_stats],
+    );
+
+    Instruction::new_with_bytes(*program_id, &[tag], accounts)
+}
+
+/// Build the instruction topping up the stream at `metadata` with `amount`
+/// taken from the `funder`'s `funder_tokens`. The funder is the stream's
+/// sender, or anyone if the stream allows external topups.
+pub fn topup_stream_ix(
+    program_id: &Pubkey,
+    amount: u64,
+    funder: &Pubkey,
+    funder_tokens: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[metadata.as_ref()], program_id);
+
+    let mut data = vec![4];
+    data.extend_from_slice(&amount.to_le_bytes());
+
+    Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        vec![
+            AccountMeta::new(*funder, true),
+            AccountMeta::new(*funder_tokens, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new(escrow_tokens, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+/// Build the instruction extending the stream at `metadata` to `end_time`,
+/// with the deposit that takes pulled from the `sender`'s `sender_tokens`.
+pub fn extend_stream_ix(
+    program_id: &Pubkey,
+    end_time: u64,
+    sender: &Pubkey,
+    sender_tokens: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[metadata.as_ref()], program_id);
+
+    let mut data = vec![8];
+    data.extend_from_slice(&end_time.to_le_bytes());
+
+    Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        vec![
+            AccountMeta::new(*sender, true),
+            AccountMeta::new(*sender_tokens, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new(escrow_tokens, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    )
+}
+
+/// Client-side keys for the split instruction. The escrow accounts are
+/// derived from the metadata keys, the new recipient's token account is
+/// its associated token account.
+#[derive(Debug, Clone)]
+pub struct SplitStreamKeys {
+    /// The recipient of the stream being split, signer and fee payer
+    pub recipient: Pubkey,
+    /// The account holding the stream metadata
+    pub metadata: Pubkey,
+    /// The recipient of the new stream
+    pub new_recipient: Pubkey,
+    /// The fresh account that will hold the new stream's metadata, must sign
+    pub new_metadata: Pubkey,
+    /// The SPL token mint
+    pub mint: Pubkey,
+    /// Optional new recipient stream registry PDA
+    pub registry: Option<Pubkey>,
+    /// Optional sender stream counter PDA
+    pub sender_counter: Option<Pubkey>,
+    /// Optional mint stats PDA
+    pub mint_stats: Option<Pubkey>,
+}
+
+/// Build the instruction splitting `amount` of the stream's deposit off
+/// into a new stream, with the accounts in the order the entrypoint
+/// expects them.
+pub fn split_stream_ix(program_id: &Pubkey, amount: u64, keys: &SplitStreamKeys) -> Instruction {
+    let (escrow_tokens, _) = Pubkey::find_program_address(&[keys.metadata.as_ref()], program_id);
+    let (new_escrow_tokens, _) =
+        Pubkey::find_program_address(&[keys.new_metadata.as_ref()], program_id);
+    let new_recipient_tokens = get_associated_token_address(&keys.new_recipient, &keys.mint);
+
+    let mut data = vec![9];
+    data.extend_from_slice(&amount.to_le_bytes());
+
+    let mut accounts = vec![
+        AccountMeta::new(keys.recipient, true),
+        AccountMeta::new(keys.metadata, false),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(keys.new_recipient, false),
+        AccountMeta::new(new_recipient_tokens, false),
+        AccountMeta::new(keys.new_metadata, true),
+        AccountMeta::new(new_escrow_tokens, false),
+        AccountMeta::new_readonly(keys.mint, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(spl_token::id(), false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+
+    push_optional_accounts(
+        &mut accounts,
+        &[keys.registry, keys.sender_counter, keys.mint_stats],
+    );
+
+    Instruction::new_with_bytes(*program_id, &data, accounts)
+}
+
+/// Build the instruction updating the permissions of the stream at
+/// `metadata`, with both its `sender` and `recipient` as signers. A change
+/// only working against one of them can clear `is_signer` of the other.
+pub fn update_permissions_ix(
+    program_id: &Pubkey,
+    permissions: &StreamPermissions,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![7];
+    permissions.serialize(&mut data)?;
+
+    Ok(Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        vec![
+            AccountMeta::new_readonly(*sender, true),
+            AccountMeta::new_readonly(*recipient, true),
+            AccountMeta::new(*metadata, false),
+        ],
+    ))
+}
+
+/// Build the instruction setting the labels of the stream at `metadata`,
+/// signed by its `sender`.
+pub fn set_labels_ix(
+    program_id: &Pubkey,
+    labels: &StreamLabels,
+    sender: &Pubkey,
+    metadata: &Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let mut data = vec![5];
+    labels.serialize(&mut data)?;
+
+    Ok(Instruction::new_with_bytes(
+        *program_id,
+        &data,
+        labels_accounts(program_id, sender, metadata),
+    ))
+}
+
+/// Build the instruction clearing the labels of the stream at `metadata`
+/// and returning their rent to its `sender`.
+pub fn clear_labels_ix(program_id: &Pubkey, sender: &Pubkey, metadata: &Pubkey) -> Instruction {
+    Instruction::new_with_bytes(
+        *program_id,
+        &[6],
+        labels_accounts(program_id, sender, metadata),
+    )
+}
+
+fn labels_accounts(program_id: &Pubkey, sender: &Pubkey, metadata: &Pubkey) -> Vec<AccountMeta> {
+    let (labels, _) = find_labels_account(metadata, program_id);
+
+    vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new_readonly(*metadata, false),
+        AccountMeta::new(labels, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ]
+}
+
+/// Append the optional trailing accounts that are given. They're positional,
+/// so each one is only passed along with all the ones before it.
+fn push_optional_accounts(accounts: &mut Vec<AccountMeta>, optional: &[Option<Pubkey>]) {
+    for key in optional.iter().map_while(|key| *key) {
+        accounts.push(AccountMeta::new(key, false));
+    }
+}
+
+/// Attach `memo` to a create, withdraw, cancel or topup instruction, for the
+/// program to pass on to the SPL Memo program.
+pub fn append_memo(ix: &mut Instruction, memo: &[u8]) -> Result<(), ProgramError> {
+    if memo.is_empty() {
+        return Ok(());
+    }
+
+    memo.serialize(&mut ix.data)?;
+    ix.accounts
+        .push(AccountMeta::new_readonly(spl_memo::id(), false));
+    Ok(())
+}
@@ -17,13 +17,25 @@
 //! The code providing timelock primitives
 //! used by [streamflow.finance](https://streamflow.finance).
 
+/// Fetching streams over RPC
+#[cfg(all(feature = "rpc-client", not(target_arch = "bpf")))]
+pub mod client;
 /// Entrypoint
 #[cfg(not(feature = "no-entrypoint"))]
 pub mod entrypoint;
 /// Errors
 pub mod error;
+/// Structured events
+pub mod events;
+/// Instruction builders for clients
+pub mod instructions;
+/// Composable filters for fetching streams over RPC
+#[cfg(all(feature = "rpc-client", not(target_arch = "bpf")))]
+pub mod query;
 /// Structs and data
 pub mod state;
+/// Stream instructions for common vesting schedules
+pub mod templates;
 /// Functions related to SPL tokens
 pub mod token;
 /// Utility functions
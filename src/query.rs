@@ -0,0 +1,210 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Composable `getProgramAccounts` filters for streams, built with the
+//! `rpc-client` feature. Conditions a memcmp can't express, like whether a
+//! stream is canceled, are checked on the decoded streams by `matches`.
+use solana_client::rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType};
+use solana_sdk::{bs58, pubkey::Pubkey};
+
+use crate::state::{TokenStreamData, MINT_OFFSET, RECIPIENT_OFFSET, SENDER_OFFSET};
+
+/// Streams to fetch, e.g. the open streams of a recipient on a mint:
+///
+/// ```
+/// # use solana_sdk::pubkey::Pubkey;
+/// use streamflow_timelock::query::StreamFilter;
+/// use streamflow_timelock::state::STRM_MAGIC;
+///
+/// # let (recipient, mint) = (Pubkey::new_unique(), Pubkey::new_unique());
+/// let filter = StreamFilter::new()
+///     .recipient(recipient)
+///     .mint(mint)
+///     .magic(STRM_MAGIC)
+///     .canceled(false);
+/// assert_eq!(filter.rpc_filters().len(), 3);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamFilter {
+    magic: Option<u64>,
+    sender: Option<Pubkey>,
+    recipient: Option<Pubkey>,
+    mint: Option<Pubkey>,
+    canceled: Option<bool>,
+    min_deposited: Option<u64>,
+}
+
+impl StreamFilter {
+    /// A filter matching every stream.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only streams whose stored `magic` is `magic`: `STRM_MAGIC` for ones
+    /// saved by this version. Legacy streams hold 0 or the version they were
+    /// written with until they're saved again.
+    pub fn magic(mut self, magic: u64) -> Self {
+        self.magic = Some(magic);
+        self
+    }
+
+    /// Only streams sent by `sender`.
+    pub fn sender(mut self, sender: Pubkey) -> Self {
+        self.sender = Some(sender);
+        self
+    }
+
+    /// Only streams to `recipient`.
+    pub fn recipient(mut self, recipient: Pubkey) -> Self {
+        self.recipient = Some(recipient);
+        self
+    }
+
+    /// Only streams of `mint`.
+    pub fn mint(mut self, mint: Pubkey) -> Self {
+        self.mint = Some(mint);
+        self
+    }
+
+    /// Only canceled streams, or only ones that aren't. Checked by `matches`.
+    pub fn canceled(mut self, canceled: bool) -> Self {
+        self.canceled = Some(canceled);
+        self
+    }
+
+    /// Only streams with at least `amount` deposited. Checked by `matches`.
+    pub fn min_deposited(mut self, amount: u64) -> Self {
+        self.min_deposited = Some(amount);
+        self
+    }
+
+    /// The memcmp filters for `getProgramAccounts`. Streams vary in size
+    /// with their name, so there's no data size filter.
+    pub fn rpc_filters(&self) -> Vec<RpcFilterType> {
+        let mut filters = vec![];
+        if let Some(magic) = self.magic {
+            filters.push(memcmp(0, &magic.to_le_bytes()));
+        }
+
+        for (offset, key) in [
+            (SENDER_OFFSET, &self.sender),
+            (RECIPIENT_OFFSET, &self.recipient),
+            (MINT_OFFSET, &self.mint),
+        ] {
+            if let Some(key) = key {
+                filters.push(memcmp(offset, key.as_ref()));
+            }
+        }
+
+        filters
+    }
+
+    /// Whether a decoded `stream` matches the filter. Decoding upgrades the
+    /// magic of legacy streams, so the stored one is left to `rpc_filters`.
+    pub fn matches(&self, stream: &TokenStreamData) -> bool {
+        let is = |want: &Option<Pubkey>, key: &Pubkey| want.map_or(true, |k| &k == key);
+
+        is(&self.sender, &stream.sender)
+            && is(&self.recipient, &stream.recipient)
+            && is(&self.mint, &stream.mint)
+            && self
+                .canceled
+                .map_or(true, |canceled| canceled == (stream.canceled_at > 0))
+            && self
+                .min_deposited
+                .map_or(true, |amount| stream.ix.deposited_amount >= amount)
+    }
+}
+
+fn memcmp(offset: usize, bytes: &[u8]) -> RpcFilterType {
+    RpcFilterType::Memcmp(Memcmp {
+        offset,
+        bytes: MemcmpEncodedBytes::Base58(bs58::encode(bytes).into_string()),
+        encoding: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use borsh::BorshSerialize;
+
+    use super::*;
+    use crate::state::{StreamInstruction, STRM_MAGIC};
+
+    fn new_stream() -> TokenStreamData {
+        TokenStreamData {
+            magic: STRM_MAGIC,
+            sender: Pubkey::new_unique(),
+            recipient: Pubkey::new_unique(),
+            mint: Pubkey::new_unique(),
+            ix: StreamInstruction {
+                deposited_amount: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn selects(filter: &StreamFilter, stream: &TokenStreamData) -> bool {
+        let data = stream.try_to_vec().unwrap();
+        filter.rpc_filters().iter().all(|f| match f {
+            RpcFilterType::Memcmp(m) => m.bytes_match(&data),
+            RpcFilterType::DataSize(size) => data.len() as u64 == *size,
+        })
+    }
+
+    #[test]
+    fn test_rpc_filters_follow_layout() {
+        let stream = new_stream();
+        let filter = StreamFilter::new()
+            .magic(STRM_MAGIC)
+            .sender(stream.sender)
+            .recipient(stream.recipient)
+            .mint(stream.mint);
+        assert_eq!(filter.rpc_filters().len(), 4);
+        assert!(selects(&filter, &stream));
+        assert!(filter.matches(&stream));
+
+        // Each condition on its own rules out a stream differing in it
+        let other = new_stream();
+        for filter in [
+            StreamFilter::new().magic(0),
+            StreamFilter::new().sender(other.sender),
+            StreamFilter::new().recipient(other.recipient),
+            StreamFilter::new().mint(other.mint),
+        ] {
+            assert!(!selects(&filter, &stream), "{:?}", filter);
+        }
+        assert!(selects(&StreamFilter::new(), &stream));
+    }
+
+    #[test]
+    fn test_matches() {
+        let mut stream = new_stream();
+        assert!(StreamFilter::new().matches(&stream));
+        assert!(StreamFilter::new().canceled(false).matches(&stream));
+        assert!(!StreamFilter::new().canceled(true).matches(&stream));
+        assert!(StreamFilter::new().min_deposited(100).matches(&stream));
+        assert!(!StreamFilter::new().min_deposited(101).matches(&stream));
+        assert!(!StreamFilter::new()
+            .mint(Pubkey::new_unique())
+            .matches(&stream));
+
+        stream.canceled_at = 1;
+        assert!(StreamFilter::new().canceled(true).matches(&stream));
+        assert!(!StreamFilter::new().canceled(false).matches(&stream));
+    }
+}
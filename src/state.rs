@@ -16,13 +16,83 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{account_info::AccountInfo, msg, pubkey::Pubkey};
+use solana_program::{
+    account_info::AccountInfo, borsh as solana_borsh, msg, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use streamflow_timelock_math::{validate_end_time, Schedule};
+
+use crate::error::{
+    math_error,
+    StreamFlowError::{
+        self, DurationTooLong, EscrowMismatch, InvalidAmounts, InvalidCancelTime, InvalidCliff,
+        InvalidLabels, InvalidLock, InvalidMetadata, InvalidMetadataOwner, InvalidMetadataSize,
+        InvalidPeriod, InvalidTimestamps, LockUnderfunded, MintMismatch, RecipientTokensMismatch,
+        SenderTokensMismatch, StreamClosed, StreamNotStarted, UnsupportedVersion, VersionMismatch,
+        VestedNotWithdrawn, WithdrawLocked, WithdrawnExceedsDeposited, ZeroDeposit,
+    },
+};
+use crate::utils::encode_base10;
 
 // Hardcoded program version
-pub const PROGRAM_VERSION: u64 = 2;
+pub const PROGRAM_VERSION: u64 = 3;
+
+/// Version of streams written without a `StreamExtension`, before
+/// `PROGRAM_VERSION` 3. They're still read and written in their layout.
+pub const LEGACY_VERSION: u64 = 2;
+
+/// Marks an account as stream metadata, stored as its `magic`. Streams
+/// written before it was introduced hold 0 or their version there instead.
+pub const STRM_MAGIC: u64 = 0x5354_524D_464C_4F57;
+
+/// `STRM_MAGIC` as stored at offset 0, a little-endian `u64`. Use it in a
+/// `getProgramAccounts` memcmp filter to find streams. Legacy streams only
+/// match once they are saved again.
+pub const STRM_MAGIC_BYTES: [u8; 8] = STRM_MAGIC.to_le_bytes();
+
+/// Size of a serialized legacy `TokenStreamData` with an empty
+/// `stream_name`, anything shorter can't be stream metadata.
+pub const METADATA_MIN_SIZE: usize = 313;
+
+/// Size of a serialized `StreamExtension`, which follows `ix` in the
+/// metadata of streams since `PROGRAM_VERSION` 3.
+pub const EXTENSION_SIZE: usize = 53;
+
+/// Longest `stream_name` a stream can be created with, in bytes
+pub const MAX_STRING_SIZE: usize = 200;
+
+/// Allocated size of the metadata with the longest `stream_name`, padded
+/// to 8 bytes like every metadata account. The program's other accounts
+/// are all smaller than `METADATA_MIN_SIZE` or bigger than this, so the
+/// size alone tells them apart from metadata.
+pub const METADATA_MAX_SIZE: usize =
+    (METADATA_MIN_SIZE + MAX_STRING_SIZE + EXTENSION_SIZE).next_multiple_of(8);
+
+/// Size of a serialized `StreamInstruction` with an empty `stream_name`.
+pub const STREAM_INSTRUCTION_MIN_SIZE: usize = 73;
+
+const YEAR: u64 = 365 * 24 * 60 * 60;
+
+/// Longest allowed period of a stream
+pub const MAX_PERIOD: u64 = 10 * YEAR;
+
+/// How far in the future a stream is allowed to start
+pub const MAX_START_DELAY: u64 = 10 * YEAR;
+
+/// Longest allowed duration of a stream, from start to end
+pub use streamflow_timelock_math::MAX_DURATION;
+
+/// How far in the past a stream is allowed to start, so a transaction built
+/// with a start "now" survives clock skew and landing delays. Such streams
+/// vest from their creation instead, see `TokenStreamData::effective_start`.
+pub const START_TIME_TOLERANCE: u64 = 5 * 60;
+
+/// Longest memo withdraw, cancel and topup pass on to the SPL Memo program
+pub const MEMO_MAX_SIZE: usize = 256;
 
 /// The struct containing instructions for initializing a stream
-#[derive(BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
 #[repr(C)]
 pub struct StreamInstruction {
     /// Timestamp when the tokens start vesting
@@ -78,11 +148,252 @@ impl Default for StreamInstruction {
     }
 }
 
+/// How a stream releases its funds
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum StreamType {
+    /// Vesting by the `StreamInstruction` schedule, every period after the
+    /// cliff, or at the release rate when recurring
+    #[default]
+    Linear,
+    /// A cliff-only lock contract, see `StreamInstruction::lock`. The whole
+    /// amount unlocks at once at the cliff, and withdrawing before fails.
+    Lock,
+}
+
+/// Create options that don't fit the `StreamInstruction` layout, passed
+/// after it by the create instruction and stored in the `StreamExtension`.
+/// The defaults are what streams created without them get.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct StreamOptions {
+    /// Timestamp before which nothing can be withdrawn, even if vested.
+    /// 0 for no lockup on top of the vesting.
+    pub withdrawable_after: u64,
+    /// How the stream releases its funds
+    pub stream_type: StreamType,
+    /// Whether the sender may withdraw on the recipient's behalf, pushing
+    /// what's available to the stream's `recipient_tokens`
+    pub sender_can_push: bool,
+    /// Whether `recipient_tokens` had to be the recipient's associated token
+    /// account at create, rather than any token account of the mint
+    pub strict_ata: bool,
+    /// Whether anyone may top up the stream from their own tokens, rather
+    /// than only the sender
+    pub allow_external_topup: bool,
+}
+
+impl StreamOptions {
+    /// Options of a lock contract, to create with `StreamInstruction::lock`
+    pub fn lock() -> Self {
+        StreamOptions {
+            stream_type: StreamType::Lock,
+            ..Default::default()
+        }
+    }
+
+    /// Check that the options are within sane bounds for a stream `ix`.
+    ///
+    /// A lock has to unlock its whole total at once at its end, and be
+    /// fully funded from the start, as there's nothing to top up over time.
+    pub fn validate(&self, ix: &StreamInstruction) -> Result<(), StreamFlowError> {
+        if self.withdrawable_after > 0
+            && validate_end_time(ix.start_time, self.withdrawable_after).is_err()
+        {
+            return Err(InvalidTimestamps);
+        }
+
+        if self.stream_type == StreamType::Lock {
+            if ix.cliff == 0
+                || ix.cliff != ix.end_time
+                || ix.cliff_amount != ix.total_amount
+                || ix.release_rate > 0
+            {
+                return Err(InvalidLock);
+            }
+
+            if ix.deposited_amount != ix.total_amount {
+                return Err(LockUnderfunded);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The part of the metadata following `ix` since `PROGRAM_VERSION` 3.
+///
+/// Legacy streams don't have it, at most the padding of their allocation
+/// follows `ix`. They read as `version` 0 with the default options, and
+/// are written back without it, so they still fit their account.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StreamExtension {
+    /// Layout version the stream was written with, 0 for legacy streams
+    pub version: u64,
+    /// Options the stream was created with
+    pub options: StreamOptions,
+    /// Decimals of the mint, as of the stream's creation
+    pub mint_decimals: u8,
+    /// Who funded the latest topup, the default key until there's one
+    pub last_topup_by: Pubkey,
+}
+
+impl BorshSerialize for StreamExtension {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        if self.version == 0 {
+            return Ok(());
+        }
+
+        self.version.serialize(writer)?;
+        self.options.serialize(writer)?;
+        self.mint_decimals.serialize(writer)?;
+        self.last_topup_by.serialize(writer)
+    }
+}
+
+impl BorshDeserialize for StreamExtension {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        if buf.len() < 8 {
+            return Ok(Self::default());
+        }
+
+        let version = u64::deserialize(buf)?;
+        // Unknown versions are left for `TokenStreamData::unpack` to reject
+        if version != PROGRAM_VERSION {
+            return Ok(Self {
+                version,
+                ..Default::default()
+            });
+        }
+
+        Ok(Self {
+            version,
+            options: StreamOptions::deserialize(buf)?,
+            mint_decimals: u8::deserialize(buf)?,
+            last_topup_by: Pubkey::deserialize(buf)?,
+        })
+    }
+}
+
+impl StreamInstruction {
+    /// The cancel and transfer permissions of the stream
+    pub fn permissions(&self) -> StreamPermissions {
+        StreamPermissions {
+            cancelable_by_sender: self.cancelable_by_sender,
+            cancelable_by_recipient: self.cancelable_by_recipient,
+            transferable_by_sender: self.transferable_by_sender,
+            transferable_by_recipient: self.transferable_by_recipient,
+        }
+    }
+
+    /// Initialize a cliff-only lock contract, where the whole `amount`
+    /// unlocks at once at `unlock_time` and nothing is streamed. It's only
+    /// created as one with `StreamOptions::lock`.
+    pub fn lock(start_time: u64, unlock_time: u64, amount: u64) -> Self {
+        StreamInstruction {
+            start_time,
+            end_time: unlock_time,
+            deposited_amount: amount,
+            total_amount: amount,
+            period: 1,
+            cliff: unlock_time,
+            cliff_amount: amount,
+            ..Default::default()
+        }
+    }
+
+    /// Check that the instruction values are within sane bounds at `now`,
+    /// so nothing absurd makes it into the vesting math.
+    pub fn validate(&self, now: u64) -> Result<(), StreamFlowError> {
+        if self.period == 0 || self.period > MAX_PERIOD {
+            return Err(InvalidPeriod);
+        }
+
+        if self.start_time > now.saturating_add(MAX_START_DELAY) {
+            return Err(InvalidTimestamps);
+        }
+
+        if validate_end_time(self.start_time, self.end_time).is_err() {
+            return Err(DurationTooLong);
+        }
+
+        if self.cliff > 0 && (self.cliff < self.start_time || self.cliff > self.end_time) {
+            return Err(InvalidCliff);
+        }
+
+        // Nothing would ever vest, and the escrow would just waste rent
+        if self.deposited_amount == 0 {
+            return Err(ZeroDeposit);
+        }
+
+        // Bounded by what the escrow actually holds, not just the total
+        let cap = self.total_amount.min(self.deposited_amount);
+        if self.release_rate > cap || self.cliff_amount > cap {
+            return Err(InvalidAmounts);
+        }
+
+        Ok(())
+    }
+
+    /// Timestamp from which the stream starts unlocking periods:
+    /// the cliff when there is one, otherwise the start time.
+    pub fn effective_start(&self) -> u64 {
+        self.schedule(0).effective_start()
+    }
+
+    /// The vesting schedule of a stream created at `created_at`.
+    pub fn schedule(&self, created_at: u64) -> Schedule {
+        Schedule {
+            created_at,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            deposited_amount: self.deposited_amount,
+            total_amount: self.total_amount,
+            period: self.period,
+            cliff: self.cliff,
+            cliff_amount: self.cliff_amount,
+            release_rate: self.release_rate,
+        }
+    }
+}
+
+/// Size of the `StreamState` region at the start of the metadata account.
+pub const STATE_SIZE: usize = 48;
+
+/// Offset of `sender` in the metadata, for `getProgramAccounts` memcmp filters.
+pub const SENDER_OFFSET: usize = STATE_SIZE;
+
+/// Offset of `recipient` in the metadata, for `getProgramAccounts` memcmp filters.
+pub const RECIPIENT_OFFSET: usize = STATE_SIZE + 2 * 32;
+
+/// Offset of `mint` in the metadata, for `getProgramAccounts` memcmp filters.
+pub const MINT_OFFSET: usize = STATE_SIZE + 4 * 32;
+
+/// StreamState is the frequently mutated head of `TokenStreamData`, its
+/// counters and timestamps, laid out exactly like its first fields.
+/// Everything after it (accounts and `ix`) is configuration, written on
+/// create and only touched again by transfer and topup.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct StreamState {
+    /// Marks the account as stream metadata, `STRM_MAGIC`
+    pub magic: u64,
+    /// Timestamp when stream was created
+    pub created_at: u64,
+    /// Amount of funds withdrawn
+    pub withdrawn_amount: u64,
+    /// Timestamp when stream was canceled (if canceled)
+    pub canceled_at: u64,
+    /// Timestamp at which stream can be safely canceled by a 3rd party
+    pub closable_at: u64,
+    /// Period boundary up to which funds were unlocked at the last withdrawal
+    pub last_withdrawn_at: u64,
+}
+
 /// TokenStreamData is the struct containing metadata for an SPL token stream.
-#[derive(BorshSerialize, BorshDeserialize, Default, Debug)]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Default, Debug, PartialEq)]
 #[repr(C)]
 pub struct TokenStreamData {
-    /// Magic bytes, will be used for version of the contract
+    /// Marks the account as stream metadata, `STRM_MAGIC`
     pub magic: u64,
     /// Timestamp when stream was created
     pub created_at: u64,
@@ -94,7 +405,9 @@ pub struct TokenStreamData {
     /// (Stream is either fully vested or there isn't enough capital to
     /// keep it active)
     pub closable_at: u64,
-    /// Timestamp of the last withdrawal
+    /// Period boundary up to which funds were unlocked at the last
+    /// withdrawal (not the wall-clock time of it). Vesting happens in whole
+    /// periods, so this is what a withdrawal actually "consumed".
     pub last_withdrawn_at: u64,
     /// Pubkey of the stream initializer
     pub sender: Pubkey,
@@ -110,6 +423,23 @@ pub struct TokenStreamData {
     pub escrow_tokens: Pubkey,
     /// The stream instruction
     pub ix: StreamInstruction,
+    /// Everything added to the layout since, see `StreamExtension`
+    pub ext: StreamExtension,
+}
+
+/// The token accounts an instruction was given, to check against the ones
+/// the stream was created with. `None` for those the instruction doesn't
+/// take, or lets the caller choose.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamTokenKeys<'k> {
+    /// The SPL token mint
+    pub mint: Option<&'k Pubkey>,
+    /// The escrow account holding the stream funds
+    pub escrow_tokens: Option<&'k Pubkey>,
+    /// The sender's token account, refunded on cancel
+    pub sender_tokens: Option<&'k Pubkey>,
+    /// The recipient's token account, paid on cancel
+    pub recipient_tokens: Option<&'k Pubkey>,
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -157,7 +487,7 @@ impl TokenStreamData {
 
         // TODO: calculate cancel_time based on other parameters (incl. deposited_amount)
         Self {
-            magic: PROGRAM_VERSION,
+            magic: STRM_MAGIC,
             created_at, //TODO: calculate
             withdrawn_amount: 0,
             canceled_at: 0,
@@ -170,89 +500,795 @@ impl TokenStreamData {
             mint,
             escrow_tokens,
             ix,
+            ext: StreamExtension {
+                version: PROGRAM_VERSION,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Pubkey of the stream initializer
+    pub fn sender(&self) -> &Pubkey {
+        &self.sender
+    }
+
+    /// Pubkey of the stream initializer's token account
+    pub fn sender_tokens(&self) -> &Pubkey {
+        &self.sender_tokens
+    }
+
+    /// Pubkey of the stream recipient
+    pub fn recipient(&self) -> &Pubkey {
+        &self.recipient
+    }
+
+    /// Pubkey of the stream recipient's token account
+    pub fn recipient_tokens(&self) -> &Pubkey {
+        &self.recipient_tokens
+    }
+
+    /// Pubkey of the token mint
+    pub fn mint(&self) -> &Pubkey {
+        &self.mint
+    }
+
+    /// Pubkey of the account holding the locked tokens
+    pub fn escrow_tokens(&self) -> &Pubkey {
+        &self.escrow_tokens
+    }
+
+    /// Load the stream metadata from `account`, checking that it's owned by
+    /// `program_id`, of a metadata size, and of a known version.
+    ///
+    /// `magic` is `STRM_MAGIC` for metadata written since it was introduced.
+    /// Older streams hold the version they were written with, or 0 from
+    /// before that was set. Those of `LEGACY_VERSION` (0 included) are
+    /// accepted and get `STRM_MAGIC` written on the next save, ones of an
+    /// older layout are left for `check_version` to reject. Which layout
+    /// follows the magic is then told by the `StreamExtension` version.
+    pub fn load_checked(account: &AccountInfo, program_id: &Pubkey) -> Result<Self, ProgramError> {
+        if account.data_is_empty() {
+            return Err(ProgramError::UninitializedAccount);
+        }
+
+        if account.owner != program_id {
+            return Err(InvalidMetadataOwner.into());
+        }
+
+        Self::unpack(&account.try_borrow_data()?)
+    }
+
+    /// Read the stream metadata from raw account data, e.g. as fetched by
+    /// a client over RPC, checking it's of a metadata size and of a known
+    /// version. Unlike `load_checked`, the account owner is left to the caller.
+    ///
+    /// Registries, labels, counters and stats are owned by the program too,
+    /// and carry no magic. Their sizes are out of the metadata's range, so
+    /// none of them is ever read as a (legacy) stream.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        if data.len() < METADATA_MIN_SIZE || data.len() > METADATA_MAX_SIZE {
+            return Err(InvalidMetadataSize.into());
+        }
+
+        let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(data) {
+            Ok(v) => v,
+            Err(_) => return Err(InvalidMetadata.into()),
+        };
+
+        match metadata.magic {
+            STRM_MAGIC => {}
+            0 | LEGACY_VERSION => metadata.magic = STRM_MAGIC,
+            v if v < LEGACY_VERSION => {}
+            v => {
+                msg!("Error: Unsupported metadata magic {:#x}", v);
+                return Err(UnsupportedVersion.into());
+            }
+        }
+
+        match metadata.ext.version {
+            0 | PROGRAM_VERSION => {}
+            v => {
+                msg!("Error: Unsupported metadata version {}", v);
+                return Err(UnsupportedVersion.into());
+            }
         }
+
+        Ok(metadata)
+    }
+
+    /// The `StreamState` part of the metadata.
+    pub fn state(&self) -> StreamState {
+        StreamState {
+            magic: self.magic,
+            created_at: self.created_at,
+            withdrawn_amount: self.withdrawn_amount,
+            canceled_at: self.canceled_at,
+            closable_at: self.closable_at,
+            last_withdrawn_at: self.last_withdrawn_at,
+        }
+    }
+
+    /// Write only the `StreamState` part of the metadata into the account
+    /// `data`, for processors that don't change the configuration.
+    pub fn save_state(&self, data: &mut [u8]) -> Result<(), ProgramError> {
+        if data.len() < STATE_SIZE {
+            return Err(InvalidMetadataSize.into());
+        }
+
+        let bytes = self.state().try_to_vec()?;
+        data[..STATE_SIZE].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Layout version of the metadata, `LEGACY_VERSION` for streams without
+    /// a `StreamExtension`.
+    pub fn version(&self) -> u64 {
+        match self.ext.version {
+            0 => LEGACY_VERSION,
+            v => v,
+        }
+    }
+
+    /// Check that the metadata has a layout the handlers can operate on,
+    /// which is all but streams of a version before `LEGACY_VERSION` that
+    /// `unpack` returns.
+    ///
+    /// Handlers mutating the stream must call this so they never operate on
+    /// an older layout they'd misinterpret. There's no migration for those.
+    /// Legacy streams are handled as if created with the default
+    /// `StreamOptions`, which is what their `ext` holds.
+    pub fn check_version(&self) -> Result<(), ProgramError> {
+        if self.magic != STRM_MAGIC {
+            msg!(
+                "Error: Metadata version {} has an older layout than version {}",
+                self.magic,
+                LEGACY_VERSION
+            );
+            return Err(VersionMismatch.into());
+        }
+
+        match self.version() {
+            LEGACY_VERSION | PROGRAM_VERSION => {}
+            v => {
+                msg!("Error: Unsupported metadata version {}", v);
+                return Err(UnsupportedVersion.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the invariants every stream must hold, whatever its history.
+    ///
+    /// Processors only call it before saving the metadata when built with
+    /// the `strict-invariants` feature, to not spend compute on mainnet.
+    pub fn check_invariants(&self) -> Result<(), StreamFlowError> {
+        if self.withdrawn_amount > self.ix.deposited_amount {
+            return Err(WithdrawnExceedsDeposited);
+        }
+
+        if self.canceled_at != 0 && self.canceled_at < self.created_at {
+            return Err(InvalidCancelTime);
+        }
+
+        if self.ix.end_time < self.ix.start_time || self.ix.end_time < self.effective_start() {
+            return Err(InvalidTimestamps);
+        }
+
+        Ok(())
+    }
+
+    /// Check that the given token accounts are the stream's, failing with
+    /// the error naming the first one that isn't.
+    pub fn validate_accounts(&self, keys: &StreamTokenKeys) -> Result<(), StreamFlowError> {
+        let checks = [
+            (keys.mint, &self.mint, MintMismatch),
+            (keys.escrow_tokens, &self.escrow_tokens, EscrowMismatch),
+            (
+                keys.sender_tokens,
+                &self.sender_tokens,
+                SenderTokensMismatch,
+            ),
+            (
+                keys.recipient_tokens,
+                &self.recipient_tokens,
+                RecipientTokensMismatch,
+            ),
+        ];
+
+        for (given, expected, err) in checks {
+            if given.is_some_and(|key| key != expected) {
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the stream is funded for at most `MAX_DURATION` from its
+    /// start. `validate` bounds `end_time`, but recurring streams ignore it
+    /// and run for as long as their deposit lasts.
+    pub fn check_duration(&self) -> Result<(), StreamFlowError> {
+        if validate_end_time(self.ix.start_time, self.closable_at).is_err() {
+            return Err(DurationTooLong);
+        }
+
+        Ok(())
     }
 
     /// Calculate amount available for withdrawal with given timestamp.
+    /// A stream split off with `split` may have withdrawn a token ahead of
+    /// its own schedule, nothing is available until that catches up.
     pub fn available(&self, now: u64) -> u64 {
-        if self.ix.start_time > now || self.ix.cliff > now {
-            return 0;
+        self.vested(now).saturating_sub(self.withdrawn_amount)
+    }
+
+    /// Record a withdrawal of `amount` at `now`, with 0 meaning everything
+    /// available. Returns the amount to transfer to the recipient.
+    ///
+    /// Withdrawing before the stream's `withdrawable_after`, or from a lock
+    /// contract before it unlocks, fails. Other streams keep allowing it as
+    /// a no-op of 0 before their start or cliff.
+    pub fn withdraw(&mut self, amount: u64, now: u64) -> Result<u64, ProgramError> {
+        if now < self.ext.options.withdrawable_after {
+            msg!(
+                "Error: Nothing can be withdrawn before {}",
+                self.ext.options.withdrawable_after
+            );
+            return Err(WithdrawLocked.into());
+        }
+
+        if self.is_lock() && now < self.ix.cliff {
+            msg!("Error: Nothing is unlocked before the lock's unlock time");
+            return Err(StreamNotStarted.into());
+        }
+
+        let available = self.available(now);
+        if amount > available {
+            msg!("Amount requested for withdraw is more than what is available");
+            return Err(ProgramError::InvalidArgument);
+        }
+
+        // 0 == MAX
+        let requested = if amount == 0 { available } else { amount };
+
+        self.withdrawn_amount += requested;
+        self.last_withdrawn_at = self.period_boundary(now);
+        Ok(requested)
+    }
+
+    /// Move the end of the stream to `end_time`, keeping the amount released
+    /// per period, and record the deposit that takes. Returns the amount the
+    /// sender has to deposit, which may be 0 if the stream is overfunded.
+    ///
+    /// Recurring streams need enough for every period started before
+    /// `end_time`, linear ones have their total scaled up along with the
+    /// duration (rounded down).
+    pub fn extend_to(&mut self, end_time: u64) -> Result<u64, ProgramError> {
+        let needed = self
+            .schedule()
+            .total_to_extend(end_time)
+            .map_err(math_error)?;
+
+        let additional = needed.saturating_sub(self.ix.deposited_amount);
+        self.ix.end_time = end_time;
+        self.ix.total_amount = self.ix.total_amount.max(needed);
+        self.ix.deposited_amount += additional;
+        self.closable_at = self.closable();
+
+        Ok(additional)
+    }
+
+    /// Record a topup of `amount`, moving `closable_at` along, see
+    /// `check_duration`.
+    pub fn topup(&mut self, amount: u64) -> Result<(), ProgramError> {
+        let mut schedule = self.schedule();
+        schedule.deposited_amount = schedule
+            .deposited_amount
+            .checked_add(amount)
+            .ok_or(InvalidAmounts)?;
+
+        let closable_at = schedule.closable();
+        if let Err(e) = validate_end_time(self.ix.start_time, closable_at) {
+            msg!("Error: Topup would fund the stream past its maximum duration");
+            return Err(math_error(e));
+        }
+
+        self.ix.deposited_amount = schedule.deposited_amount;
+        self.closable_at = closable_at;
+        Ok(())
+    }
+
+    /// Split `amount` of the deposit off into a new stream at `now`, which is
+    /// returned with `recipient`, `recipient_tokens` and `escrow_tokens`
+    /// left for the caller to set. Everything else is copied, so both
+    /// streams keep the schedule, permissions and creation time.
+    ///
+    /// The split takes the share `amount / deposited_amount` of the total,
+    /// cliff amount, release rate and what's already withdrawn, rounded
+    /// down, and the rest stays. Each stream rounds its vested amount down
+    /// on its own, so together they may vest a token less than the original
+    /// in between unlocks, never more, and exactly as much once the original
+    /// would be done. Both get their own `closable_at` for their deposit.
+    ///
+    /// What's vested stays with the current recipient, who has to withdraw
+    /// it first. The split's share of the withdrawals then covers its share
+    /// of what's vested, so only unvested tokens move: the escrow has to
+    /// move `deposited_amount - withdrawn_amount` of the returned stream
+    /// to its new escrow.
+    pub fn split(&mut self, amount: u64, now: u64) -> Result<TokenStreamData, ProgramError> {
+        if self.canceled_at > 0 {
+            return Err(StreamClosed.into());
+        }
+
+        if self.available(now) > 0 {
+            return Err(VestedNotWithdrawn.into());
+        }
+
+        if amount == 0 || amount >= self.ix.deposited_amount {
+            msg!("Error: Split amount must be between 0 and the deposit");
+            return Err(InvalidAmounts.into());
+        }
+
+        let schedule = self.schedule();
+        let share = |x: u64| schedule.share_of(x, amount);
+
+        let mut split = self.clone();
+        split.ix.deposited_amount = amount;
+        split.ix.total_amount = share(self.ix.total_amount);
+        split.ix.cliff_amount = share(self.ix.cliff_amount);
+        split.ix.release_rate = share(self.ix.release_rate);
+        split.withdrawn_amount = share(self.withdrawn_amount);
+
+        // Neither part may turn from recurring into linear
+        if self.ix.release_rate > 0
+            && (split.ix.release_rate == 0 || split.ix.release_rate == self.ix.release_rate)
+        {
+            msg!("Error: Split would leave a release rate of 0");
+            return Err(InvalidAmounts.into());
+        }
+
+        let mut kept = self.clone();
+        kept.ix.deposited_amount -= amount;
+        kept.ix.total_amount -= split.ix.total_amount;
+        kept.ix.cliff_amount -= split.ix.cliff_amount;
+        kept.ix.release_rate -= split.ix.release_rate;
+        kept.withdrawn_amount -= split.withdrawn_amount;
+
+        // A smaller rate rounded down streams for longer
+        for stream in [&mut kept, &mut split] {
+            stream.closable_at = stream.closable();
+            if let Err(e) = validate_end_time(stream.ix.start_time, stream.closable_at) {
+                msg!("Error: Split would stream past the maximum duration");
+                return Err(math_error(e));
+            }
+        }
+
+        *self = kept;
+        Ok(split)
+    }
+
+    /// What cancelling at `now` would pay out, exactly as `cancel` does.
+    pub fn refundable_on_cancel(&self, now: u64) -> RefundBreakdown {
+        let to_recipient = self.available(now);
+        RefundBreakdown {
+            to_sender: self.ix.deposited_amount - self.withdrawn_amount - to_recipient,
+            to_recipient,
         }
+    }
+
+    /// Record the cancellation at `now`, releasing whatever is available.
+    /// Returns the amounts to transfer to the recipient and back to the sender.
+    pub fn cancel(&mut self, now: u64) -> RefundBreakdown {
+        let refund = self.refundable_on_cancel(now);
+        self.withdrawn_amount += refund.to_recipient;
 
-        // Ignore end date when recurring
-        if now >= self.ix.end_time && self.ix.release_rate == 0 {
-            return self.ix.deposited_amount - self.withdrawn_amount;
+        if now < self.closable_at {
+            if refund.to_recipient > 0 {
+                self.last_withdrawn_at = self.period_boundary(now);
+            }
+            self.canceled_at = now;
         }
 
-        let cliff = if self.ix.cliff > 0 {
-            self.ix.cliff
+        refund
+    }
+
+    /// Amount vested (unlocked) by `ts`, whether withdrawn or not.
+    /// Capped at the deposited amount and frozen at the time of cancellation.
+    pub fn vested_amount_at(&self, ts: u64) -> u64 {
+        let ts = if self.canceled_at > 0 {
+            ts.min(self.canceled_at)
         } else {
-            self.ix.start_time
+            ts
         };
 
-        let cliff_amount = if self.ix.cliff_amount > 0 {
-            self.ix.cliff_amount
+        self.vested(ts)
+    }
+
+    /// Amount still locked in the stream at `ts`, e.g. for weighting votes at
+    /// a governance snapshot. Zero once the stream is canceled, since the
+    /// unvested part is returned to the sender.
+    ///
+    /// ```
+    /// use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+    /// use streamflow_timelock::state::TokenStreamData;
+    ///
+    /// // In a governance program, given the stream's metadata account
+    /// fn voting_weight(
+    ///     metadata: &AccountInfo,
+    ///     timelock_program_id: &Pubkey,
+    ///     snapshot: u64,
+    /// ) -> Result<u64, ProgramError> {
+    ///     let stream = TokenStreamData::load_checked(metadata, timelock_program_id)?;
+    ///     Ok(stream.locked_amount_at(snapshot))
+    /// }
+    /// ```
+    pub fn locked_amount_at(&self, ts: u64) -> u64 {
+        if self.canceled_at > 0 && ts >= self.canceled_at {
+            return 0;
+        }
+
+        self.ix
+            .deposited_amount
+            .saturating_sub(self.vested_amount_at(ts))
+    }
+
+    /// Total amount unlocked by the stream schedule at `now`, capped at the
+    /// deposited amount so a final partial period releases the remainder.
+    fn vested(&self, now: u64) -> u64 {
+        self.schedule().vested(now)
+    }
+
+    /// Raw token `amount` of the stream's mint in UI units, like
+    /// `spl_token::amount_to_ui_amount`. Legacy streams don't snapshot the
+    /// mint decimals, their amounts need the mint fetched to render.
+    pub fn ui_amount(&self, amount: u64) -> f64 {
+        amount as f64 / 10_u64.pow(self.ext.mint_decimals.into()) as f64
+    }
+
+    /// Raw token `amount` of the stream's mint in UI units, formatted
+    /// exactly without trailing zeros, e.g. "1.5" rather than "1.500000".
+    pub fn ui_amount_str(&self, amount: u64) -> String {
+        encode_base10(amount, self.ext.mint_decimals.into())
+    }
+
+    /// Whether the stream was created as a cliff-only lock contract.
+    /// Legacy streams never are, lock contracts came with their options.
+    pub fn is_lock(&self) -> bool {
+        self.ext.options.stream_type == StreamType::Lock
+    }
+
+    /// The vesting schedule of the stream, for the math shared with
+    /// off-chain and `no_std` users.
+    pub fn schedule(&self) -> Schedule {
+        self.ix.schedule(self.created_at)
+    }
+
+    /// Timestamp the schedule is anchored to: the instruction's effective
+    /// start, or the creation time for streams that were created with a start
+    /// in the past, so nothing vests retroactively. The requested start time
+    /// is kept in `ix.start_time`.
+    pub fn effective_start(&self) -> u64 {
+        self.schedule().effective_start()
+    }
+
+    /// Number of whole periods passed since the cliff (or start, when there
+    /// is no cliff) at the given timestamp.
+    pub fn periods_passed(&self, now: u64) -> u64 {
+        self.schedule().periods_passed(now)
+    }
+
+    /// Timestamp of the last period boundary at or before `now`, i.e. up to
+    /// which point funds are unlocked. Before the cliff (or start) that's the
+    /// cliff itself, and past the end of a non-recurring stream it's `end_time`.
+    pub fn period_boundary(&self, now: u64) -> u64 {
+        self.schedule().period_boundary(now)
+    }
+
+    /// Timestamp of the next unlock after `now`, counting the cliff as the
+    /// first one, or `None` when everything deposited is already vested.
+    pub fn next_unlock_timestamp(&self, now: u64) -> Option<u64> {
+        if self.canceled_at > 0 || self.vested_amount_at(now) >= self.ix.deposited_amount {
+            return None;
+        }
+
+        let start = self.effective_start();
+        let next = if now < start && self.ix.cliff > 0 && self.ix.cliff_amount > 0 {
+            start
         } else {
-            0
+            self.period_boundary(now.max(start)) + self.ix.period
         };
 
-        // TODO: Use uint arithmetics, floats are imprecise
-        let num_periods = (self.ix.end_time - cliff) as f64 / self.ix.period as f64;
-        let period_amount = if self.ix.release_rate > 0 {
-            self.ix.release_rate as f64
+        // Whatever is left unlocks at the end, even off the period grid
+        if self.ix.release_rate == 0 {
+            Some(next.min(self.ix.end_time))
         } else {
-            (self.ix.total_amount - cliff_amount) as f64 / num_periods
-        };
-        let periods_passed = (now - cliff) / self.ix.period;
-        (periods_passed as f64 * period_amount) as u64 + cliff_amount - self.withdrawn_amount
+            Some(next)
+        }
+    }
+
+    /// Amount released per second after the cliff, rounded down. See
+    /// `Schedule::rate_per_second` for how it's derived.
+    pub fn effective_rate_per_second(&self) -> Result<u64, ProgramError> {
+        self.schedule().rate_per_second().map_err(math_error)
+    }
+
+    /// What `effective_rate_per_second` floors away. See
+    /// `Schedule::rate_per_second_remainder` for what it's a fraction of.
+    pub fn effective_rate_per_second_remainder(&self) -> Result<u64, ProgramError> {
+        self.schedule()
+            .rate_per_second_remainder()
+            .map_err(math_error)
     }
 
     /// Calculate timestamp when stream is cancellable
     /// end_time when deposit=total else time when funds run out
     pub fn closable(&self) -> u64 {
-        let cliff_time = if self.ix.cliff > 0 {
-            self.ix.cliff
-        } else {
-            self.ix.start_time
-        };
-
-        let cliff_amount = if self.ix.cliff_amount > 0 {
-            self.ix.cliff_amount
-        } else {
-            0
-        };
-        // Deposit smaller then cliff amount, cancelable at cliff
-        if self.ix.deposited_amount < cliff_amount {
-            return cliff_time;
-        }
-        // Nr of seconds after the cliff
-        let seconds_nr = self.ix.end_time - cliff_time;
+        self.schedule().closable()
+    }
+}
 
-        let amount_per_second = if self.ix.release_rate > 0 {
-            self.ix.release_rate / self.ix.period
-        } else {
-            // stream per second
-            ((self.ix.total_amount - cliff_amount) / seconds_nr) as u64
-        };
-        // Seconds till account runs out of available funds, +1 as ceil (integer)
-        let seconds_left = ((self.ix.deposited_amount - cliff_amount) / amount_per_second) + 1;
+/// The cancel and transfer permissions of a stream, as updated by the
+/// sender and recipient.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct StreamPermissions {
+    /// Whether or not a stream can be canceled by a sender
+    pub cancelable_by_sender: bool,
+    /// Whether or not a stream can be canceled by a recipient
+    pub cancelable_by_recipient: bool,
+    /// Whether or not the sender can transfer the stream
+    pub transferable_by_sender: bool,
+    /// Whether or not the recipient can transfer the stream
+    pub transferable_by_recipient: bool,
+}
 
-        msg!(
-            "Release {}, Period {}, seconds left {}",
-            self.ix.release_rate,
-            self.ix.period,
-            seconds_left
-        );
-        // closable_at time, ignore end time when recurring
-        if cliff_time + seconds_left > self.ix.end_time && self.ix.release_rate == 0 {
-            self.ix.end_time
-        } else {
-            cliff_time + seconds_left
+impl StreamPermissions {
+    /// Whether the sender and the recipient, respectively, have to sign to
+    /// change these permissions into `new`. Each changed flag needs the
+    /// party it works against: granting a right needs the other party,
+    /// while giving one up needs its holder.
+    pub fn required_signers(&self, new: &StreamPermissions) -> (bool, bool) {
+        let (mut sender, mut recipient) = (false, false);
+        for (old, new, senders_right) in [
+            (self.cancelable_by_sender, new.cancelable_by_sender, true),
+            (
+                self.cancelable_by_recipient,
+                new.cancelable_by_recipient,
+                false,
+            ),
+            (
+                self.transferable_by_sender,
+                new.transferable_by_sender,
+                true,
+            ),
+            (
+                self.transferable_by_recipient,
+                new.transferable_by_recipient,
+                false,
+            ),
+        ] {
+            if old == new {
+                continue;
+            }
+            // Granting a sender's right, or revoking a recipient's one
+            if new == senders_right {
+                recipient = true;
+            } else {
+                sender = true;
+            }
         }
+        (sender, recipient)
     }
 }
 
-/// The account-holding struct for the stream initialization instruction
+/// How the tokens left in escrow are split when a stream is canceled.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct RefundBreakdown {
+    /// Unvested tokens returned to the sender
+    pub to_sender: u64,
+    /// Vested but not yet withdrawn tokens released to the recipient
+    pub to_recipient: u64,
+}
+
+/// Most streams a registry indexes, further ones are simply not indexed.
+pub const REGISTRY_MAX_STREAMS: usize = 50;
+
+/// Allocated size of a registry account, fits `REGISTRY_MAX_STREAMS` keys.
+pub const REGISTRY_SIZE: usize = 4 + REGISTRY_MAX_STREAMS * 32;
+
+/// StreamRegistry is an advisory per-recipient index of stream metadata
+/// accounts, living at the `find_registry_account` PDA. It lets wallets
+/// find a recipient's streams without scanning all program accounts.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct StreamRegistry {
+    /// Metadata accounts of the recipient's streams
+    pub streams: Vec<Pubkey>,
+}
+
+impl StreamRegistry {
+    /// Index `metadata`, returns false if it's already there or the
+    /// registry is full.
+    pub fn add(&mut self, metadata: &Pubkey) -> bool {
+        if self.streams.len() >= REGISTRY_MAX_STREAMS || self.streams.contains(metadata) {
+            return false;
+        }
+
+        self.streams.push(*metadata);
+        true
+    }
+
+    /// Read the registry from its account data, as written by `pack`.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        solana_borsh::try_from_slice_unchecked(data).map_err(|_| InvalidMetadata.into())
+    }
+
+    /// Write the registry into its account data, zeroing what's left.
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        let bytes = self.try_to_vec()?;
+        if bytes.len() > dst.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        dst[..bytes.len()].copy_from_slice(&bytes);
+        dst[bytes.len()..].fill(0);
+        Ok(())
+    }
+
+    /// Drop `metadata` from the index, returns false if it wasn't there.
+    pub fn remove(&mut self, metadata: &Pubkey) -> bool {
+        let len = self.streams.len();
+        self.streams.retain(|k| k != metadata);
+        self.streams.len() != len
+    }
+}
+
+/// Allocated size of a sender stream counter account.
+pub const COUNTER_SIZE: usize = 8;
+
+/// StreamCounter is an advisory count of a sender's open streams, living at
+/// the `find_sender_counter_account` PDA. It's incremented on create and
+/// decremented once a stream is canceled or fully withdrawn.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct StreamCounter {
+    /// Number of the sender's open streams
+    pub count: u64,
+}
+
+impl StreamCounter {
+    /// Read the counter from its account data.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        solana_borsh::try_from_slice_unchecked(data).map_err(|_| InvalidMetadata.into())
+    }
+
+    /// Write the counter into its account data.
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        let bytes = self.try_to_vec()?;
+        if bytes.len() > dst.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        dst[..bytes.len()].copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+/// Allocated size of a mint stats account.
+pub const MINT_STATS_SIZE: usize = 24;
+
+/// MintStats is an advisory tally of a mint's streams, living at the
+/// `find_mint_stats_account` PDA, so the value locked per mint is known
+/// without scanning every stream. It's best-effort: only instructions it's
+/// passed to update it, so streams created without it are missing, and
+/// topups and extends aren't counted. All arithmetic saturates, so
+/// a missed update never makes a later one fail.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct MintStats {
+    /// Amount deposited at the creation of counted streams
+    pub total_net_deposited: u64,
+    /// Amount paid out of escrows, withdrawn or returned on cancel
+    pub total_withdrawn: u64,
+    /// Number of streams created and not yet fully withdrawn or canceled
+    pub active_streams: u64,
+}
+
+impl MintStats {
+    /// Read the stats from their account data.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        solana_borsh::try_from_slice_unchecked(data).map_err(|_| InvalidMetadata.into())
+    }
+
+    /// Write the stats into their account data.
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        let bytes = self.try_to_vec()?;
+        if bytes.len() > dst.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        dst[..bytes.len()].copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    /// Count a stream created with `deposited_amount`.
+    pub fn add_stream(&mut self, deposited_amount: u64) {
+        self.total_net_deposited = self.total_net_deposited.saturating_add(deposited_amount);
+        self.active_streams = self.active_streams.saturating_add(1);
+    }
+
+    /// Count `amount` paid out of a stream's escrow, and the stream as
+    /// ended when `closed`.
+    pub fn add_payout(&mut self, amount: u64, closed: bool) {
+        self.total_withdrawn = self.total_withdrawn.saturating_add(amount);
+        if closed {
+            self.active_streams = self.active_streams.saturating_sub(1);
+        }
+    }
+
+    /// Amount still locked in the counted streams.
+    pub fn locked(&self) -> u64 {
+        self.total_net_deposited
+            .saturating_sub(self.total_withdrawn)
+    }
+}
+
+/// Most key/value pairs a stream's labels hold.
+pub const LABELS_MAX_PAIRS: usize = 8;
+
+/// Most bytes of keys and values together a stream's labels hold.
+pub const LABELS_MAX_BYTES: usize = 512;
+
+/// Allocated size of a labels account, fits the largest valid labels.
+pub const LABELS_SIZE: usize = 4 + 4 + 2 * LABELS_MAX_PAIRS * 4 + LABELS_MAX_BYTES;
+
+/// StreamLabels are free-form key/value pairs the sender attaches to a
+/// stream, living at the `find_labels_account` PDA of its metadata, so
+/// references longer than `stream_name` don't touch the metadata layout.
+#[derive(BorshSerialize, BorshDeserialize, Default, Debug, PartialEq)]
+#[repr(C)]
+pub struct StreamLabels {
+    /// Label keys
+    pub keys: Vec<String>,
+    /// Label values, matching `keys` by index
+    pub values: Vec<String>,
+}
+
+impl StreamLabels {
+    /// Check the labels fit in `LABELS_SIZE` and every key has a value.
+    pub fn validate(&self) -> Result<(), StreamFlowError> {
+        let bytes: usize = self.keys.iter().chain(&self.values).map(|s| s.len()).sum();
+        if self.keys.len() != self.values.len()
+            || self.keys.len() > LABELS_MAX_PAIRS
+            || bytes > LABELS_MAX_BYTES
+        {
+            return Err(InvalidLabels);
+        }
+
+        Ok(())
+    }
+
+    /// Read the labels from their account data, as written by `pack`.
+    pub fn unpack(data: &[u8]) -> Result<Self, ProgramError> {
+        solana_borsh::try_from_slice_unchecked(data).map_err(|_| InvalidMetadata.into())
+    }
+
+    /// Write the labels into their account data, zeroing what's left.
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        let bytes = self.try_to_vec()?;
+        if bytes.len() > dst.len() {
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        dst[..bytes.len()].copy_from_slice(&bytes);
+        dst[bytes.len()..].fill(0);
+        Ok(())
+    }
+}
+
+/// The account-holding struct for the stream initialization instruction
 #[derive(Debug)]
 pub struct InitializeAccounts<'a> {
     /// The main wallet address of the initializer.
@@ -282,6 +1318,16 @@ pub struct InitializeAccounts<'a> {
     pub associated_token_program: AccountInfo<'a>,
     /// The Solana system program
     pub system_program: AccountInfo<'a>,
+    /// Optional `recipient` stream registry, created if empty.
+    pub registry: Option<AccountInfo<'a>>,
+    /// Optional `sender` stream counter, created if empty.
+    /// Needs `registry` to be passed as well.
+    pub sender_counter: Option<AccountInfo<'a>>,
+    /// Optional `mint` stats, created if empty.
+    /// Needs `registry` and `sender_counter` to be passed as well.
+    pub mint_stats: Option<AccountInfo<'a>>,
+    /// The SPL Memo program, passed last and only along with a memo.
+    pub memo_program: Option<AccountInfo<'a>>,
 }
 
 /// The account-holding struct for the stream withdraw instruction
@@ -305,11 +1351,22 @@ pub struct WithdrawAccounts<'a> {
     pub mint: AccountInfo<'a>,
     /// The SPL token program
     pub token_program: AccountInfo<'a>,
+    /// The stream labels account, only taken by collect, which closes it
+    /// along with the metadata if the collect completes the stream.
+    pub labels: Option<AccountInfo<'a>>,
+    /// Optional `recipient` stream registry, pruned once all is withdrawn.
+    pub registry: Option<AccountInfo<'a>>,
+    /// Optional `sender` stream counter, decremented once all is withdrawn.
+    pub sender_counter: Option<AccountInfo<'a>>,
+    /// Optional `mint` stats, counting the withdrawal.
+    pub mint_stats: Option<AccountInfo<'a>>,
+    /// The SPL Memo program, passed last and only along with a memo.
+    pub memo_program: Option<AccountInfo<'a>>,
 }
 
 /// The account-holding struct for the stream cancel instruction
 pub struct CancelAccounts<'a> {
-    /// Account invoking cancel. Must match `sender`.
+    /// Account invoking cancel, must sign.
     /// Can be either `sender` or `recipient` depending on the value
     /// of `cancelable_by_sender` and `cancelable_by_recipient`
     /// But when stream expires anyone can cancel
@@ -330,6 +1387,17 @@ pub struct CancelAccounts<'a> {
     pub mint: AccountInfo<'a>,
     /// The SPL token program
     pub token_program: AccountInfo<'a>,
+    /// The stream labels account, only taken by cancel and close, which
+    /// closes it along with the metadata if the sender set any labels.
+    pub labels: Option<AccountInfo<'a>>,
+    /// Optional `recipient` stream registry, pruned on cancel.
+    pub registry: Option<AccountInfo<'a>>,
+    /// Optional `sender` stream counter, decremented on cancel.
+    pub sender_counter: Option<AccountInfo<'a>>,
+    /// Optional `mint` stats, counting the payout and the stream as ended.
+    pub mint_stats: Option<AccountInfo<'a>>,
+    /// The SPL Memo program, passed last and only along with a memo.
+    pub memo_program: Option<AccountInfo<'a>>,
 }
 
 /// Accounts needed for updating stream recipient
@@ -361,12 +1429,54 @@ pub struct TransferAccounts<'a> {
     pub system_program: AccountInfo<'a>,
 }
 
+/// Accounts needed for splitting part of a stream off to a new recipient
+pub struct SplitAccounts<'a> {
+    /// Recipient of the stream being split, pays for the new accounts
+    pub recipient: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+    /// The escrow account holding the stream funds
+    pub escrow_tokens: AccountInfo<'a>,
+    /// Recipient of the new stream
+    pub new_recipient: AccountInfo<'a>,
+    /// The associated token account of `new_recipient`.
+    /// If not initialized, it will be created.
+    pub new_recipient_tokens: AccountInfo<'a>,
+    /// The account for the new stream's metadata.
+    /// Expects empty (non-initialized) account.
+    pub new_metadata: AccountInfo<'a>,
+    /// The escrow account for the new stream's funds.
+    /// Expects empty (non-initialized) account.
+    pub new_escrow_tokens: AccountInfo<'a>,
+    /// The SPL token mint account
+    pub mint: AccountInfo<'a>,
+    /// The Rent Sysvar account
+    pub rent: AccountInfo<'a>,
+    /// The SPL Token program
+    pub token_program: AccountInfo<'a>,
+    /// The Associated Token program needed in case associated
+    /// account for the new recipient is being created.
+    pub associated_token_program: AccountInfo<'a>,
+    /// The Solana system program
+    pub system_program: AccountInfo<'a>,
+    /// Optional `new_recipient` stream registry, created if empty.
+    pub registry: Option<AccountInfo<'a>>,
+    /// Optional `sender` stream counter, created if empty.
+    /// Needs `registry` to be passed as well.
+    pub sender_counter: Option<AccountInfo<'a>>,
+    /// Optional `mint` stats, created if empty.
+    /// Needs `registry` and `sender_counter` to be passed as well.
+    pub mint_stats: Option<AccountInfo<'a>>,
+}
+
 /// The account-holding struct for the stream topup instruction
 #[derive(Debug)]
 pub struct TopUpAccounts<'a> {
-    /// The main wallet address of the initializer.
+    /// The funder, signing for `sender_tokens`. The main wallet address of
+    /// the initializer, or anyone for a topup of a stream allowing external
+    /// topups.
     pub sender: AccountInfo<'a>,
-    /// The associated token account address of `sender`.
+    /// The funder's token account the deposit is taken from.
     pub sender_tokens: AccountInfo<'a>,
     /// The account holding the stream metadata.
     /// Expects existing account.
@@ -378,4 +1488,1642 @@ pub struct TopUpAccounts<'a> {
     pub mint: AccountInfo<'a>,
     /// The SPL program needed for transfer
     pub token_program: AccountInfo<'a>,
+    /// The SPL Memo program, passed last and only along with a memo.
+    pub memo_program: Option<AccountInfo<'a>>,
+}
+
+/// The account-holding struct for the update permissions instruction
+#[derive(Debug)]
+pub struct UpdatePermissionsAccounts<'a> {
+    /// The stream sender, must sign for changes working against them
+    pub sender: AccountInfo<'a>,
+    /// The stream recipient, must sign for changes working against them
+    pub recipient: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+}
+
+/// The account-holding struct for the set and clear labels instructions
+#[derive(Debug)]
+pub struct LabelsAccounts<'a> {
+    /// The stream sender, the only one allowed to label it.
+    /// Pays the labels rent and gets it back on clear.
+    pub sender: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+    /// The stream labels account, at the `find_labels_account` PDA
+    pub labels: AccountInfo<'a>,
+    /// The Solana system program needed to create the labels account
+    pub system_program: AccountInfo<'a>,
+}
+
+#[cfg(test)]
+mod tests {
+    use borsh::BorshSerialize;
+    use solana_program::{account_info::AccountInfo, program_error::ProgramError, pubkey::Pubkey};
+
+    use crate::error::StreamFlowError::{
+        self, CannotShortenStream, DurationTooLong, EscrowMismatch, InvalidAmounts, InvalidCliff,
+        InvalidLabels, InvalidLock, InvalidPeriod, InvalidTimestamps, LockUnderfunded,
+        MintMismatch, RecipientTokensMismatch, SenderTokensMismatch, StreamNotStarted,
+        VestedNotWithdrawn, WithdrawLocked, ZeroDeposit,
+    };
+    use crate::state::{
+        MintStats, StreamExtension, StreamInstruction, StreamLabels, StreamOptions,
+        StreamPermissions, StreamRegistry, StreamTokenKeys, TokenStreamData, COUNTER_SIZE,
+        LABELS_MAX_BYTES, LABELS_MAX_PAIRS, LABELS_SIZE, LEGACY_VERSION, MAX_DURATION, MAX_PERIOD,
+        MAX_START_DELAY, MAX_STRING_SIZE, METADATA_MAX_SIZE, METADATA_MIN_SIZE, MINT_OFFSET,
+        MINT_STATS_SIZE, PROGRAM_VERSION, RECIPIENT_OFFSET, REGISTRY_MAX_STREAMS, REGISTRY_SIZE,
+        SENDER_OFFSET, STATE_SIZE, STRM_MAGIC, STRM_MAGIC_BYTES,
+    };
+
+    fn load(
+        metadata: &TokenStreamData,
+        owner: &Pubkey,
+        pid: &Pubkey,
+    ) -> Result<TokenStreamData, ProgramError> {
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = metadata.try_to_vec().unwrap();
+        let account =
+            AccountInfo::new(&key, false, true, &mut lamports, &mut data, owner, false, 0);
+        TokenStreamData::load_checked(&account, pid)
+    }
+
+    #[test]
+    fn test_metadata_min_size() {
+        let metadata = TokenStreamData {
+            ix: StreamInstruction {
+                stream_name: String::new(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(metadata.try_to_vec().unwrap().len(), METADATA_MIN_SIZE);
+    }
+
+    #[test]
+    fn test_load_checked() {
+        let pid = Pubkey::new_unique();
+        let metadata = TokenStreamData {
+            magic: STRM_MAGIC,
+            ..Default::default()
+        };
+
+        let loaded = load(&metadata, &pid, &pid).unwrap();
+        assert_eq!(loaded.magic, STRM_MAGIC);
+
+        // Wrong owner
+        assert_eq!(
+            load(&metadata, &Pubkey::new_unique(), &pid).err().unwrap(),
+            ProgramError::Custom(StreamFlowError::InvalidMetadataOwner as u32)
+        );
+
+        // Newer version than this program knows about
+        let newer = TokenStreamData {
+            magic: LEGACY_VERSION + 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            load(&newer, &pid, &pid).err().unwrap(),
+            ProgramError::Custom(StreamFlowError::UnsupportedVersion as u32)
+        );
+
+        // Not stream metadata at all
+        let foreign = TokenStreamData {
+            magic: u64::from_le_bytes(*b"REGISTRY"),
+            ..Default::default()
+        };
+        assert_eq!(
+            load(&foreign, &pid, &pid).err().unwrap(),
+            ProgramError::Custom(StreamFlowError::UnsupportedVersion as u32)
+        );
+    }
+
+    #[test]
+    fn test_load_checked_bad_data() {
+        let pid = Pubkey::new_unique();
+        let key = Pubkey::new_unique();
+        let mut lamports = 0;
+
+        let mut data = vec![];
+        let account = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &pid, false, 0);
+        assert_eq!(
+            TokenStreamData::load_checked(&account, &pid).err().unwrap(),
+            ProgramError::UninitializedAccount
+        );
+
+        let mut data = vec![0; METADATA_MIN_SIZE - 1];
+        let account = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &pid, false, 0);
+        assert_eq!(
+            TokenStreamData::load_checked(&account, &pid).err().unwrap(),
+            ProgramError::Custom(StreamFlowError::InvalidMetadataSize as u32)
+        );
+
+        // Name length prefix pointing way past the end of the account
+        let mut data = vec![0xff; METADATA_MIN_SIZE];
+        let account = AccountInfo::new(&key, false, true, &mut lamports, &mut data, &pid, false, 0);
+        assert_eq!(
+            TokenStreamData::load_checked(&account, &pid).err().unwrap(),
+            ProgramError::Custom(StreamFlowError::InvalidMetadata as u32)
+        );
+    }
+
+    #[test]
+    fn test_unpack_other_accounts() {
+        // The longest name still fits, padded like create allocates it
+        let mut metadata = TokenStreamData {
+            magic: STRM_MAGIC,
+            ..Default::default()
+        };
+        metadata.ix.stream_name = "x".repeat(MAX_STRING_SIZE);
+        let mut data = metadata.try_to_vec().unwrap();
+        data.resize(METADATA_MAX_SIZE, 0);
+        assert_eq!(TokenStreamData::unpack(&data).unwrap(), metadata);
+
+        // The program's other accounts, zeroed as freshly created, would
+        // otherwise read as legacy streams with a magic of 0
+        let mut registry = vec![0; REGISTRY_SIZE];
+        StreamRegistry {
+            streams: vec![Pubkey::new_unique()],
+        }
+        .pack(&mut registry)
+        .unwrap();
+        for data in [
+            vec![0; COUNTER_SIZE],
+            vec![0; MINT_STATS_SIZE],
+            vec![0; LABELS_SIZE],
+            registry,
+        ] {
+            assert_eq!(
+                TokenStreamData::unpack(&data),
+                Err(ProgramError::Custom(
+                    StreamFlowError::InvalidMetadataSize as u32
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_version() {
+        let pid = Pubkey::new_unique();
+        let older = TokenStreamData {
+            magic: LEGACY_VERSION - 1,
+            ..Default::default()
+        };
+
+        // Older versions can still be read, but not mutated
+        let loaded = load(&older, &pid, &pid).unwrap();
+        assert_eq!(
+            loaded.check_version().err().unwrap(),
+            ProgramError::Custom(StreamFlowError::VersionMismatch as u32)
+        );
+
+        let legacy = TokenStreamData {
+            magic: STRM_MAGIC,
+            ..Default::default()
+        };
+        let loaded = load(&legacy, &pid, &pid).unwrap();
+        assert_eq!(loaded.version(), LEGACY_VERSION);
+        assert!(loaded.check_version().is_ok());
+
+        let current = TokenStreamData {
+            magic: STRM_MAGIC,
+            ext: StreamExtension {
+                version: PROGRAM_VERSION,
+                options: StreamOptions {
+                    withdrawable_after: 1000,
+                    ..Default::default()
+                },
+                mint_decimals: 6,
+                last_topup_by: Pubkey::new_unique(),
+            },
+            ..Default::default()
+        };
+        let loaded = load(&current, &pid, &pid).unwrap();
+        assert_eq!(loaded, current);
+        assert_eq!(loaded.version(), PROGRAM_VERSION);
+        assert!(loaded.check_version().is_ok());
+
+        // A layout after this program's
+        let newer = TokenStreamData {
+            ext: StreamExtension {
+                version: PROGRAM_VERSION + 1,
+                ..current.ext.clone()
+            },
+            ..current.clone()
+        };
+        assert_eq!(
+            load(&newer, &pid, &pid).err().unwrap(),
+            ProgramError::Custom(StreamFlowError::UnsupportedVersion as u32)
+        );
+        let mut unpacked = current;
+        unpacked.ext.version = PROGRAM_VERSION + 1;
+        assert_eq!(
+            unpacked.check_version().err().unwrap(),
+            ProgramError::Custom(StreamFlowError::UnsupportedVersion as u32)
+        );
+    }
+
+    #[test]
+    fn test_load_checked_legacy_magic() {
+        let pid = Pubkey::new_unique();
+
+        // Grandfathered, and the magic gets written on next save
+        for magic in [0, LEGACY_VERSION] {
+            let legacy = TokenStreamData {
+                magic,
+                ..Default::default()
+            };
+            let loaded = load(&legacy, &pid, &pid).unwrap();
+            assert_eq!(loaded.magic, STRM_MAGIC);
+            assert!(loaded.check_version().is_ok());
+
+            let mut data = legacy.try_to_vec().unwrap();
+            loaded.save_state(&mut data).unwrap();
+            assert_eq!(data[0..8], STRM_MAGIC_BYTES);
+        }
+    }
+
+    fn lock(start_time: u64, unlock_time: u64, amount: u64) -> TokenStreamData {
+        TokenStreamData {
+            ix: StreamInstruction::lock(start_time, unlock_time, amount),
+            ext: StreamExtension {
+                version: PROGRAM_VERSION,
+                options: StreamOptions::lock(),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ui_amount() {
+        let mut stream = TokenStreamData::default();
+        assert_eq!(stream.ui_amount(1_500_000), 1_500_000.0);
+        assert_eq!(stream.ui_amount_str(1_500_000), "1500000");
+        assert_eq!(stream.ui_amount_str(0), "0");
+
+        stream.ext.mint_decimals = 6;
+        assert_eq!(stream.ui_amount(1_500_000), 1.5);
+        assert_eq!(stream.ui_amount_str(1_500_000), "1.5");
+        assert_eq!(stream.ui_amount_str(2_000_000), "2");
+        assert_eq!(stream.ui_amount_str(1_000_100), "1.0001");
+        assert_eq!(stream.ui_amount_str(10), "0.00001");
+        assert_eq!(stream.ui_amount_str(0), "0");
+
+        stream.ext.mint_decimals = 9;
+        assert_eq!(stream.ui_amount(1), 0.000000001);
+        assert_eq!(stream.ui_amount_str(1), "0.000000001");
+        assert_eq!(stream.ui_amount_str(120_000_000_000), "120");
+        assert_eq!(stream.ui_amount_str(u64::MAX), "18446744073.709551615");
+    }
+
+    #[test]
+    fn test_lock_available() {
+        let metadata = lock(100, 200, 1000);
+
+        assert!(metadata.is_lock());
+        assert_eq!(metadata.available(99), 0);
+        assert_eq!(metadata.available(100), 0);
+        assert_eq!(metadata.available(199), 0);
+        // Exact unlock second
+        assert_eq!(metadata.available(200), 1000);
+        assert_eq!(metadata.available(100_000), 1000);
+        assert_eq!(metadata.closable(), 200);
+    }
+
+    #[test]
+    fn test_withdrawable_after() {
+        let mut metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                ..Default::default()
+            },
+            ext: StreamExtension {
+                version: PROGRAM_VERSION,
+                options: StreamOptions {
+                    withdrawable_after: 600,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Vested, but locked up until withdrawable_after
+        assert_eq!(metadata.available(599), 400);
+        assert_eq!(
+            metadata.withdraw(0, 599),
+            Err(ProgramError::Custom(WithdrawLocked as u32))
+        );
+        assert_eq!(metadata.withdrawn_amount, 0);
+
+        assert_eq!(metadata.withdraw(0, 600), Ok(500));
+        assert_eq!(metadata.withdraw(0, 1100), Ok(500));
+
+        // 0 disables the lockup
+        metadata.ext.options.withdrawable_after = 0;
+        metadata.withdrawn_amount = 0;
+        assert_eq!(metadata.withdraw(0, 200), Ok(100));
+    }
+
+    #[test]
+    fn test_validate_options() {
+        let ix = valid_ix(1000);
+        let mut options = StreamOptions::default();
+        assert_eq!(options.validate(&ix), Ok(()));
+
+        options.withdrawable_after = ix.start_time + MAX_DURATION;
+        assert_eq!(options.validate(&ix), Ok(()));
+
+        options.withdrawable_after += 1;
+        assert_eq!(options.validate(&ix), Err(InvalidTimestamps));
+    }
+
+    #[test]
+    fn test_period_boundary() {
+        let metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 60,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(metadata.periods_passed(50), 0);
+        assert_eq!(metadata.periods_passed(159), 0);
+        assert_eq!(metadata.periods_passed(160), 1);
+        assert_eq!(metadata.periods_passed(219), 1);
+
+        assert_eq!(metadata.period_boundary(100), 100);
+        assert_eq!(metadata.period_boundary(159), 100);
+        assert_eq!(metadata.period_boundary(160), 160);
+        assert_eq!(metadata.period_boundary(219), 160);
+        // Everything is unlocked at the end, even off the period grid
+        assert_eq!(metadata.period_boundary(1100), 1100);
+        assert_eq!(metadata.period_boundary(5000), 1100);
+    }
+
+    #[test]
+    fn test_same_period_withdrawals() {
+        let mut metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Withdraw at the first second of a period, then try again
+        // at its last second: nothing more is unlocked until the next one.
+        let available = metadata.available(300);
+        assert_eq!(available, 200);
+        metadata.withdrawn_amount += available;
+        metadata.last_withdrawn_at = metadata.period_boundary(300);
+        assert_eq!(metadata.last_withdrawn_at, 300);
+
+        assert_eq!(metadata.available(399), 0);
+        assert_eq!(metadata.period_boundary(399), 300);
+        assert_eq!(metadata.available(400), 100);
+    }
+
+    #[test]
+    fn test_effective_start() {
+        let mut metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        for (cliff, start) in [(0, 100), (300, 300)] {
+            metadata.ix.cliff = cliff;
+            metadata.ix.cliff_amount = if cliff > 0 { 100 } else { 0 };
+            assert_eq!(metadata.ix.effective_start(), start);
+
+            assert_eq!(metadata.available(start - 1), 0);
+            assert_eq!(metadata.available(start), metadata.ix.cliff_amount);
+            assert_eq!(metadata.periods_passed(start + 99), 0);
+            assert_eq!(metadata.period_boundary(start + 99), start);
+            // Fully funded, so closable exactly at the end
+            assert_eq!(metadata.closable(), 1100);
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let linear = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 30,
+                cliff: 200,
+                cliff_amount: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let recurring = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 200,
+                deposited_amount: 990,
+                total_amount: 1000,
+                period: 10,
+                release_rate: 30,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        for original in [linear, recurring] {
+            // Whatever's vested has to be withdrawn first
+            let amount = original.ix.deposited_amount * 3 / 10;
+            assert_eq!(
+                original.clone().split(amount, 500),
+                Err(ProgramError::Custom(VestedNotWithdrawn as u32))
+            );
+
+            // 70/30, after withdrawing what's vested
+            let mut kept = original.clone();
+            kept.withdrawn_amount = kept.available(500);
+            let mut split = kept.split(amount, 500).unwrap();
+
+            assert_eq!(
+                kept.ix.deposited_amount + split.ix.deposited_amount,
+                original.ix.deposited_amount
+            );
+            // The withdrawal is shared like the deposit, covering what
+            // each part has vested, so the vested tokens stay withdrawn
+            assert_eq!(
+                kept.withdrawn_amount + split.withdrawn_amount,
+                original.available(500)
+            );
+            assert_eq!(split.withdrawn_amount, original.available(500) * 3 / 10);
+            assert_eq!(kept.available(500), 0);
+            assert_eq!(split.available(500), 0);
+            assert_eq!(kept.closable_at, kept.closable());
+            assert_eq!(split.closable_at, split.closable());
+
+            for now in 500..5000 {
+                let vested = kept.vested_amount_at(now) + split.vested_amount_at(now);
+                let original_vested = original.vested_amount_at(now);
+                assert!(vested <= original_vested && vested + 1 >= original_vested);
+            }
+
+            kept.withdrawn_amount += kept.available(5000);
+            split.withdrawn_amount += split.available(5000);
+            assert_eq!(kept.withdrawn_amount, kept.ix.deposited_amount);
+            assert_eq!(split.withdrawn_amount, split.ix.deposited_amount);
+        }
+
+        let mut stream = TokenStreamData {
+            ix: StreamInstruction {
+                deposited_amount: 100,
+                total_amount: 100,
+                release_rate: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for amount in [0, 100, 101] {
+            assert_eq!(
+                stream.split(amount, 0),
+                Err(ProgramError::Custom(InvalidAmounts as u32))
+            );
+        }
+        // A rate of 1 can't be shared
+        assert_eq!(
+            stream.split(50, 0),
+            Err(ProgramError::Custom(InvalidAmounts as u32))
+        );
+
+        stream.canceled_at = 1;
+        assert_eq!(
+            stream.split(50, 0),
+            Err(ProgramError::Custom(StreamFlowError::StreamClosed as u32))
+        );
+    }
+
+    #[test]
+    fn test_past_start_vests_from_creation() {
+        // Created at 110 with a start 10 seconds earlier
+        let mut metadata = TokenStreamData {
+            created_at: 110,
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1110,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(metadata.ix.validate(110).is_ok());
+        assert_eq!(metadata.ix.start_time, 100);
+        assert_eq!(metadata.effective_start(), 110);
+
+        // Nothing unlocks retroactively, the first period ends 100s after creation
+        assert_eq!(metadata.available(110), 0);
+        assert_eq!(metadata.available(209), 0);
+        assert_eq!(metadata.period_boundary(209), 110);
+        assert_eq!(metadata.available(210), 100);
+        assert_eq!(metadata.next_unlock_timestamp(110), Some(210));
+        assert_eq!(metadata.available(1110), 1000);
+        assert_eq!(metadata.closable(), 1110);
+
+        // A cliff that has already passed unlocks at creation
+        metadata.ix.cliff = 105;
+        metadata.ix.cliff_amount = 100;
+        assert_eq!(metadata.effective_start(), 110);
+        assert_eq!(metadata.available(110), 100);
+        assert_eq!(metadata.available(210), 190);
+    }
+
+    #[test]
+    fn test_locked_amount_at() {
+        let mut metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                cliff: 300,
+                cliff_amount: 200,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // (timestamp, vested, locked)
+        let lifetime = [
+            (0, 0, 1000),
+            (100, 0, 1000),
+            (299, 0, 1000),
+            (300, 200, 800),
+            (399, 200, 800),
+            (400, 300, 700),
+            (1050, 900, 100),
+            (1100, 1000, 0),
+            (5000, 1000, 0),
+        ];
+        for (ts, vested, locked) in lifetime {
+            assert_eq!(metadata.vested_amount_at(ts), vested, "vested at {}", ts);
+            assert_eq!(metadata.locked_amount_at(ts), locked, "locked at {}", ts);
+        }
+
+        // Withdrawals don't change what's vested or locked
+        metadata.withdrawn_amount = metadata.available(400);
+        assert_eq!(metadata.vested_amount_at(400), 300);
+        assert_eq!(metadata.locked_amount_at(400), 700);
+        assert_eq!(metadata.available(400), 0);
+
+        // Canceled at 450: vesting stops and nothing stays locked
+        metadata.canceled_at = 450;
+        assert_eq!(metadata.locked_amount_at(449), 700);
+        assert_eq!(metadata.locked_amount_at(450), 0);
+        assert_eq!(metadata.vested_amount_at(5000), 300);
+    }
+
+    fn valid_ix(now: u64) -> StreamInstruction {
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            period: 100,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(valid_ix(1000).validate(1000), Ok(()));
+        assert_eq!(
+            StreamInstruction::lock(1010, 2010, 1000).validate(1000),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_validate_period() {
+        let mut ix = valid_ix(1000);
+        for (period, result) in [
+            (0, Err(InvalidPeriod)),
+            (1, Ok(())),
+            (MAX_PERIOD, Ok(())),
+            (MAX_PERIOD + 1, Err(InvalidPeriod)),
+            (u64::MAX, Err(InvalidPeriod)),
+        ] {
+            ix.period = period;
+            assert_eq!(ix.validate(1000), result, "period {}", period);
+        }
+    }
+
+    #[test]
+    fn test_validate_start_time() {
+        let mut ix = valid_ix(1000);
+        ix.start_time = 1000 + MAX_START_DELAY;
+        ix.end_time = ix.start_time + 1000;
+        assert_eq!(ix.validate(1000), Ok(()));
+
+        ix.start_time += 1;
+        ix.end_time += 1;
+        assert_eq!(ix.validate(1000), Err(InvalidTimestamps));
+
+        ix.start_time = u64::MAX - 1;
+        ix.end_time = u64::MAX;
+        assert_eq!(ix.validate(1000), Err(InvalidTimestamps));
+    }
+
+    #[test]
+    fn test_validate_end_time() {
+        let mut ix = valid_ix(1000);
+        ix.end_time = ix.start_time + MAX_DURATION;
+        assert_eq!(ix.validate(1000), Ok(()));
+
+        ix.end_time += 1;
+        assert_eq!(ix.validate(1000), Err(DurationTooLong));
+
+        ix.end_time = u64::MAX;
+        assert_eq!(ix.validate(1000), Err(DurationTooLong));
+    }
+
+    #[test]
+    fn test_validate_deposited_amount() {
+        let mut ix = valid_ix(1000);
+        ix.deposited_amount = 0;
+        assert_eq!(ix.validate(1000), Err(ZeroDeposit));
+
+        // Partially funded streams are fine, they can be topped up
+        ix.deposited_amount = 1;
+        assert_eq!(ix.validate(1000), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_release_rate() {
+        let mut ix = valid_ix(1000);
+        ix.release_rate = 1000;
+        assert_eq!(ix.validate(1000), Ok(()));
+
+        ix.release_rate = 1001;
+        assert_eq!(ix.validate(1000), Err(InvalidAmounts));
+    }
+
+    #[test]
+    fn test_validate_release_rate_deposited() {
+        let mut ix = valid_ix(1000);
+        ix.deposited_amount = 500;
+        ix.release_rate = 500;
+        assert_eq!(ix.validate(1000), Ok(()));
+
+        ix.release_rate = 501;
+        assert_eq!(ix.validate(1000), Err(InvalidAmounts));
+    }
+
+    #[test]
+    fn test_validate_cliff_amount() {
+        let mut ix = valid_ix(1000);
+        ix.cliff = ix.start_time;
+        ix.cliff_amount = 1000;
+        assert_eq!(ix.validate(1000), Ok(()));
+
+        ix.cliff_amount = 1001;
+        assert_eq!(ix.validate(1000), Err(InvalidAmounts));
+    }
+
+    #[test]
+    fn test_validate_cliff_amount_deposited() {
+        let mut ix = valid_ix(1000);
+        ix.cliff = ix.start_time;
+        ix.deposited_amount = 500;
+        ix.cliff_amount = 500;
+        assert_eq!(ix.validate(1000), Ok(()));
+
+        ix.cliff_amount = 501;
+        assert_eq!(ix.validate(1000), Err(InvalidAmounts));
+    }
+
+    #[test]
+    fn test_validate_cliff() {
+        let mut ix = valid_ix(1000);
+        for (cliff, result) in [
+            (0, Ok(())),
+            (ix.start_time - 1, Err(InvalidCliff)),
+            (ix.start_time, Ok(())),
+            (ix.end_time, Ok(())),
+            (ix.end_time + 1, Err(InvalidCliff)),
+        ] {
+            ix.cliff = cliff;
+            assert_eq!(ix.validate(1000), result, "cliff {}", cliff);
+        }
+    }
+
+    #[test]
+    fn test_vested_large_cliff_amount() {
+        // Found by the vesting fuzz target: the unlocked periods plus the
+        // cliff amount overflowed before being capped at the deposit.
+        let metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 200,
+                deposited_amount: 1000,
+                total_amount: u64::MAX,
+                period: 1,
+                cliff: 100,
+                cliff_amount: u64::MAX - 1,
+                release_rate: u64::MAX,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(metadata.available(150), 1000);
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = StreamRegistry::default();
+        let keys: Vec<Pubkey> = (0..=REGISTRY_MAX_STREAMS)
+            .map(|_| Pubkey::new_unique())
+            .collect();
+
+        assert!(registry.add(&keys[0]));
+        assert!(!registry.add(&keys[0]));
+        for k in &keys[1..REGISTRY_MAX_STREAMS] {
+            assert!(registry.add(k));
+        }
+        // Full, overflowing streams just aren't indexed
+        assert!(!registry.add(&keys[REGISTRY_MAX_STREAMS]));
+        assert_eq!(registry.streams.len(), REGISTRY_MAX_STREAMS);
+        assert_eq!(registry.try_to_vec().unwrap().len(), REGISTRY_SIZE);
+
+        assert!(registry.remove(&keys[1]));
+        assert!(!registry.remove(&keys[1]));
+        assert_eq!(registry.streams[1], keys[2]);
+        assert!(registry.add(&keys[REGISTRY_MAX_STREAMS]));
+
+        let mut data = vec![0xff; REGISTRY_SIZE];
+        registry.pack(&mut data).unwrap();
+        assert_eq!(StreamRegistry::unpack(&data).unwrap(), registry);
+
+        // Shrinking leaves no stale keys behind
+        registry.streams.truncate(1);
+        registry.pack(&mut data).unwrap();
+        assert!(data[4 + 32..].iter().all(|b| *b == 0));
+        assert_eq!(StreamRegistry::unpack(&data).unwrap(), registry);
+        assert_eq!(
+            StreamRegistry::unpack(&[0; REGISTRY_SIZE]).unwrap(),
+            StreamRegistry::default()
+        );
+    }
+
+    #[test]
+    fn test_mint_stats() {
+        let mut stats = MintStats::default();
+        assert_eq!(stats.try_to_vec().unwrap().len(), MINT_STATS_SIZE);
+
+        stats.add_stream(1000);
+        stats.add_stream(500);
+        stats.add_payout(300, false);
+        stats.add_payout(700, true);
+        assert_eq!(
+            stats,
+            MintStats {
+                total_net_deposited: 1500,
+                total_withdrawn: 1000,
+                active_streams: 1,
+            }
+        );
+        assert_eq!(stats.locked(), 500);
+
+        let mut data = vec![0xff; MINT_STATS_SIZE];
+        stats.pack(&mut data).unwrap();
+        assert_eq!(MintStats::unpack(&data).unwrap(), stats);
+
+        // Payouts of streams that were never counted don't underflow
+        stats.add_payout(1000, true);
+        stats.add_payout(1000, true);
+        assert_eq!(stats.active_streams, 0);
+        assert_eq!(stats.locked(), 0);
+        stats.add_stream(u64::MAX);
+        assert_eq!(stats.total_net_deposited, u64::MAX);
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut labels = StreamLabels {
+            keys: vec!["invoice".to_string()],
+            values: vec!["INV-0042".to_string()],
+        };
+        assert_eq!(labels.validate(), Ok(()));
+
+        let mut data = vec![0xff; LABELS_SIZE];
+        labels.pack(&mut data).unwrap();
+        assert_eq!(StreamLabels::unpack(&data).unwrap(), labels);
+
+        // Every key needs a value
+        labels.keys.push("po".to_string());
+        assert_eq!(labels.validate(), Err(InvalidLabels));
+
+        // The largest valid labels still fit the account
+        let half = LABELS_MAX_BYTES / LABELS_MAX_PAIRS / 2;
+        labels.keys = vec!["k".repeat(half); LABELS_MAX_PAIRS];
+        labels.values = vec!["v".repeat(half); LABELS_MAX_PAIRS];
+        assert_eq!(labels.validate(), Ok(()));
+        assert_eq!(labels.try_to_vec().unwrap().len(), LABELS_SIZE);
+        labels.pack(&mut data).unwrap();
+        assert_eq!(StreamLabels::unpack(&data).unwrap(), labels);
+
+        labels.values[0].push('v');
+        assert_eq!(labels.validate(), Err(InvalidLabels));
+
+        labels.values[0].pop();
+        labels.keys.push(String::new());
+        labels.values.push(String::new());
+        assert_eq!(labels.validate(), Err(InvalidLabels));
+    }
+
+    #[test]
+    fn test_required_signers() {
+        let old = StreamInstruction::default().permissions();
+        assert!(old.cancelable_by_sender && !old.cancelable_by_recipient);
+        assert!(!old.transferable_by_sender && old.transferable_by_recipient);
+        assert_eq!(old.required_signers(&old), (false, false));
+
+        let change = |f: fn(&mut StreamPermissions)| {
+            let mut new = old.clone();
+            f(&mut new);
+            old.required_signers(&new)
+        };
+        // Revoking a right needs its holder, granting one the other party
+        assert_eq!(change(|p| p.cancelable_by_sender = false), (true, false));
+        assert_eq!(change(|p| p.cancelable_by_recipient = true), (true, false));
+        assert_eq!(change(|p| p.transferable_by_sender = true), (false, true));
+        assert_eq!(
+            change(|p| p.transferable_by_recipient = false),
+            (false, true)
+        );
+
+        let granted = StreamPermissions {
+            cancelable_by_sender: true,
+            cancelable_by_recipient: true,
+            ..Default::default()
+        };
+        let none = StreamPermissions::default();
+        assert_eq!(none.required_signers(&granted), (true, true));
+        assert_eq!(granted.required_signers(&none), (true, true));
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let valid = || TokenStreamData {
+            created_at: 1000,
+            ix: valid_ix(1000),
+            ..Default::default()
+        };
+        assert_eq!(valid().check_invariants(), Ok(()));
+
+        let mut metadata = valid();
+        metadata.withdrawn_amount = metadata.ix.deposited_amount;
+        assert_eq!(metadata.check_invariants(), Ok(()));
+        metadata.withdrawn_amount += 1;
+        assert_eq!(
+            metadata.check_invariants(),
+            Err(StreamFlowError::WithdrawnExceedsDeposited)
+        );
+
+        let mut metadata = valid();
+        metadata.canceled_at = 1000;
+        assert_eq!(metadata.check_invariants(), Ok(()));
+        metadata.canceled_at = 999;
+        assert_eq!(
+            metadata.check_invariants(),
+            Err(StreamFlowError::InvalidCancelTime)
+        );
+
+        let mut metadata = valid();
+        metadata.ix.end_time = metadata.ix.start_time - 1;
+        assert_eq!(metadata.check_invariants(), Err(InvalidTimestamps));
+
+        let mut metadata = valid();
+        metadata.ix.cliff = metadata.ix.end_time + 1;
+        assert_eq!(metadata.check_invariants(), Err(InvalidTimestamps));
+
+        // Vesting would be anchored after the end
+        let mut metadata = valid();
+        metadata.created_at = metadata.ix.end_time;
+        assert_eq!(metadata.check_invariants(), Ok(()));
+        metadata.created_at += 1;
+        assert_eq!(metadata.check_invariants(), Err(InvalidTimestamps));
+    }
+
+    #[test]
+    fn test_next_unlock_timestamp() {
+        let mut metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1050,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                cliff: 300,
+                cliff_amount: 200,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Before the cliff, the cliff is the first unlock
+        assert_eq!(metadata.next_unlock_timestamp(0), Some(300));
+        assert_eq!(metadata.next_unlock_timestamp(299), Some(300));
+        // Mid-stream, the next period boundary
+        assert_eq!(metadata.next_unlock_timestamp(300), Some(400));
+        assert_eq!(metadata.next_unlock_timestamp(450), Some(500));
+        // The last period is cut short by the end
+        assert_eq!(metadata.next_unlock_timestamp(1000), Some(1050));
+        // Fully vested
+        assert_eq!(metadata.next_unlock_timestamp(1050), None);
+        assert_eq!(metadata.next_unlock_timestamp(5000), None);
+
+        // Without a cliff amount, the first unlock is a period in
+        metadata.ix.cliff = 0;
+        metadata.ix.cliff_amount = 0;
+        assert_eq!(metadata.next_unlock_timestamp(0), Some(200));
+
+        metadata.canceled_at = 150;
+        assert_eq!(metadata.next_unlock_timestamp(150), None);
+    }
+
+    #[test]
+    fn test_next_unlock_timestamp_recurring() {
+        let metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 200,
+                deposited_amount: 50,
+                total_amount: 50,
+                period: 10,
+                release_rate: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Recurring streams ignore the end time, until funds run out
+        assert_eq!(metadata.next_unlock_timestamp(105), Some(110));
+        assert_eq!(metadata.next_unlock_timestamp(140), Some(150));
+        assert_eq!(metadata.next_unlock_timestamp(150), None);
+    }
+
+    fn golden_metadata() -> TokenStreamData {
+        TokenStreamData {
+            magic: 2,
+            created_at: 1,
+            withdrawn_amount: 3,
+            canceled_at: 4,
+            closable_at: 5,
+            last_withdrawn_at: 6,
+            sender: Pubkey::new_from_array([1; 32]),
+            sender_tokens: Pubkey::new_from_array([2; 32]),
+            recipient: Pubkey::new_from_array([3; 32]),
+            recipient_tokens: Pubkey::new_from_array([4; 32]),
+            mint: Pubkey::new_from_array([5; 32]),
+            escrow_tokens: Pubkey::new_from_array([6; 32]),
+            ix: StreamInstruction {
+                start_time: 7,
+                end_time: 8,
+                deposited_amount: 9,
+                total_amount: 10,
+                period: 11,
+                cliff: 12,
+                cliff_amount: 13,
+                cancelable_by_sender: true,
+                cancelable_by_recipient: false,
+                withdrawal_public: true,
+                transferable_by_sender: false,
+                transferable_by_recipient: true,
+                release_rate: 14,
+                stream_name: "golden".to_string(),
+            },
+            ext: StreamExtension::default(),
+        }
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut metadata = golden_metadata();
+        let mut data = metadata.try_to_vec().unwrap();
+
+        metadata.withdrawn_amount = 9;
+        metadata.canceled_at = 20;
+        metadata.last_withdrawn_at = 19;
+        metadata.save_state(&mut data).unwrap();
+
+        assert_eq!(data, metadata.try_to_vec().unwrap());
+        assert_eq!(
+            metadata.save_state(&mut [0; STATE_SIZE - 1]),
+            Err(ProgramError::Custom(
+                StreamFlowError::InvalidMetadataSize as u32
+            ))
+        );
+    }
+
+    #[test]
+    fn test_key_offsets() {
+        let metadata = golden_metadata();
+        let data = metadata.try_to_vec().unwrap();
+
+        assert_eq!(
+            &data[SENDER_OFFSET..SENDER_OFFSET + 32],
+            metadata.sender.as_ref()
+        );
+        assert_eq!(
+            &data[RECIPIENT_OFFSET..RECIPIENT_OFFSET + 32],
+            metadata.recipient.as_ref()
+        );
+        assert_eq!(&data[MINT_OFFSET..MINT_OFFSET + 32], metadata.mint.as_ref());
+    }
+
+    #[test]
+    fn test_key_accessors() {
+        let metadata = golden_metadata();
+
+        let keys = [
+            metadata.sender(),
+            metadata.sender_tokens(),
+            metadata.recipient(),
+            metadata.recipient_tokens(),
+            metadata.mint(),
+            metadata.escrow_tokens(),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(**key, Pubkey::new_from_array([i as u8 + 1; 32]));
+        }
+    }
+
+    #[test]
+    fn test_validate_accounts() {
+        let metadata = golden_metadata();
+        let other = Pubkey::new_unique();
+        let keys = StreamTokenKeys {
+            mint: Some(&metadata.mint),
+            escrow_tokens: Some(&metadata.escrow_tokens),
+            sender_tokens: Some(&metadata.sender_tokens),
+            recipient_tokens: Some(&metadata.recipient_tokens),
+        };
+        assert_eq!(metadata.validate_accounts(&keys), Ok(()));
+        assert_eq!(
+            metadata.validate_accounts(&StreamTokenKeys::default()),
+            Ok(())
+        );
+
+        let cases = [
+            (
+                StreamTokenKeys {
+                    mint: Some(&other),
+                    ..keys
+                },
+                MintMismatch,
+            ),
+            (
+                StreamTokenKeys {
+                    escrow_tokens: Some(&other),
+                    ..keys
+                },
+                EscrowMismatch,
+            ),
+            (
+                StreamTokenKeys {
+                    sender_tokens: Some(&other),
+                    ..keys
+                },
+                SenderTokensMismatch,
+            ),
+            (
+                StreamTokenKeys {
+                    recipient_tokens: Some(&other),
+                    ..keys
+                },
+                RecipientTokensMismatch,
+            ),
+        ];
+        for (keys, err) in cases {
+            assert_eq!(metadata.validate_accounts(&keys), Err(err));
+        }
+    }
+
+    #[test]
+    fn test_remainder_release() {
+        // 20 at 7 per period is two full periods and a remainder of 6
+        let mut metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 130,
+                deposited_amount: 20,
+                total_amount: 20,
+                period: 10,
+                release_rate: 7,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(metadata.available(120), 14);
+        metadata.withdrawn_amount = 14;
+        assert_eq!(metadata.available(129), 0);
+        assert_eq!(metadata.available(130), 6);
+        assert_eq!(metadata.available(5000), 6);
+
+        // Underfunded non-recurring streams stop at what was deposited
+        let metadata = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 200,
+                deposited_amount: 25,
+                total_amount: 100,
+                period: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(metadata.available(120), 20);
+        assert_eq!(metadata.available(130), 25);
+        assert_eq!(metadata.available(199), 25);
+        assert_eq!(metadata.available(200), 25);
+    }
+
+    #[test]
+    fn test_validate_lock() {
+        let options = StreamOptions::lock();
+        let ix = StreamInstruction::lock(100, 200, 1000);
+        assert_eq!(options.validate(&ix), Ok(()));
+
+        // The same schedule is a plain stream without the options
+        assert_eq!(StreamOptions::default().validate(&ix), Ok(()));
+        let mut metadata = lock(100, 200, 1000);
+        assert!(metadata.is_lock());
+        metadata.ext = StreamExtension::default();
+        assert!(!metadata.is_lock());
+
+        // Must unlock the whole total at once at the end
+        for invalid in [
+            StreamInstruction {
+                cliff: 0,
+                ..ix.clone()
+            },
+            StreamInstruction {
+                cliff: 150,
+                ..ix.clone()
+            },
+            StreamInstruction {
+                cliff_amount: 500,
+                ..ix.clone()
+            },
+            StreamInstruction {
+                release_rate: 10,
+                ..ix.clone()
+            },
+        ] {
+            assert_eq!(options.validate(&invalid), Err(InvalidLock));
+        }
+
+        let underfunded = StreamInstruction {
+            deposited_amount: 999,
+            ..ix
+        };
+        assert_eq!(options.validate(&underfunded), Err(LockUnderfunded));
+    }
+
+    fn lifecycle_streams() -> Vec<TokenStreamData> {
+        let schedules = [
+            // Linear with a cliff
+            StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                cliff: 300,
+                cliff_amount: 200,
+                ..Default::default()
+            },
+            // Underfunded linear, uneven period
+            StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 555,
+                total_amount: 1000,
+                period: 7,
+                ..Default::default()
+            },
+            // Recurring with a remainder
+            StreamInstruction {
+                start_time: 100,
+                end_time: 130,
+                deposited_amount: 200,
+                total_amount: 200,
+                period: 10,
+                release_rate: 70,
+                ..Default::default()
+            },
+        ];
+
+        schedules
+            .iter()
+            .map(|ix| TokenStreamData {
+                ix: ix.clone(),
+                ..Default::default()
+            })
+            .chain([lock(100, 1100, 1000)])
+            .map(|stream| {
+                let mut metadata = TokenStreamData {
+                    created_at: 50,
+                    ..stream
+                };
+                metadata.closable_at = metadata.closable();
+                metadata
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_vesting_every_second() {
+        for metadata in lifecycle_streams() {
+            let first_unlock = metadata.ix.effective_start();
+            let mut vested = 0;
+            let mut next_unlock = metadata.next_unlock_timestamp(metadata.created_at);
+
+            for now in metadata.created_at + 1..metadata.closable_at + 100 {
+                let vested_now = metadata.vested_amount_at(now);
+                assert!(vested_now >= vested, "vested decreased at {}", now);
+                assert!(vested_now <= metadata.ix.deposited_amount);
+
+                // Amounts only ever unlock at the predicted timestamps
+                match next_unlock {
+                    Some(next) if now < next => assert_eq!(vested_now, vested, "at {}", now),
+                    Some(next) => assert_eq!(now, next),
+                    None => assert_eq!(vested_now, metadata.ix.deposited_amount),
+                }
+
+                if now >= first_unlock {
+                    assert!(metadata.period_boundary(now) <= now);
+                    assert!(metadata.period_boundary(now) >= first_unlock);
+                }
+
+                vested = vested_now;
+                next_unlock = metadata.next_unlock_timestamp(now);
+            }
+
+            assert_eq!(vested, metadata.ix.deposited_amount);
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_withdraw() {
+        for mut metadata in lifecycle_streams() {
+            let first_unlock = metadata.ix.effective_start();
+            if metadata.is_lock() {
+                assert_eq!(
+                    metadata.withdraw(0, first_unlock - 1),
+                    Err(StreamNotStarted.into())
+                );
+            } else {
+                assert_eq!(metadata.clone().withdraw(0, first_unlock - 1), Ok(0));
+                assert_eq!(
+                    metadata.clone().withdraw(1, first_unlock - 1),
+                    Err(ProgramError::InvalidArgument)
+                );
+            }
+
+            let mut withdrawn = 0;
+            for now in (first_unlock..metadata.closable_at + 20).step_by(3) {
+                let available = metadata.available(now);
+                assert_eq!(
+                    metadata.withdraw(available + 1, now),
+                    Err(ProgramError::InvalidArgument)
+                );
+
+                assert_eq!(metadata.withdraw(0, now), Ok(available));
+                withdrawn += available;
+                assert_eq!(metadata.withdrawn_amount, withdrawn);
+                assert_eq!(metadata.available(now), 0);
+                assert!(metadata.last_withdrawn_at <= now);
+                assert_eq!(metadata.check_invariants(), Ok(()));
+            }
+
+            assert_eq!(withdrawn, metadata.ix.deposited_amount);
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_cancel() {
+        for stream in lifecycle_streams() {
+            let first_unlock = stream.ix.effective_start();
+            for now in (stream.created_at..stream.closable_at + 20).step_by(5) {
+                let mut metadata = stream.clone();
+
+                // Withdraw halfway to the cancellation, whenever possible
+                let midway = (stream.created_at + now) / 2;
+                let withdrawn = if midway >= first_unlock {
+                    metadata.withdraw(0, midway).unwrap()
+                } else {
+                    0
+                };
+
+                let vested = metadata.vested_amount_at(now);
+                let preview = metadata.refundable_on_cancel(now);
+                let refund = metadata.cancel(now);
+                assert_eq!(refund, preview);
+                let (released, refunded) = (refund.to_recipient, refund.to_sender);
+                assert_eq!(withdrawn + released, vested, "cancel at {}", now);
+                assert_eq!(
+                    withdrawn + released + refunded,
+                    stream.ix.deposited_amount,
+                    "cancel at {}",
+                    now
+                );
+                assert_eq!(metadata.check_invariants(), Ok(()));
+
+                // Cancelling early freezes the schedule, nothing vests later
+                if now < stream.closable_at {
+                    assert_eq!(metadata.canceled_at, now);
+                    assert_eq!(metadata.vested_amount_at(stream.closable_at + 1000), vested);
+                } else {
+                    assert_eq!(metadata.canceled_at, 0);
+                    assert_eq!(refunded, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_topup() {
+        let mut stream = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                release_rate: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(stream.topup(500), Ok(()));
+        assert_eq!(stream.ix.deposited_amount, 1500);
+        assert_eq!(stream.closable_at, 1601);
+        assert_eq!(stream.check_duration(), Ok(()));
+
+        // Funded right up to the maximum duration, and one second over
+        assert_eq!(stream.topup(MAX_DURATION - 1501), Ok(()));
+        assert_eq!(stream.closable_at, 100 + MAX_DURATION);
+        assert_eq!(stream.check_duration(), Ok(()));
+        let before = stream.clone();
+        assert_eq!(stream.topup(1), Err(DurationTooLong.into()));
+        assert_eq!(stream.topup(u64::MAX), Err(InvalidAmounts.into()));
+        assert_eq!(stream, before);
+
+        stream.closable_at += 1;
+        assert_eq!(stream.check_duration(), Err(DurationTooLong));
+    }
+
+    #[test]
+    fn test_effective_rate_per_second() {
+        // 250 every 100 seconds
+        let mut stream = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                release_rate: 250,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(stream.effective_rate_per_second(), Ok(2));
+        assert_eq!(stream.effective_rate_per_second_remainder(), Ok(50));
+
+        stream.ix.release_rate = 300;
+        assert_eq!(stream.effective_rate_per_second(), Ok(3));
+        assert_eq!(stream.effective_rate_per_second_remainder(), Ok(0));
+
+        stream.ix.period = 0;
+        assert_eq!(
+            stream.effective_rate_per_second(),
+            Err(InvalidPeriod.into())
+        );
+        assert_eq!(
+            stream.effective_rate_per_second_remainder(),
+            Err(InvalidPeriod.into())
+        );
+    }
+
+    #[test]
+    fn test_extend_to() {
+        let linear = |deposited_amount, cliff, cliff_amount| TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount,
+                total_amount: 1000,
+                period: 100,
+                cliff,
+                cliff_amount,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        // Target end time, stream => additional deposit, new total
+        for (end_time, stream, additional, total) in [
+            (1100, linear(1000, 0, 0), 0, 1000),
+            (2100, linear(1000, 0, 0), 1000, 2000),
+            (1600, linear(1000, 0, 0), 500, 1500),
+            (2100, linear(555, 0, 0), 1445, 2000),
+            (2100, linear(3000, 0, 0), 0, 2000),
+            (1300, linear(1000, 300, 200), 200, 1200),
+        ] {
+            let mut extended = stream.clone();
+            assert_eq!(extended.extend_to(end_time), Ok(additional));
+            assert_eq!(extended.ix.end_time, end_time);
+            assert_eq!(extended.ix.total_amount, total);
+            assert_eq!(
+                extended.ix.deposited_amount,
+                stream.ix.deposited_amount + additional
+            );
+            assert_eq!(extended.closable_at, extended.closable());
+
+            // Same amount per period, so it vests as before until the old end
+            for now in (0..stream.ix.end_time).step_by(50) {
+                assert_eq!(
+                    extended
+                        .vested_amount_at(now)
+                        .min(stream.ix.deposited_amount),
+                    stream.vested_amount_at(now),
+                    "at {}",
+                    now
+                );
+            }
+            assert_eq!(
+                extended.vested_amount_at(end_time),
+                extended.ix.deposited_amount
+            );
+        }
+
+        let mut recurring = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 130,
+                deposited_amount: 200,
+                total_amount: 200,
+                period: 10,
+                release_rate: 70,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // Ten periods of 70 until 200
+        assert_eq!(recurring.extend_to(200), Ok(500));
+        assert_eq!(recurring.ix.deposited_amount, 700);
+        assert_eq!(recurring.ix.total_amount, 700);
+        assert_eq!(recurring.vested_amount_at(190), 630);
+        assert_eq!(recurring.vested_amount_at(200), 700);
+        assert!(recurring.closable_at > 190);
+
+        let mut stream = linear(1000, 0, 0);
+        assert_eq!(stream.extend_to(1099), Err(CannotShortenStream.into()));
+        assert_eq!(
+            stream.extend_to(100 + MAX_DURATION + 1),
+            Err(DurationTooLong.into())
+        );
+        assert_eq!(stream, linear(1000, 0, 0));
+
+        assert_eq!(
+            lock(100, 1100, 1000).extend_to(2100),
+            Err(ProgramError::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn test_cancel_before_start() {
+        let stream = TokenStreamData {
+            ix: StreamInstruction {
+                start_time: 100,
+                end_time: 1100,
+                deposited_amount: 1000,
+                total_amount: 1000,
+                period: 100,
+                ..Default::default()
+            },
+            closable_at: 1100,
+            ..Default::default()
+        };
+
+        // Nothing vests before the first period ends, the sender gets it all
+        for now in [99, 100, 199] {
+            let mut canceled = stream.clone();
+            let refund = canceled.cancel(now);
+            assert_eq!((refund.to_sender, refund.to_recipient), (1000, 0));
+            assert_eq!(canceled.withdrawn_amount, 0);
+            assert_eq!(canceled.canceled_at, now);
+        }
+        assert_eq!(stream.refundable_on_cancel(200).to_recipient, 100);
+
+        // A cliff amount is due right at the cliff
+        let stream = TokenStreamData {
+            ix: StreamInstruction {
+                cliff: 300,
+                cliff_amount: 250,
+                ..stream.ix.clone()
+            },
+            ..stream
+        };
+        assert_eq!(stream.refundable_on_cancel(299).to_sender, 1000);
+        assert_eq!(stream.refundable_on_cancel(300).to_recipient, 250);
+    }
+
+    #[test]
+    fn test_refundable_on_cancel() {
+        // Seeded xorshift, so failures are reproducible
+        let mut seed = 0x5eed_f00d_cafe_b0ba_u64;
+        let mut rand = |n: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % n
+        };
+
+        for _ in 0..2000 {
+            let start_time = 100 + rand(1000);
+            let end_time = start_time + 1 + rand(100_000);
+            let total_amount = 1 + rand(1_000_000_000_000);
+            let cliff = if rand(2) == 0 {
+                0
+            } else {
+                start_time + rand(end_time - start_time + 1)
+            };
+            let release_rate = if rand(2) == 0 {
+                0
+            } else {
+                1 + rand(total_amount)
+            };
+
+            let mut metadata = TokenStreamData {
+                ix: StreamInstruction {
+                    start_time,
+                    end_time,
+                    deposited_amount: 1 + rand(total_amount),
+                    total_amount,
+                    period: 1 + rand(end_time - start_time),
+                    cliff,
+                    cliff_amount: if cliff > 0 { rand(total_amount + 1) } else { 0 },
+                    release_rate,
+                    ..Default::default()
+                },
+                closable_at: end_time,
+                ..Default::default()
+            };
+
+            let withdrawn_at = start_time + rand(2 * (end_time - start_time));
+            if withdrawn_at >= metadata.ix.effective_start() {
+                metadata.withdraw(0, withdrawn_at).unwrap();
+            }
+
+            let now = withdrawn_at + rand(2 * (end_time - start_time));
+            let refund = metadata.refundable_on_cancel(now);
+            assert_eq!(
+                refund.to_sender + refund.to_recipient,
+                metadata.ix.deposited_amount - metadata.withdrawn_amount,
+                "{:?} at {}",
+                metadata,
+                now
+            );
+        }
+    }
 }
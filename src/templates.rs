@@ -0,0 +1,161 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Stream instructions for common vesting schedules, e.g. employee grants,
+//! so clients don't have to work out periods and cliff amounts themselves.
+//! Only the schedule is set, `stream_name` and the permissions are left at
+//! their defaults for the caller to adjust.
+use crate::{
+    error::StreamFlowError::{self, DurationTooLong, InvalidCliff, InvalidTimestamps},
+    state::StreamInstruction,
+};
+
+/// A twelfth of a 365 day year, the period of monthly templates
+pub const MONTH: u64 = 365 * 24 * 60 * 60 / 12;
+
+/// Vest `total_amount` over `months` from `start_time`, one release every
+/// month. With a non-zero `cliff_months`, nothing vests until the cliff,
+/// which then releases the share of the months passed at once.
+///
+/// The result is validated as if created at `start_time`, the program still
+/// checks the start against its own clock at create.
+pub fn monthly_with_cliff(
+    start_time: u64,
+    total_amount: u64,
+    months: u64,
+    cliff_months: u64,
+) -> Result<StreamInstruction, StreamFlowError> {
+    if months == 0 {
+        return Err(InvalidTimestamps);
+    }
+
+    if cliff_months > months {
+        return Err(InvalidCliff);
+    }
+
+    let (cliff, cliff_amount) = if cliff_months > 0 {
+        let cliff = cliff_months
+            .checked_mul(MONTH)
+            .and_then(|t| start_time.checked_add(t))
+            .ok_or(InvalidCliff)?;
+        // Can't overflow, it's at most `total_amount`
+        let cliff_amount = (total_amount as u128 * cliff_months as u128 / months as u128) as u64;
+        (cliff, cliff_amount)
+    } else {
+        (0, 0)
+    };
+
+    let end_time = months
+        .checked_mul(MONTH)
+        .and_then(|t| start_time.checked_add(t))
+        .ok_or(DurationTooLong)?;
+
+    let ix = StreamInstruction {
+        start_time,
+        end_time,
+        deposited_amount: total_amount,
+        total_amount,
+        period: MONTH,
+        cliff,
+        cliff_amount,
+        ..Default::default()
+    };
+    ix.validate(start_time)?;
+
+    Ok(ix)
+}
+
+/// The standard employee grant: four years of monthly vesting, with a one
+/// year cliff releasing the first quarter.
+pub fn four_year_monthly_cliff(
+    start_time: u64,
+    total_amount: u64,
+) -> Result<StreamInstruction, StreamFlowError> {
+    monthly_with_cliff(start_time, total_amount, 48, 12)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{four_year_monthly_cliff, monthly_with_cliff, MONTH};
+    use crate::error::StreamFlowError::{
+        DurationTooLong, InvalidCliff, InvalidTimestamps, ZeroDeposit,
+    };
+
+    const START: u64 = 1_600_000_000;
+
+    #[test]
+    fn test_four_year_monthly_cliff() {
+        let ix = four_year_monthly_cliff(START, 48_000).unwrap();
+        assert_eq!(ix.validate(START), Ok(()));
+        assert_eq!(ix.cliff, START + 12 * MONTH);
+        assert_eq!(ix.cliff_amount, 12_000);
+
+        let schedule = ix.schedule(START);
+        assert_eq!(schedule.vested(START + 12 * MONTH - 1), 0);
+        assert_eq!(schedule.vested(START + 12 * MONTH), 12_000);
+        assert_eq!(schedule.vested(START + 13 * MONTH - 1), 12_000);
+        assert_eq!(schedule.vested(START + 13 * MONTH), 13_000);
+        assert_eq!(schedule.vested(START + 30 * MONTH), 30_000);
+        assert_eq!(schedule.vested(START + 48 * MONTH - 1), 47_000);
+        assert_eq!(schedule.vested(START + 48 * MONTH), 48_000);
+    }
+
+    #[test]
+    fn test_monthly_with_cliff() {
+        // No cliff, linear over a year
+        let ix = monthly_with_cliff(START, 1200, 12, 0).unwrap();
+        assert_eq!(ix.validate(START), Ok(()));
+        assert_eq!((ix.cliff, ix.cliff_amount), (0, 0));
+        let schedule = ix.schedule(START);
+        assert_eq!(schedule.vested(START + MONTH - 1), 0);
+        assert_eq!(schedule.vested(START + MONTH), 100);
+        assert_eq!(schedule.vested(START + 12 * MONTH), 1200);
+
+        // Amounts that don't divide evenly still vest the total at the end
+        let ix = monthly_with_cliff(START, 1000, 7, 3).unwrap();
+        assert_eq!(ix.validate(START), Ok(()));
+        assert_eq!(ix.cliff_amount, 428);
+        let schedule = ix.schedule(START);
+        assert_eq!(schedule.vested(START + 3 * MONTH), 428);
+        assert_eq!(schedule.vested(START + 7 * MONTH - 1), 857);
+        assert_eq!(schedule.vested(START + 7 * MONTH), 1000);
+    }
+
+    #[test]
+    fn test_monthly_with_cliff_invalid() {
+        assert_eq!(monthly_with_cliff(START, 1000, 12, 13), Err(InvalidCliff));
+        assert_eq!(
+            monthly_with_cliff(START, 1000, 0, 0),
+            Err(InvalidTimestamps)
+        );
+        assert_eq!(monthly_with_cliff(START, 0, 12, 3), Err(ZeroDeposit));
+
+        // Would overflow the end or the cliff time
+        assert_eq!(
+            monthly_with_cliff(u64::MAX - MONTH, 1000, 12, 0),
+            Err(DurationTooLong)
+        );
+        assert_eq!(
+            monthly_with_cliff(START, 1000, u64::MAX, u64::MAX),
+            Err(InvalidCliff)
+        );
+        // Longer than the maximum duration
+        assert_eq!(
+            monthly_with_cliff(START, 1000, 1201, 12),
+            Err(DurationTooLong)
+        );
+    }
+}
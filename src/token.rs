@@ -17,7 +17,7 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 use borsh::BorshSerialize;
 use solana_program::{
-    borsh as solana_borsh,
+    account_info::AccountInfo,
     entrypoint::ProgramResult,
     msg,
     program::{invoke, invoke_signed},
@@ -30,14 +30,21 @@ use solana_program::{
 use spl_associated_token_account::{create_associated_token_account, get_associated_token_address};
 
 use crate::error::StreamFlowError::{
-    AccountsNotWritable, InvalidMetadata, MintMismatch, TransferNotAllowed, StreamClosed
+    AccountsNotWritable, EscrowAccountCompromised, EscrowFrozen, ExternalTopupNotAllowed,
+    InsufficientFunds, InvalidAccount, InvalidSenderAccount, MintMismatch, NotRentExempt,
+    StreamClosed, TransferNotAllowed,
 };
+use crate::events::{emit, StreamEvent};
 use crate::state::{
-    CancelAccounts, InitializeAccounts, StreamInstruction, TokenStreamData, TopUpAccounts,
-    TransferAccounts, WithdrawAccounts,
+    CancelAccounts, InitializeAccounts, LabelsAccounts, MintStats, SplitAccounts, StreamCounter,
+    StreamInstruction, StreamLabels, StreamOptions, StreamPermissions, StreamRegistry,
+    StreamTokenKeys, TokenStreamData, TopUpAccounts, TransferAccounts, UpdatePermissionsAccounts,
+    WithdrawAccounts, COUNTER_SIZE, LABELS_SIZE, MAX_STRING_SIZE, MINT_STATS_SIZE, REGISTRY_SIZE,
 };
 use crate::utils::{
-    duration_sanity, encode_base10, pretty_time, unpack_mint_account, unpack_token_account,
+    duration_sanity, encode_base10, find_labels_account, find_mint_stats_account,
+    find_registry_account, find_sender_counter_account, is_null_key, memo_instruction, pretty_time,
+    unpack_mint_account, unpack_token_account,
 };
 
 /// Initialize an SPL token stream
@@ -46,20 +53,66 @@ use crate::utils::{
 /// and the stream's metadata. Both accounts will be funded to be
 /// rent-exempt if necessary. When the stream is finished, these
 /// shall be returned to the stream initializer.
-
-const MAX_STRING_SIZE: usize = 200;
-
+///
+/// The escrow token account is created here, at the PDA derived from the
+/// metadata key, and initialized with itself as the owner before any tokens
+/// are deposited. Clients only pass its address. An account already there
+/// is only used if it's exactly such an empty escrow of the stream's mint,
+/// anything else fails with `EscrowAccountCompromised`. The sender's token
+/// account must be neither frozen nor delegated, or it's `InvalidSenderAccount`.
+///
+/// If the recipient's stream registry is passed, the stream gets indexed in it,
+/// and so does the sender's stream counter get incremented if passed too.
+/// The mint's stats, passed after both, count the stream and its deposit.
+///
+/// The stream is written in the current layout, with `options` in its
+/// `StreamExtension`, the defaults for clients that don't pass them.
+///
+/// `recipient_tokens` is the recipient's associated token account, created
+/// here if needed. Unless `strict_ata` is set, it may also be any other
+/// existing token account of the mint.
 pub fn create(
     program_id: &Pubkey,
     acc: InitializeAccounts,
     ix: StreamInstruction,
+    options: StreamOptions,
+    memo: &[u8],
 ) -> ProgramResult {
     msg!("Initializing SPL token stream");
 
-    if !acc.escrow_tokens.data_is_empty() || !acc.metadata.data_is_empty() {
+    let now = Clock::get()?.unix_timestamp as u64;
+    ix.validate(now)?;
+    options.validate(&ix)?;
+
+    if !acc.metadata.data_is_empty() {
         return Err(ProgramError::AccountAlreadyInitialized);
     }
 
+    // Nobody but the program can sign for the escrow PDA, but don't take
+    // that on faith: an escrow that's already there must be one it would
+    // have created itself, still empty.
+    let escrow_exists = !acc.escrow_tokens.data_is_empty();
+    if escrow_exists && !is_pristine_escrow(&acc.escrow_tokens, acc.mint.key) {
+        return Err(EscrowAccountCompromised.into());
+    }
+
+    // One that would be garbage collected mid-stream, taking the funds with it
+    if escrow_exists && !is_rent_exempt(&acc.escrow_tokens)? {
+        msg!("Error: Escrow account is not rent-exempt");
+        return Err(NotRentExempt.into());
+    }
+
+    // Guard against client bugs creating streams nobody can ever withdraw from
+    if is_null_key(acc.sender.key)
+        || is_null_key(acc.sender_tokens.key)
+        || is_null_key(acc.recipient.key)
+        || is_null_key(acc.recipient_tokens.key)
+        || is_null_key(acc.mint.key)
+    {
+        msg!("Error: Stream accounts can't be the default or system program pubkey");
+        return Err(InvalidAccount.into());
+    }
+
     if !acc.sender.is_writable
         || !acc.sender_tokens.is_writable
         || !acc.recipient.is_writable // TODO: Could it be read-only?
@@ -78,15 +131,27 @@ pub fn create(
         || acc.token_program.key != &spl_token::id()
         || acc.rent.key != &sysvar::rent::id()
         || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.recipient_tokens.key != &recipient_tokens_key
+        || acc.recipient_tokens.key == acc.escrow_tokens.key
+        || (options.strict_ata && acc.recipient_tokens.key != &recipient_tokens_key)
     {
         return Err(ProgramError::InvalidAccountData);
     }
 
+    // Any existing token account of the mint will do, e.g. one owned by a
+    // PDA recipient, unless the sender insisted on the associated one.
+    if acc.recipient_tokens.key != &recipient_tokens_key {
+        let recipient_token_info = unpack_token_account(&acc.recipient_tokens)?;
+        if &recipient_token_info.mint != acc.mint.key {
+            return Err(MintMismatch.into());
+        }
+    }
+
     if !acc.sender.is_signer || !acc.metadata.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
+    log_memo(acc.memo_program.as_ref(), memo)?;
+
     let sender_token_info = unpack_token_account(&acc.sender_tokens)?;
     let mint_info = unpack_mint_account(&acc.mint)?;
 
@@ -95,12 +160,35 @@ pub fn create(
         return Err(MintMismatch.into());
     }
 
-    let now = Clock::get()?.unix_timestamp as u64;
+    // Only fund from an account the sender fully controls: a frozen one
+    // can't send, and a delegate could move the tokens out from under us.
+    if sender_token_info.state != spl_token::state::AccountState::Initialized
+        || sender_token_info.delegate.is_some()
+        || sender_token_info.delegated_amount > 0
+    {
+        msg!("Error: Sender token account is frozen or has a delegate");
+        return Err(InvalidSenderAccount.into());
+    }
+
+    // Fail early and clearly instead of deep in the token transfer
+    if sender_token_info.amount < ix.deposited_amount {
+        msg!(
+            "Error: Sender has {} tokens, stream needs {}",
+            sender_token_info.amount,
+            ix.deposited_amount
+        );
+        return Err(InsufficientFunds.into());
+    }
+
     if !duration_sanity(now, ix.start_time, ix.end_time, ix.cliff) {
         msg!("Error: Given timestamps are invalid");
         return Err(ProgramError::InvalidArgument);
     }
 
+    if ix.start_time < now {
+        msg!("Start time is in the past, vesting from {} instead", now);
+    }
+
     if ix.stream_name.len() > MAX_STRING_SIZE {
         msg!("Error: Stream name too long!");
         return Err(ProgramError::InvalidArgument);
@@ -130,12 +218,18 @@ pub fn create(
         ix.release_rate,
         ix.stream_name,
     );
+    metadata.ext.options = options;
+    metadata.ext.mint_decimals = mint_info.decimals;
 
     // Move closable_at (from third party), when reccuring ignore end_date
     if ix.deposited_amount < ix.total_amount || ix.release_rate > 0 {
         metadata.closable_at = metadata.closable();
         msg!("Closable at: {}", metadata.closable_at);
     }
+    metadata.check_duration()?;
+
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
 
     // We also transfer enough to be rent-exempt on the metadata account.
     let metadata_bytes = metadata.try_to_vec()?;
@@ -162,11 +256,6 @@ pub fn create(
         return Err(ProgramError::InsufficientFunds);
     }
 
-    if sender_token_info.amount < ix.deposited_amount {
-        msg!("Error: Insufficient tokens in sender's wallet");
-        return Err(ProgramError::InsufficientFunds);
-    }
-
     if acc.recipient_tokens.data_is_empty() {
         msg!("Initializing recipient's associated token account");
         invoke(
@@ -203,40 +292,42 @@ pub fn create(
     let mut data = acc.metadata.try_borrow_mut_data()?;
     data[0..metadata_bytes.len()].clone_from_slice(&metadata_bytes);
 
-    let seeds = [acc.metadata.key.as_ref(), &[nonce]];
-    msg!("Creating account for holding tokens");
-    invoke_signed(
-        &system_instruction::create_account(
-            acc.sender.key,
-            acc.escrow_tokens.key,
-            cluster_rent.minimum_balance(tokens_struct_size),
-            tokens_struct_size as u64,
-            &spl_token::id(),
-        ),
-        &[
-            acc.sender.clone(),
-            acc.escrow_tokens.clone(),
-            acc.system_program.clone(),
-        ],
-        &[&seeds],
-    )?;
+    if !escrow_exists {
+        let seeds = [acc.metadata.key.as_ref(), &[nonce]];
+        msg!("Creating account for holding tokens");
+        invoke_signed(
+            &system_instruction::create_account(
+                acc.sender.key,
+                acc.escrow_tokens.key,
+                cluster_rent.minimum_balance(tokens_struct_size),
+                tokens_struct_size as u64,
+                &spl_token::id(),
+            ),
+            &[
+                acc.sender.clone(),
+                acc.escrow_tokens.clone(),
+                acc.system_program.clone(),
+            ],
+            &[&seeds],
+        )?;
 
-    msg!("Initializing escrow account for {} token", acc.mint.key);
-    invoke(
-        &spl_token::instruction::initialize_account(
-            acc.token_program.key,
-            acc.escrow_tokens.key,
-            acc.mint.key,
-            acc.escrow_tokens.key,
-        )?,
-        &[
-            acc.token_program.clone(),
-            acc.escrow_tokens.clone(),
-            acc.mint.clone(),
-            acc.escrow_tokens.clone(),
-            acc.rent.clone(),
-        ],
-    )?;
+        msg!("Initializing escrow account for {} token", acc.mint.key);
+        invoke(
+            &spl_token::instruction::initialize_account(
+                acc.token_program.key,
+                acc.escrow_tokens.key,
+                acc.mint.key,
+                acc.escrow_tokens.key,
+            )?,
+            &[
+                acc.token_program.clone(),
+                acc.escrow_tokens.clone(),
+                acc.mint.clone(),
+                acc.escrow_tokens.clone(),
+                acc.rent.clone(),
+            ],
+        )?;
+    }
 
     msg!("Moving funds into escrow account");
     invoke(
@@ -256,9 +347,41 @@ pub fn create(
         ],
     )?;
 
+    if let Some(registry) = &acc.registry {
+        register_stream(
+            program_id,
+            registry,
+            &acc.sender,
+            &acc.system_program,
+            acc.recipient.key,
+            acc.metadata.key,
+        )?;
+    }
+
+    if let Some(sender_counter) = &acc.sender_counter {
+        count_stream(
+            program_id,
+            sender_counter,
+            &acc.sender,
+            &acc.system_program,
+            acc.sender.key,
+        )?;
+    }
+
+    if let Some(mint_stats) = &acc.mint_stats {
+        count_mint_stream(
+            program_id,
+            mint_stats,
+            &acc.sender,
+            &acc.system_program,
+            acc.mint.key,
+            metadata.ix.deposited_amount,
+        )?;
+    }
+
     msg!(
         "Successfully initialized {} {} token stream for {}",
-        encode_base10(metadata.ix.deposited_amount, mint_info.decimals.into()),
+        metadata.ui_amount_str(metadata.ix.deposited_amount),
         metadata.mint,
         acc.recipient.key
     );
@@ -274,7 +397,21 @@ pub fn create(
         msg!("Cliff happens at {}", pretty_time(metadata.ix.cliff));
     }
 
-    Ok(())
+    if metadata.ext.options.withdrawable_after > 0 {
+        msg!(
+            "Withdrawable after {}",
+            metadata.ext.options.withdrawable_after
+        );
+    }
+
+    emit(&StreamEvent::StreamCreated {
+        metadata: *acc.metadata.key,
+        sender: *acc.sender.key,
+        recipient: *acc.recipient.key,
+        mint: *acc.mint.key,
+        mint_decimals: mint_info.decimals,
+        deposited_amount: metadata.ix.deposited_amount,
+    })
 }
 
 /// Withdraw from an SPL Token stream
@@ -283,14 +420,48 @@ pub fn create(
 /// if there are any unlocked funds. If so, they will be transferred from the
 /// escrow account to the stream recipient. If the entire amount has been
 /// withdrawn, the remaining rents shall be returned to the stream initializer.
-pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> ProgramResult {
+/// The stream carries no fees, so the full requested amount goes to the
+/// recipient and nothing is split off to any other account.
+///
+/// `recipient_tokens` defaults to the recipient's associated token account,
+/// but the recipient can direct the withdrawal to any token account of the
+/// stream's mint instead.
+///
+/// Streams created with `sender_can_push` may be withdrawn from by their
+/// sender too, but only into the stream's own `recipient_tokens` and within
+/// the same limits as the recipient. The `Withdrawn` event records who did.
+///
+/// A non-empty `memo` is passed on to the SPL Memo program, so indexers can
+/// tie it to this withdrawal. The same goes for create, cancel and topup.
+pub fn withdraw(
+    program_id: &Pubkey,
+    acc: WithdrawAccounts,
+    amount: u64,
+    memo: &[u8],
+) -> ProgramResult {
+    withdraw_stream(program_id, acc, amount, memo, false)
+}
+
+/// Collect everything available from an SPL Token stream
+///
+/// Works like `withdraw` of everything available, for a one-click claim.
+/// If that drains the stream, which completes it, the metadata and labels
+/// accounts are closed too along with the escrow, their rent returned to
+/// the sender. Otherwise it only withdraws, and can be collected again later.
+pub fn collect(program_id: &Pubkey, acc: WithdrawAccounts, memo: &[u8]) -> ProgramResult {
+    withdraw_stream(program_id, acc, 0, memo, true)
+}
+
+fn withdraw_stream(
+    program_id: &Pubkey,
+    acc: WithdrawAccounts,
+    amount: u64,
+    memo: &[u8],
+    close_metadata: bool,
+) -> ProgramResult {
     msg!("Withdrawing from SPL token stream");
 
-    if acc.escrow_tokens.data_is_empty()
-        || acc.escrow_tokens.owner != &spl_token::id()
-        || acc.metadata.data_is_empty()
-        || acc.metadata.owner != program_id
-    {
+    if acc.escrow_tokens.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -304,14 +475,8 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
 
     let (escrow_tokens_pubkey, nonce) =
         Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
-    let recipient_tokens_key = get_associated_token_address(acc.recipient.key, acc.mint.key);
 
-    if acc.token_program.key != &spl_token::id()
-        || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.recipient_tokens.key != &recipient_tokens_key
-        //TODO: Update in future releases based on `is_withdrawal_public`
-        || acc.withdraw_authority.key != acc.recipient.key
-    {
+    if acc.token_program.key != &spl_token::id() || acc.escrow_tokens.key != &escrow_tokens_pubkey {
         return Err(ProgramError::InvalidAccountData);
     }
 
@@ -319,40 +484,53 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
         return Err(ProgramError::MissingRequiredSignature);
     }
 
+    log_memo(acc.memo_program.as_ref(), memo)?;
+
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
     let mut data = acc.metadata.try_borrow_mut_data()?;
-    // This thing is nasty lol
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
 
     let mint_info = unpack_mint_account(&acc.mint)?;
 
-    if acc.recipient.key != &metadata.recipient
-        || acc.recipient_tokens.key != &metadata.recipient_tokens
-        || acc.mint.key != &metadata.mint
-        || acc.escrow_tokens.key != &metadata.escrow_tokens
-    {
+    if acc.recipient.key != &metadata.recipient {
         msg!("Error: Metadata does not match given accounts");
         return Err(ProgramError::InvalidAccountData);
     }
 
-    let now = Clock::get()?.unix_timestamp as u64;
-    let available = metadata.available(now);
-    let requested: u64;
-
-    if amount > available {
-        msg!("Amount requested for withdraw is more than what is available");
-        return Err(ProgramError::InvalidArgument);
+    //TODO: Update in future releases based on `is_withdrawal_public`
+    let pushed_by_sender =
+        acc.withdraw_authority.key == &metadata.sender && metadata.ext.options.sender_can_push;
+    if acc.withdraw_authority.key != acc.recipient.key && !pushed_by_sender {
+        msg!("Error: Only the recipient can withdraw from this stream");
+        return Err(ProgramError::InvalidAccountData);
     }
 
-    // 0 == MAX
-    if amount == 0 {
-        requested = available;
-    } else {
-        requested = amount;
+    // The recipient's tokens are checked below, they may withdraw elsewhere
+    metadata.validate_accounts(&StreamTokenKeys {
+        mint: Some(acc.mint.key),
+        escrow_tokens: Some(acc.escrow_tokens.key),
+        ..Default::default()
+    })?;
+
+    check_escrow_not_frozen(&acc.escrow_tokens)?;
+
+    // The recipient may route the funds into any token account of the
+    // stream's mint (e.g. a PDA-owned vault), whose owner needn't sign.
+    if acc.recipient_tokens.key != &metadata.recipient_tokens {
+        if acc.withdraw_authority.key != &metadata.recipient {
+            msg!("Error: Only the recipient can withdraw to another account");
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let destination_info = unpack_token_account(&acc.recipient_tokens)?;
+        if destination_info.mint != metadata.mint {
+            return Err(MintMismatch.into());
+        }
     }
 
+    let now = Clock::get()?.unix_timestamp as u64;
+    let requested = metadata.withdraw(amount, now)?;
+
     let seeds = [acc.metadata.key.as_ref(), &[nonce]];
     invoke_signed(
         &spl_token::instruction::transfer(
@@ -372,21 +550,24 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
         &[&seeds],
     )?;
 
-    metadata.withdrawn_amount += requested;
-    metadata.last_withdrawn_at = now;
-    let bytes = metadata.try_to_vec()?;
-    data[0..bytes.len()].clone_from_slice(&bytes);
+    emit(&StreamEvent::Withdrawn {
+        metadata: *acc.metadata.key,
+        initiator: *acc.withdraw_authority.key,
+        amount: requested,
+    })?;
+
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
+
+    metadata.save_state(&mut data)?;
 
     // Return rent when everything is withdrawn
-    if metadata.withdrawn_amount == metadata.ix.deposited_amount {
+    let drained = metadata.withdrawn_amount == metadata.ix.deposited_amount;
+    if drained {
         // Do we need this?
         if !acc.sender.is_writable || acc.sender.key != &metadata.sender {
             return Err(ProgramError::InvalidAccountData);
         }
-        //TODO: Close metadata account once there is alternative storage solution for historic data.
-        // let rent = acc.metadata.lamports();
-        // **acc.metadata.try_borrow_mut_lamports()? -= rent;
-        // **acc.sender.try_borrow_mut_lamports()? += rent;
 
         let escrow_tokens_rent = acc.escrow_tokens.lamports();
         //Close escrow token account
@@ -410,6 +591,32 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
             ],
             &[&seeds],
         )?;
+
+        if let Some(registry) = &acc.registry {
+            unregister_stream(program_id, registry, &metadata.recipient, acc.metadata.key)?;
+        }
+
+        if let Some(sender_counter) = &acc.sender_counter {
+            uncount_stream(program_id, sender_counter, &metadata.sender)?;
+        }
+
+        // The metadata is kept as a record of the stream, unless collected
+        if close_metadata {
+            if let Some(labels) = &acc.labels {
+                close_labels(program_id, labels, &acc.sender, acc.metadata.key)?;
+            }
+
+            data.fill(0);
+
+            let rent = acc.metadata.lamports();
+            **acc.metadata.try_borrow_mut_lamports()? = 0;
+            **acc.sender.try_borrow_mut_lamports()? += rent;
+            msg!("Closed metadata, returned rent: {} lamports", rent);
+        }
+    }
+
+    if let Some(mint_stats) = &acc.mint_stats {
+        count_mint_payout(program_id, mint_stats, &metadata.mint, requested, drained)?;
     }
 
     msg!(
@@ -434,14 +641,30 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
 /// The function will read the instructions from the metadata account and see
 /// if there are any unlocked funds. If so, they will be transferred to the
 /// stream recipient.
-pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
+pub fn cancel(program_id: &Pubkey, acc: CancelAccounts, memo: &[u8]) -> ProgramResult {
+    cancel_stream(program_id, acc, memo, false)
+}
+
+/// Cancel an SPL Token stream and close its metadata account
+///
+/// Works like `cancel`, which already pays out the whole escrow and closes
+/// it. On top of that, the metadata account is closed too and its rent
+/// returned to the sender, for those who don't need it kept as a record.
+/// So are the stream's labels, which can't be cleared once the metadata
+/// naming their sender is gone.
+pub fn cancel_and_close(program_id: &Pubkey, acc: CancelAccounts, memo: &[u8]) -> ProgramResult {
+    cancel_stream(program_id, acc, memo, true)
+}
+
+fn cancel_stream(
+    program_id: &Pubkey,
+    acc: CancelAccounts,
+    memo: &[u8],
+    close_metadata: bool,
+) -> ProgramResult {
     msg!("Cancelling SPL token stream");
 
-    if acc.escrow_tokens.data_is_empty()
-        || acc.escrow_tokens.owner != &spl_token::id()
-        || acc.metadata.data_is_empty()
-        || acc.metadata.owner != program_id
-    {
+    if acc.escrow_tokens.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -457,29 +680,31 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
 
     let (escrow_tokens_pubkey, nonce) =
         Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
-    let recipient_tokens_key = get_associated_token_address(acc.recipient.key, acc.mint.key);
 
-    if acc.token_program.key != &spl_token::id()
-        || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.recipient_tokens.key != &recipient_tokens_key
-    {
+    if acc.token_program.key != &spl_token::id() || acc.escrow_tokens.key != &escrow_tokens_pubkey {
         return Err(ProgramError::InvalidAccountData);
     }
 
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
     let mut data = acc.metadata.try_borrow_mut_data()?;
-    // let mut metadata = match TokenStreamData::try_from_slice(&data) {
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
+
+    metadata.validate_accounts(&StreamTokenKeys {
+        mint: Some(acc.mint.key),
+        escrow_tokens: Some(acc.escrow_tokens.key),
+        sender_tokens: Some(acc.sender_tokens.key),
+        recipient_tokens: Some(acc.recipient_tokens.key),
+    })?;
     let mint_info = unpack_mint_account(&acc.mint)?;
 
     let now = Clock::get()?.unix_timestamp as u64;
     // if stream expired anyone can close it, if not check cancel authority
     msg!("Now: {}, closable at {}", now, metadata.closable_at);
     if now < metadata.closable_at {
-        //TODO: Update in future releases based on `cancelable_by_sender/recipient`
-        if acc.cancel_authority.key != acc.sender.key {
+        let cancel_authority = acc.cancel_authority.key;
+        let can_cancel = (cancel_authority == &metadata.sender && metadata.ix.cancelable_by_sender)
+            || (cancel_authority == &metadata.recipient && metadata.ix.cancelable_by_recipient);
+        if !can_cancel {
             return Err(ProgramError::InvalidAccountData);
         }
         if !acc.cancel_authority.is_signer {
@@ -487,42 +712,42 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
         }
     }
 
-    if acc.sender.key != &metadata.sender
-        || acc.sender_tokens.key != &metadata.sender_tokens
-        || acc.recipient.key != &metadata.recipient
-        || acc.recipient_tokens.key != &metadata.recipient_tokens
-        || acc.mint.key != &metadata.mint
-        || acc.escrow_tokens.key != &metadata.escrow_tokens
-    {
+    if acc.sender.key != &metadata.sender || acc.recipient.key != &metadata.recipient {
         return Err(ProgramError::InvalidAccountData);
     }
 
-    let available = metadata.available(now);
+    check_escrow_not_frozen(&acc.escrow_tokens)?;
+
+    log_memo(acc.memo_program.as_ref(), memo)?;
+
+    let refund = metadata.cancel(now);
+    let (available, remains) = (refund.to_recipient, refund.to_sender);
     msg!("Available {}", available);
     let escrow_token_info = unpack_token_account(&acc.escrow_tokens)?;
     msg!("Amount {}", escrow_token_info.amount);
     let seeds = [acc.metadata.key.as_ref(), &[nonce]];
-    invoke_signed(
-        &spl_token::instruction::transfer(
-            acc.token_program.key,
-            acc.escrow_tokens.key,
-            acc.recipient_tokens.key,
-            acc.escrow_tokens.key,
-            &[],
-            available,
-        )?,
-        &[
-            acc.escrow_tokens.clone(),    // src
-            acc.recipient_tokens.clone(), // dest
-            acc.escrow_tokens.clone(),    // auth
-            acc.token_program.clone(),    // program
-        ],
-        &[&seeds],
-    )?;
+    // Nothing vested yet, don't CPI (a frozen recipient account would fail it)
+    if available > 0 {
+        invoke_signed(
+            &spl_token::instruction::transfer(
+                acc.token_program.key,
+                acc.escrow_tokens.key,
+                acc.recipient_tokens.key,
+                acc.escrow_tokens.key,
+                &[],
+                available,
+            )?,
+            &[
+                acc.escrow_tokens.clone(),    // src
+                acc.recipient_tokens.clone(), // dest
+                acc.escrow_tokens.clone(),    // auth
+                acc.token_program.clone(),    // program
+            ],
+            &[&seeds],
+        )?;
+    }
     let escrow_token_info = unpack_token_account(&acc.escrow_tokens)?;
     msg!("Amount {}", escrow_token_info.amount);
-    metadata.withdrawn_amount += available;
-    let remains = metadata.ix.deposited_amount - metadata.withdrawn_amount;
     msg!(
         "Deposited {} , withdrawn: {}, tokens remain {}",
         metadata.ix.deposited_amount,
@@ -570,14 +795,43 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
         &[&seeds],
     )?;
 
-    //TODO: Close metadata account once there is alternative storage solution for historic data.
-    if now < metadata.closable_at {
-        metadata.last_withdrawn_at = now;
-        metadata.canceled_at = now;
+    if let Some(registry) = &acc.registry {
+        unregister_stream(program_id, registry, &metadata.recipient, acc.metadata.key)?;
+    }
+
+    if let Some(sender_counter) = &acc.sender_counter {
+        uncount_stream(program_id, sender_counter, &metadata.sender)?;
+    }
+
+    if let Some(mint_stats) = &acc.mint_stats {
+        count_mint_payout(
+            program_id,
+            mint_stats,
+            &metadata.mint,
+            available + remains,
+            true,
+        )?;
+    }
+
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
+
+    // The metadata is kept as a record of the stream, unless asked otherwise
+    if close_metadata {
+        if let Some(labels) = &acc.labels {
+            close_labels(program_id, labels, &acc.sender, acc.metadata.key)?;
+        }
+
+        data.fill(0);
+
+        let rent = acc.metadata.lamports();
+        **acc.metadata.try_borrow_mut_lamports()? = 0;
+        **acc.sender.try_borrow_mut_lamports()? += rent;
+        msg!("Closed metadata, returned rent: {} lamports", rent);
+    } else {
+        // Only the counters and timestamps change on cancel
+        metadata.save_state(&mut data)?;
     }
-    // Write the metadata to the account
-    let bytes = metadata.try_to_vec().unwrap();
-    data[0..bytes.len()].clone_from_slice(&bytes);
 
     msg!(
         "Transferred: {} {} tokens",
@@ -600,11 +854,7 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
 pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> ProgramResult {
     msg!("Transferring stream recipient");
 
-    if acc.metadata.data_is_empty()
-        || acc.metadata.owner != program_id
-        || acc.escrow_tokens.data_is_empty()
-        || acc.escrow_tokens.owner != &spl_token::id()
-    {
+    if acc.escrow_tokens.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -619,17 +869,22 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
         return Err(ProgramError::InvalidAccountData);
     }
 
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
     let mut data = acc.metadata.try_borrow_mut_data()?;
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
+
+    metadata.validate_accounts(&StreamTokenKeys {
+        mint: Some(acc.mint.key),
+        escrow_tokens: Some(acc.escrow_tokens.key),
+        ..Default::default()
+    })?;
 
     if !metadata.ix.transferable_by_recipient && !metadata.ix.transferable_by_sender {
         return Err(TransferNotAllowed.into());
     }
 
-    // See if the caller is authorized
+    // See if the caller is authorized, the sender and the recipient
+    // can each transfer only if their own flag allows it
     let mut authorized = false;
     if metadata.ix.transferable_by_recipient && &metadata.recipient == acc.authorized_wallet.key {
         authorized = true;
@@ -648,9 +903,6 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
         get_associated_token_address(acc.new_recipient.key, acc.mint.key);
 
     if acc.new_recipient_tokens.key != &new_recipient_tokens_key
-        || acc.mint.key != &metadata.mint
-        || acc.authorized_wallet.key != &metadata.recipient
-        || acc.escrow_tokens.key != &metadata.escrow_tokens
         || acc.escrow_tokens.key != &escrow_tokens_pubkey
         || acc.token_program.key != &spl_token::id()
         || acc.system_program.key != &system_program::id()
@@ -696,6 +948,9 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
     metadata.recipient = *acc.new_recipient.key;
     metadata.recipient_tokens = *acc.new_recipient_tokens.key;
 
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
+
     let bytes = metadata.try_to_vec()?;
     data[0..bytes.len()].clone_from_slice(&bytes);
 
@@ -704,11 +959,28 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
 
 /// Top up the SPL Token stream
 ///
-/// The function will add the amount to the metadata SPL account
-pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> ProgramResult {
+/// The function will add the amount to the metadata SPL account.
+/// No fees are charged on top of the deposit, so `amount` is both what
+/// gets pulled from `sender_tokens` and what `deposited_amount` grows by
+/// (i.e. the topup is always "net").
+///
+/// Vesting stays a function of the elapsed periods only, so topped up
+/// tokens vest retroactively: whatever the schedule has already unlocked
+/// becomes withdrawable immediately.
+///
+/// The signing `sender` is the funder. Streams created with
+/// `allow_external_topup` may be funded by anyone from their own tokens,
+/// others only by their sender. The funder is recorded as `last_topup_by`
+/// and in the `ToppedUp` event.
+pub fn topup_stream(
+    program_id: &Pubkey,
+    acc: TopUpAccounts,
+    amount: u64,
+    memo: &[u8],
+) -> ProgramResult {
     msg!("Topping up the escrow account");
 
-    if acc.metadata.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
+    if acc.escrow_tokens.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -731,6 +1003,8 @@ pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> Pro
         return Err(ProgramError::MissingRequiredSignature);
     }
 
+    log_memo(acc.memo_program.as_ref(), memo)?;
+
     let sender_token_info = unpack_token_account(&acc.sender_tokens)?;
 
     if &sender_token_info.mint != acc.mint.key {
@@ -742,15 +1016,19 @@ pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> Pro
         return Err(ProgramError::InvalidArgument);
     }
 
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
     let mut data = acc.metadata.try_borrow_mut_data()?;
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
 
-    if acc.mint.key != &metadata.mint || acc.escrow_tokens.key != &metadata.escrow_tokens {
-        msg!("Error: Metadata does not match given accounts");
-        return Err(ProgramError::InvalidAccountData);
+    // The funder's tokens needn't be the sender's, see below who may fund
+    metadata.validate_accounts(&StreamTokenKeys {
+        mint: Some(acc.mint.key),
+        escrow_tokens: Some(acc.escrow_tokens.key),
+        ..Default::default()
+    })?;
+
+    if acc.sender.key != &metadata.sender && !metadata.ext.options.allow_external_topup {
+        return Err(ExternalTopupNotAllowed.into());
     }
 
     let now = Clock::get()?.unix_timestamp as u64;
@@ -759,6 +1037,10 @@ pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> Pro
         return Err(StreamClosed.into());
     }
 
+    metadata.topup(amount)?;
+    // Legacy streams have nowhere to keep it, the event still records it
+    metadata.ext.last_topup_by = *acc.sender.key;
+
     msg!("Transferring to the escrow account");
     invoke(
         &spl_token::instruction::transfer(
@@ -777,8 +1059,8 @@ pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> Pro
         ],
     )?;
 
-    metadata.ix.deposited_amount += amount;
-    metadata.closable_at = metadata.closable();
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
 
     let bytes = metadata.try_to_vec().unwrap();
     data[0..bytes.len()].clone_from_slice(&bytes);
@@ -792,5 +1074,820 @@ pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> Pro
         acc.sender.key,
     );
 
-    Ok(())
+    emit(&StreamEvent::ToppedUp {
+        metadata: *acc.metadata.key,
+        funder: *acc.sender.key,
+        amount,
+    })
+}
+
+/// Extend an SPL Token stream to a new end time
+///
+/// The sender picks the `end_time` and the program works out the deposit
+/// needed to keep releasing the same amount per period until then, see
+/// `TokenStreamData::extend_to`, and pulls it from `sender_tokens`.
+pub fn extend_stream(program_id: &Pubkey, acc: TopUpAccounts, end_time: u64) -> ProgramResult {
+    msg!("Extending SPL token stream");
+
+    if acc.escrow_tokens.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.sender.is_writable
+        || !acc.sender_tokens.is_writable
+        || !acc.metadata.is_writable
+        || !acc.escrow_tokens.is_writable
+    {
+        return Err(AccountsNotWritable.into());
+    }
+
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
+
+    if acc.token_program.key != &spl_token::id() || acc.escrow_tokens.key != &escrow_tokens_pubkey {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if !acc.sender.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let sender_token_info = unpack_token_account(&acc.sender_tokens)?;
+
+    if &sender_token_info.mint != acc.mint.key {
+        return Err(MintMismatch.into());
+    }
+
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
+    let mut data = acc.metadata.try_borrow_mut_data()?;
+
+    // Unlike a topup, this changes the schedule, so only the sender may
+    if acc.sender.key != &metadata.sender {
+        msg!("Error: Metadata does not match given accounts");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    metadata.validate_accounts(&StreamTokenKeys {
+        mint: Some(acc.mint.key),
+        escrow_tokens: Some(acc.escrow_tokens.key),
+        ..Default::default()
+    })?;
+
+    let now = Clock::get()?.unix_timestamp as u64;
+    if metadata.canceled_at > 0 || metadata.closable() < now {
+        msg!("Error: Extending after the stream is closed");
+        return Err(StreamClosed.into());
+    }
+
+    let amount = metadata.extend_to(end_time)?;
+
+    if sender_token_info.amount < amount {
+        msg!(
+            "Error: Sender has {} tokens, extending needs {}",
+            sender_token_info.amount,
+            amount
+        );
+        return Err(InsufficientFunds.into());
+    }
+
+    if amount > 0 {
+        msg!("Transferring to the escrow account");
+        invoke(
+            &spl_token::instruction::transfer(
+                acc.token_program.key,
+                acc.sender_tokens.key,
+                acc.escrow_tokens.key,
+                acc.sender.key,
+                &[],
+                amount,
+            )?,
+            &[
+                acc.sender_tokens.clone(),
+                acc.escrow_tokens.clone(),
+                acc.sender.clone(),
+                acc.token_program.clone(),
+            ],
+        )?;
+    }
+
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
+
+    let bytes = metadata.try_to_vec()?;
+    data[0..bytes.len()].clone_from_slice(&bytes);
+
+    let mint_info = unpack_mint_account(&acc.mint)?;
+
+    msg!(
+        "Extended token stream {} to {}, depositing {}",
+        acc.metadata.key,
+        end_time,
+        encode_base10(amount, mint_info.decimals.into()),
+    );
+
+    Ok(())
+}
+
+/// Split part of an SPL Token stream off to a new recipient
+///
+/// The recipient of a stream that's transferable by its recipient can
+/// hand `amount` of its deposit to `new_recipient`, as a new stream at
+/// `new_metadata` with its own escrow, once everything vested so far is
+/// withdrawn. See `TokenStreamData::split` for how the schedule and what's
+/// already withdrawn are shared. The new stream
+/// keeps the sender and its permissions, so the sender can still cancel
+/// either part. The recipient pays the rent for the new accounts.
+///
+/// The new stream is indexed like a created one in whichever of the new
+/// recipient's registry, the sender's counter and the mint stats are
+/// passed. Its deposit is moved rather than new, so only the mint's
+/// active streams grow.
+pub fn split_stream(program_id: &Pubkey, acc: SplitAccounts, amount: u64) -> ProgramResult {
+    msg!("Splitting SPL token stream");
+
+    if acc.escrow_tokens.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.new_metadata.data_is_empty() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    let new_escrow_exists = !acc.new_escrow_tokens.data_is_empty();
+    if new_escrow_exists && !is_pristine_escrow(&acc.new_escrow_tokens, acc.mint.key) {
+        return Err(EscrowAccountCompromised.into());
+    }
+
+    if new_escrow_exists && !is_rent_exempt(&acc.new_escrow_tokens)? {
+        msg!("Error: Escrow account is not rent-exempt");
+        return Err(NotRentExempt.into());
+    }
+
+    if is_null_key(acc.new_recipient.key) {
+        msg!("Error: Stream accounts can't be the default or system program pubkey");
+        return Err(InvalidAccount.into());
+    }
+
+    if !acc.recipient.is_writable
+        || !acc.metadata.is_writable
+        || !acc.escrow_tokens.is_writable
+        || !acc.new_recipient_tokens.is_writable
+        || !acc.new_metadata.is_writable
+        || !acc.new_escrow_tokens.is_writable
+    {
+        return Err(AccountsNotWritable.into());
+    }
+
+    let (escrow_tokens_pubkey, nonce) =
+        Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
+    let (new_escrow_tokens_pubkey, new_nonce) =
+        Pubkey::find_program_address(&[acc.new_metadata.key.as_ref()], program_id);
+    let new_recipient_tokens_key =
+        get_associated_token_address(acc.new_recipient.key, acc.mint.key);
+
+    if acc.system_program.key != &system_program::id()
+        || acc.token_program.key != &spl_token::id()
+        || acc.rent.key != &sysvar::rent::id()
+        || acc.escrow_tokens.key != &escrow_tokens_pubkey
+        || acc.new_escrow_tokens.key != &new_escrow_tokens_pubkey
+        || acc.new_recipient_tokens.key != &new_recipient_tokens_key
+    {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if !acc.recipient.is_signer || !acc.new_metadata.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
+    let mut data = acc.metadata.try_borrow_mut_data()?;
+
+    if acc.recipient.key != &metadata.recipient {
+        msg!("Error: Metadata does not match given accounts");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    metadata.validate_accounts(&StreamTokenKeys {
+        mint: Some(acc.mint.key),
+        escrow_tokens: Some(acc.escrow_tokens.key),
+        ..Default::default()
+    })?;
+
+    // Handing part of the stream to someone else is a partial transfer
+    if !metadata.ix.transferable_by_recipient {
+        return Err(TransferNotAllowed.into());
+    }
+
+    let now = Clock::get()?.unix_timestamp as u64;
+    let mut split = metadata.split(amount, now)?;
+    split.recipient = *acc.new_recipient.key;
+    split.recipient_tokens = *acc.new_recipient_tokens.key;
+    split.escrow_tokens = *acc.new_escrow_tokens.key;
+    let moved = split.ix.deposited_amount - split.withdrawn_amount;
+
+    #[cfg(feature = "strict-invariants")]
+    {
+        metadata.check_invariants()?;
+        split.check_invariants()?;
+    }
+
+    let split_bytes = split.try_to_vec()?;
+    // We pad % 8 for size , since that's what has to be allocated.
+    let mut split_struct_size = split_bytes.len();
+    while split_struct_size % 8 > 0 {
+        split_struct_size += 1;
+    }
+    let tokens_struct_size = spl_token::state::Account::LEN;
+
+    let cluster_rent = Rent::get()?;
+    let metadata_rent = cluster_rent.minimum_balance(split_struct_size);
+    let mut tokens_rent = cluster_rent.minimum_balance(tokens_struct_size);
+    if acc.new_recipient_tokens.data_is_empty() {
+        tokens_rent += cluster_rent.minimum_balance(tokens_struct_size);
+    }
+
+    let fees = Fees::get()?;
+    let lps = fees.fee_calculator.lamports_per_signature;
+
+    if acc.recipient.lamports() < metadata_rent + tokens_rent + (2 * lps) {
+        msg!("Error: Insufficient funds in {}", acc.recipient.key);
+        return Err(ProgramError::InsufficientFunds);
+    }
+
+    if acc.new_recipient_tokens.data_is_empty() {
+        msg!("Initializing new recipient's associated token account");
+        invoke(
+            &create_associated_token_account(
+                acc.recipient.key,
+                acc.new_recipient.key,
+                acc.mint.key,
+            ),
+            &[
+                acc.recipient.clone(),
+                acc.new_recipient_tokens.clone(),
+                acc.new_recipient.clone(),
+                acc.mint.clone(),
+                acc.system_program.clone(),
+                acc.token_program.clone(),
+                acc.rent.clone(),
+            ],
+        )?;
+    }
+
+    msg!("Creating account for holding the new stream's metadata");
+    invoke(
+        &system_instruction::create_account(
+            acc.recipient.key,
+            acc.new_metadata.key,
+            metadata_rent,
+            split_struct_size as u64,
+            program_id,
+        ),
+        &[
+            acc.recipient.clone(),
+            acc.new_metadata.clone(),
+            acc.system_program.clone(),
+        ],
+    )?;
+
+    let mut new_data = acc.new_metadata.try_borrow_mut_data()?;
+    new_data[0..split_bytes.len()].clone_from_slice(&split_bytes);
+
+    if !new_escrow_exists {
+        let seeds = [acc.new_metadata.key.as_ref(), &[new_nonce]];
+        msg!("Creating account for holding the new stream's tokens");
+        invoke_signed(
+            &system_instruction::create_account(
+                acc.recipient.key,
+                acc.new_escrow_tokens.key,
+                cluster_rent.minimum_balance(tokens_struct_size),
+                tokens_struct_size as u64,
+                &spl_token::id(),
+            ),
+            &[
+                acc.recipient.clone(),
+                acc.new_escrow_tokens.clone(),
+                acc.system_program.clone(),
+            ],
+            &[&seeds],
+        )?;
+
+        msg!("Initializing escrow account for {} token", acc.mint.key);
+        invoke(
+            &spl_token::instruction::initialize_account(
+                acc.token_program.key,
+                acc.new_escrow_tokens.key,
+                acc.mint.key,
+                acc.new_escrow_tokens.key,
+            )?,
+            &[
+                acc.token_program.clone(),
+                acc.new_escrow_tokens.clone(),
+                acc.mint.clone(),
+                acc.new_escrow_tokens.clone(),
+                acc.rent.clone(),
+            ],
+        )?;
+    }
+
+    msg!("Moving funds into the new escrow account");
+    let seeds = [acc.metadata.key.as_ref(), &[nonce]];
+    invoke_signed(
+        &spl_token::instruction::transfer(
+            acc.token_program.key,
+            acc.escrow_tokens.key,
+            acc.new_escrow_tokens.key,
+            acc.escrow_tokens.key,
+            &[],
+            moved,
+        )?,
+        &[
+            acc.escrow_tokens.clone(),
+            acc.new_escrow_tokens.clone(),
+            acc.escrow_tokens.clone(),
+            acc.token_program.clone(),
+        ],
+        &[&seeds],
+    )?;
+
+    let bytes = metadata.try_to_vec()?;
+    data[0..bytes.len()].clone_from_slice(&bytes);
+
+    if let Some(registry) = &acc.registry {
+        register_stream(
+            program_id,
+            registry,
+            &acc.recipient,
+            &acc.system_program,
+            acc.new_recipient.key,
+            acc.new_metadata.key,
+        )?;
+    }
+
+    if let Some(sender_counter) = &acc.sender_counter {
+        count_stream(
+            program_id,
+            sender_counter,
+            &acc.recipient,
+            &acc.system_program,
+            &metadata.sender,
+        )?;
+    }
+
+    if let Some(mint_stats) = &acc.mint_stats {
+        count_mint_stream(
+            program_id,
+            mint_stats,
+            &acc.recipient,
+            &acc.system_program,
+            acc.mint.key,
+            0,
+        )?;
+    }
+
+    let mint_info = unpack_mint_account(&acc.mint)?;
+
+    msg!(
+        "Split {} {} tokens off token stream {} into {} for {}",
+        encode_base10(amount, mint_info.decimals.into()),
+        metadata.mint,
+        acc.metadata.key,
+        acc.new_metadata.key,
+        acc.new_recipient.key
+    );
+
+    Ok(())
+}
+
+/// Update the cancel and transfer permissions of a stream
+///
+/// Each changed flag needs the signature of the party it works against, see
+/// `StreamPermissions::required_signers`: the sender can give up their own
+/// rights or grant the recipient new ones alone, and the other way around.
+/// Either party has to sign even when nothing changes.
+pub fn update_permissions(
+    program_id: &Pubkey,
+    acc: UpdatePermissionsAccounts,
+    permissions: StreamPermissions,
+) -> ProgramResult {
+    msg!("Updating stream permissions");
+
+    if !acc.metadata.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    let mut metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
+    let mut data = acc.metadata.try_borrow_mut_data()?;
+
+    if acc.sender.key != &metadata.sender || acc.recipient.key != &metadata.recipient {
+        msg!("Error: Metadata does not match given accounts");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let (sender_signs, recipient_signs) = metadata.ix.permissions().required_signers(&permissions);
+    if (sender_signs && !acc.sender.is_signer)
+        || (recipient_signs && !acc.recipient.is_signer)
+        || (!acc.sender.is_signer && !acc.recipient.is_signer)
+    {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    metadata.ix.cancelable_by_sender = permissions.cancelable_by_sender;
+    metadata.ix.cancelable_by_recipient = permissions.cancelable_by_recipient;
+    metadata.ix.transferable_by_sender = permissions.transferable_by_sender;
+    metadata.ix.transferable_by_recipient = permissions.transferable_by_recipient;
+
+    #[cfg(feature = "strict-invariants")]
+    metadata.check_invariants()?;
+
+    let bytes = metadata.try_to_vec()?;
+    data[0..bytes.len()].clone_from_slice(&bytes);
+
+    msg!("Stream {} permissions: {:?}", acc.metadata.key, permissions);
+    emit(&StreamEvent::PermissionsUpdated {
+        metadata: *acc.metadata.key,
+        permissions,
+    })
+}
+
+/// Set the labels of a stream, replacing any previous ones
+///
+/// The labels account is created on first use at the `find_labels_account`
+/// PDA of the stream, with the sender paying the rent. Only the sender can
+/// label a stream, and the metadata itself is left untouched.
+pub fn set_labels(program_id: &Pubkey, acc: LabelsAccounts, labels: StreamLabels) -> ProgramResult {
+    msg!("Setting stream labels");
+
+    if !acc.sender.is_writable || !acc.labels.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if !acc.sender.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
+    if acc.sender.key != &metadata.sender {
+        msg!("Error: Only the stream sender can set labels");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    labels.validate()?;
+
+    let (labels_pubkey, nonce) = find_labels_account(acc.metadata.key, program_id);
+    if acc.labels.key != &labels_pubkey {
+        msg!("Error: Invalid stream labels account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if acc.labels.data_is_empty() {
+        msg!("Creating stream labels for {}", acc.metadata.key);
+        let seeds = [b"labels".as_ref(), acc.metadata.key.as_ref(), &[nonce]];
+        create_pda_account(
+            program_id,
+            &acc.labels,
+            &acc.sender,
+            &acc.system_program,
+            &seeds,
+            LABELS_SIZE,
+        )?;
+    } else if acc.labels.owner != program_id {
+        msg!("Error: Invalid stream labels account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut data = acc.labels.try_borrow_mut_data()?;
+    labels.pack(&mut data)
+}
+
+/// Clear the labels of a stream
+///
+/// The labels account is emptied and its rent returned to the sender.
+/// Streams whose metadata is kept need this to get the labels rent back,
+/// `cancel_and_close` closes the labels along with the metadata.
+pub fn clear_labels(program_id: &Pubkey, acc: LabelsAccounts) -> ProgramResult {
+    msg!("Clearing stream labels");
+
+    if !acc.sender.is_writable || !acc.labels.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if !acc.sender.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    let metadata = TokenStreamData::load_checked(&acc.metadata, program_id)?;
+    metadata.check_version()?;
+    if acc.sender.key != &metadata.sender {
+        msg!("Error: Only the stream sender can clear labels");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if acc.labels.data_is_empty() {
+        msg!("Error: Invalid stream labels account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    close_labels(program_id, &acc.labels, &acc.sender, acc.metadata.key)
+}
+
+/// Close the labels account of the stream at `metadata`, if there is one,
+/// returning its rent to `sender`.
+fn close_labels(
+    program_id: &Pubkey,
+    labels: &AccountInfo,
+    sender: &AccountInfo,
+    metadata: &Pubkey,
+) -> ProgramResult {
+    let (labels_pubkey, _) = find_labels_account(metadata, program_id);
+    if labels.key != &labels_pubkey {
+        msg!("Error: Invalid stream labels account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if labels.data_is_empty() {
+        return Ok(());
+    }
+
+    if labels.owner != program_id || !labels.is_writable {
+        msg!("Error: Invalid stream labels account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    labels.try_borrow_mut_data()?.fill(0);
+
+    let rent = labels.lamports();
+    **labels.try_borrow_mut_lamports()? = 0;
+    **sender.try_borrow_mut_lamports()? += rent;
+
+    msg!("Closed labels, returned rent: {} lamports", rent);
+
+    Ok(())
+}
+
+/// Index `metadata` in the `recipient`'s stream registry, creating the
+/// registry on first use with `payer` funding the rent.
+fn register_stream<'a>(
+    program_id: &Pubkey,
+    registry: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+) -> ProgramResult {
+    let (registry_pubkey, nonce) = find_registry_account(recipient, program_id);
+    if registry.key != &registry_pubkey || !registry.is_writable {
+        msg!("Error: Invalid stream registry account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if registry.data_is_empty() {
+        msg!("Creating stream registry for {}", recipient);
+        let seeds = [b"registry".as_ref(), recipient.as_ref(), &[nonce]];
+        create_pda_account(
+            program_id,
+            registry,
+            payer,
+            system_program,
+            &seeds,
+            REGISTRY_SIZE,
+        )?;
+    } else if registry.owner != program_id {
+        msg!("Error: Invalid stream registry account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut data = registry.try_borrow_mut_data()?;
+    let mut index = StreamRegistry::unpack(&data)?;
+    if !index.add(metadata) {
+        msg!("Stream not indexed, registry is full");
+        return Ok(());
+    }
+
+    index.pack(&mut data)
+}
+
+/// Drop `metadata` from the `recipient`'s stream registry, if it exists.
+fn unregister_stream(
+    program_id: &Pubkey,
+    registry: &AccountInfo,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+) -> ProgramResult {
+    let (registry_pubkey, _) = find_registry_account(recipient, program_id);
+    if registry.key != &registry_pubkey || !registry.is_writable {
+        msg!("Error: Invalid stream registry account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if registry.data_is_empty() || registry.owner != program_id {
+        return Ok(());
+    }
+
+    let mut data = registry.try_borrow_mut_data()?;
+    let mut index = StreamRegistry::unpack(&data)?;
+    if index.remove(metadata) {
+        index.pack(&mut data)?;
+    }
+
+    Ok(())
+}
+
+/// Increment the `sender`'s stream counter, creating the counter on first
+/// use with `payer` funding the rent.
+fn count_stream<'a>(
+    program_id: &Pubkey,
+    sender_counter: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    sender: &Pubkey,
+) -> ProgramResult {
+    let (counter_pubkey, nonce) = find_sender_counter_account(sender, program_id);
+    if sender_counter.key != &counter_pubkey || !sender_counter.is_writable {
+        msg!("Error: Invalid sender stream counter account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if sender_counter.data_is_empty() {
+        msg!("Creating stream counter for {}", sender);
+        let seeds = [b"counter".as_ref(), sender.as_ref(), &[nonce]];
+        create_pda_account(
+            program_id,
+            sender_counter,
+            payer,
+            system_program,
+            &seeds,
+            COUNTER_SIZE,
+        )?;
+    } else if sender_counter.owner != program_id {
+        msg!("Error: Invalid sender stream counter account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut data = sender_counter.try_borrow_mut_data()?;
+    let mut counter = StreamCounter::unpack(&data)?;
+    counter.count += 1;
+    counter.pack(&mut data)
+}
+
+/// Decrement the `sender`'s stream counter, if it exists.
+fn uncount_stream(
+    program_id: &Pubkey,
+    sender_counter: &AccountInfo,
+    sender: &Pubkey,
+) -> ProgramResult {
+    let (counter_pubkey, _) = find_sender_counter_account(sender, program_id);
+    if sender_counter.key != &counter_pubkey || !sender_counter.is_writable {
+        msg!("Error: Invalid sender stream counter account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if sender_counter.data_is_empty() || sender_counter.owner != program_id {
+        return Ok(());
+    }
+
+    let mut data = sender_counter.try_borrow_mut_data()?;
+    let mut counter = StreamCounter::unpack(&data)?;
+    // Streams created without passing the counter were never counted
+    counter.count = counter.count.saturating_sub(1);
+    counter.pack(&mut data)
+}
+
+/// Count a stream created with `deposited_amount` in the stats of `mint`,
+/// creating them on first use with `payer` funding the rent.
+fn count_mint_stream<'a>(
+    program_id: &Pubkey,
+    mint_stats: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    mint: &Pubkey,
+    deposited_amount: u64,
+) -> ProgramResult {
+    let (stats_pubkey, nonce) = find_mint_stats_account(mint, program_id);
+    if mint_stats.key != &stats_pubkey || !mint_stats.is_writable {
+        msg!("Error: Invalid mint stats account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if mint_stats.data_is_empty() {
+        msg!("Creating stream stats for {}", mint);
+        let seeds = [b"stats".as_ref(), mint.as_ref(), &[nonce]];
+        create_pda_account(
+            program_id,
+            mint_stats,
+            payer,
+            system_program,
+            &seeds,
+            MINT_STATS_SIZE,
+        )?;
+    } else if mint_stats.owner != program_id {
+        msg!("Error: Invalid mint stats account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    let mut data = mint_stats.try_borrow_mut_data()?;
+    let mut stats = MintStats::unpack(&data)?;
+    stats.add_stream(deposited_amount);
+    stats.pack(&mut data)
+}
+
+/// Count `amount` paid out of a stream in the stats of `mint`, if they
+/// exist, and the stream as ended when `closed`.
+fn count_mint_payout(
+    program_id: &Pubkey,
+    mint_stats: &AccountInfo,
+    mint: &Pubkey,
+    amount: u64,
+    closed: bool,
+) -> ProgramResult {
+    let (stats_pubkey, _) = find_mint_stats_account(mint, program_id);
+    if mint_stats.key != &stats_pubkey || !mint_stats.is_writable {
+        msg!("Error: Invalid mint stats account");
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if mint_stats.data_is_empty() || mint_stats.owner != program_id {
+        return Ok(());
+    }
+
+    let mut data = mint_stats.try_borrow_mut_data()?;
+    let mut stats = MintStats::unpack(&data)?;
+    stats.add_payout(amount, closed);
+    stats.pack(&mut data)
+}
+
+/// Whether `escrow` is an empty token account of `mint` that only the escrow
+/// PDA itself controls, exactly as `create` initializes it.
+fn is_pristine_escrow(escrow: &AccountInfo, mint: &Pubkey) -> bool {
+    match unpack_token_account(escrow) {
+        Ok(info) => {
+            &info.mint == mint
+                && &info.owner == escrow.key
+                && info.amount == 0
+                && info.delegate.is_none()
+                && info.close_authority.is_none()
+                && !info.is_frozen()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Fail clearly when the mint's freeze authority froze the escrow, instead
+/// of with the token program's opaque error on the transfer out of it.
+fn check_escrow_not_frozen(escrow: &AccountInfo) -> ProgramResult {
+    if unpack_token_account(escrow)?.is_frozen() {
+        msg!("Error: Escrow {} is frozen, funds can't move", escrow.key);
+        return Err(EscrowFrozen.into());
+    }
+
+    Ok(())
+}
+
+/// Whether `account` holds enough lamports for its data to be rent-exempt.
+fn is_rent_exempt(account: &AccountInfo) -> Result<bool, ProgramError> {
+    Ok(Rent::get()?.is_exempt(account.lamports(), account.data_len()))
+}
+
+/// Pass a non-empty `memo` on to the SPL Memo program, which must be given
+/// along with it.
+fn log_memo(memo_program: Option<&AccountInfo>, memo: &[u8]) -> ProgramResult {
+    if memo.is_empty() {
+        return Ok(());
+    }
+
+    let ix = memo_instruction(memo)?;
+
+    let memo_program = memo_program.ok_or(ProgramError::NotEnoughAccountKeys)?;
+    if memo_program.key != &spl_memo::id() {
+        return Err(ProgramError::IncorrectProgramId);
+    }
+
+    invoke(&ix, std::slice::from_ref(memo_program))
+}
+
+/// Create a program-owned account of `size` at the PDA signed for by
+/// `seeds`, with `payer` funding the rent.
+fn create_pda_account<'a>(
+    program_id: &Pubkey,
+    account: &AccountInfo<'a>,
+    payer: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    seeds: &[&[u8]],
+    size: usize,
+) -> ProgramResult {
+    invoke_signed(
+        &system_instruction::create_account(
+            payer.key,
+            account.key,
+            Rent::get()?.minimum_balance(size),
+            size as u64,
+            program_id,
+        ),
+        &[payer.clone(), account.clone(), system_program.clone()],
+        &[seeds],
+    )
 }
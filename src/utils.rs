@@ -15,7 +15,14 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 use std::iter::FromIterator;
 
-use solana_program::{account_info::AccountInfo, program_error::ProgramError, program_pack::Pack};
+use solana_program::{
+    account_info::AccountInfo, instruction::Instruction, program_error::ProgramError,
+    program_pack::Pack, pubkey::Pubkey, system_program,
+};
+use spl_associated_token_account::get_associated_token_address;
+
+use crate::error::StreamFlowError::{self, InvalidMemo};
+use crate::state::{MEMO_MAX_SIZE, START_TIME_TOLERANCE};
 
 /// Do a sanity check with given Unix timestamps.
 pub fn duration_sanity(now: u64, start: u64, end: u64, cliff: u64) -> bool {
@@ -25,7 +32,86 @@ pub fn duration_sanity(now: u64, start: u64, end: u64, cliff: u64) -> bool {
         start <= cliff && cliff <= end
     };
 
-    now < start && start < end && cliff_cond
+    start_time_sanity(now, start) && start < end && now < end && cliff_cond
+}
+
+/// Check that `start` is no more than `START_TIME_TOLERANCE` in the past.
+pub fn start_time_sanity(now: u64, start: u64) -> bool {
+    start.saturating_add(START_TIME_TOLERANCE) >= now
+}
+
+/// Check if given pubkey is the default (all zeros) or the system program one.
+pub fn is_null_key(key: &Pubkey) -> bool {
+    key == &Pubkey::default() || key == &system_program::id()
+}
+
+/// Derive the address of the `recipient`'s stream registry
+pub fn find_registry_account(recipient: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"registry", recipient.as_ref()], program_id)
+}
+
+/// Derive the address of the `sender`'s stream counter
+pub fn find_sender_counter_account(sender: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"counter", sender.as_ref()], program_id)
+}
+
+/// Derive the address of the stats of the streams of `mint`
+pub fn find_mint_stats_account(mint: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"stats", mint.as_ref()], program_id)
+}
+
+/// Derive the address of the labels attached to the stream at `metadata`
+pub fn find_labels_account(metadata: &Pubkey, program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[b"labels", metadata.as_ref()], program_id)
+}
+
+/// Addresses of the accounts a stream uses besides its metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamAccounts {
+    /// The sender's associated token account
+    pub sender_tokens: Pubkey,
+    /// The recipient's associated token account
+    pub recipient_tokens: Pubkey,
+    /// The escrow account holding the stream funds
+    pub escrow_tokens: Pubkey,
+    /// The recipient's stream registry
+    pub registry: Pubkey,
+    /// The sender's stream counter
+    pub sender_counter: Pubkey,
+    /// The stream labels account
+    pub labels: Pubkey,
+    /// The stats of the streams of the mint
+    pub mint_stats: Pubkey,
+}
+
+/// Derive the addresses of the accounts used by the stream at `metadata`
+/// from `sender` to `recipient` of `mint`, so clients needn't know the seeds.
+pub fn derive_stream_accounts(
+    metadata: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    program_id: &Pubkey,
+) -> StreamAccounts {
+    StreamAccounts {
+        sender_tokens: get_associated_token_address(sender, mint),
+        recipient_tokens: get_associated_token_address(recipient, mint),
+        escrow_tokens: Pubkey::find_program_address(&[metadata.as_ref()], program_id).0,
+        registry: find_registry_account(recipient, program_id).0,
+        sender_counter: find_sender_counter_account(sender, program_id).0,
+        labels: find_labels_account(metadata, program_id).0,
+        mint_stats: find_mint_stats_account(mint, program_id).0,
+    }
+}
+
+/// Build the SPL Memo instruction logging `memo`, which must be valid UTF-8
+/// of at most `MEMO_MAX_SIZE` bytes.
+pub fn memo_instruction(memo: &[u8]) -> Result<Instruction, StreamFlowError> {
+    if memo.len() > MEMO_MAX_SIZE || std::str::from_utf8(memo).is_err() {
+        return Err(InvalidMemo);
+    }
+
+    Ok(spl_memo::build_memo(memo, &[]))
 }
 
 /// Unpack token account from `account_info`
@@ -74,7 +160,15 @@ pub fn encode_base10(amount: u64, decimal_places: usize) -> String {
 
 #[allow(unused_imports)]
 mod tests {
-    use crate::utils::duration_sanity;
+    use solana_program::{pubkey::Pubkey, system_program};
+
+    use crate::error::StreamFlowError::InvalidMemo;
+    use crate::state::{MEMO_MAX_SIZE, START_TIME_TOLERANCE};
+    use crate::utils::{
+        derive_stream_accounts, duration_sanity, find_labels_account, find_mint_stats_account,
+        find_registry_account, find_sender_counter_account, is_null_key, memo_instruction,
+        start_time_sanity,
+    };
 
     #[test]
     fn test_duration_sanity() {
@@ -86,4 +180,79 @@ mod tests {
         assert!(!duration_sanity(130, 130, 130, 130));
         assert!(!duration_sanity(100, 110, 130, 140));
     }
+
+    #[test]
+    fn test_start_time_sanity() {
+        // now, start
+        assert!(start_time_sanity(1000, 1010));
+        assert!(start_time_sanity(1000, 990));
+        assert!(start_time_sanity(1000, 1000 - START_TIME_TOLERANCE));
+        assert!(!start_time_sanity(1000, 999 - START_TIME_TOLERANCE));
+        assert!(!start_time_sanity(u64::MAX, 0));
+
+        // A recent start is fine as long as the stream hasn't ended yet
+        assert!(duration_sanity(1000, 990, 1100, 0));
+        assert!(!duration_sanity(1000, 990, 1000, 0));
+    }
+
+    #[test]
+    fn test_is_null_key() {
+        assert!(is_null_key(&Pubkey::default()));
+        assert!(is_null_key(&system_program::id()));
+        assert!(!is_null_key(&Pubkey::new_unique()));
+        assert!(!is_null_key(&spl_token::id()));
+    }
+
+    #[test]
+    fn test_derive_stream_accounts() {
+        let program_id = Pubkey::new_unique();
+        let (metadata, sender, recipient, mint) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+
+        let accounts = derive_stream_accounts(&metadata, &sender, &recipient, &mint, &program_id);
+        assert_eq!(
+            accounts.escrow_tokens,
+            Pubkey::find_program_address(&[metadata.as_ref()], &program_id).0
+        );
+        assert_eq!(
+            accounts.recipient_tokens,
+            spl_associated_token_account::get_associated_token_address(&recipient, &mint)
+        );
+        assert_eq!(
+            accounts.registry,
+            find_registry_account(&recipient, &program_id).0
+        );
+        assert_eq!(
+            accounts.sender_counter,
+            find_sender_counter_account(&sender, &program_id).0
+        );
+        assert_eq!(
+            accounts.labels,
+            find_labels_account(&metadata, &program_id).0
+        );
+        assert_eq!(
+            accounts.mint_stats,
+            find_mint_stats_account(&mint, &program_id).0
+        );
+        assert_ne!(accounts.sender_tokens, accounts.recipient_tokens);
+    }
+
+    #[test]
+    fn test_memo_instruction() {
+        let ix = memo_instruction("stream 42, invoice №7".as_bytes()).unwrap();
+        assert_eq!(ix.program_id, spl_memo::id());
+        assert_eq!(ix.data, "stream 42, invoice №7".as_bytes());
+        assert!(ix.accounts.is_empty());
+
+        assert!(memo_instruction(&[b'a'; MEMO_MAX_SIZE]).is_ok());
+        assert_eq!(
+            memo_instruction(&[b'a'; MEMO_MAX_SIZE + 1]),
+            Err(InvalidMemo)
+        );
+        assert_eq!(memo_instruction(&[0xff, 0xfe]), Err(InvalidMemo));
+    }
 }
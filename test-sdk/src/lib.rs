@@ -43,8 +43,20 @@ pub struct ProgramTestBench {
 
 impl ProgramTestBench {
     pub async fn start_new(programs: &[TestBenchProgram<'_>]) -> Self {
+        Self::start_with_accounts(programs, vec![]).await
+    }
+
+    /// Start the bench with `accounts` already in place at genesis
+    pub async fn start_with_accounts(
+        programs: &[TestBenchProgram<'_>],
+        accounts: Vec<(Pubkey, Account)>,
+    ) -> Self {
         let mut program_test = ProgramTest::default();
 
+        for (address, account) in accounts {
+            program_test.add_account(address, account);
+        }
+
         let alice = Keypair::new();
         let bob = Keypair::new();
 
@@ -125,6 +137,17 @@ impl ProgramTestBench {
     }
 
     pub async fn create_mint(&mut self, mint_keypair: &Keypair, mint_authority: &Pubkey) {
+        self.create_mint_with_decimals(mint_keypair, mint_authority, 0)
+            .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn create_mint_with_decimals(
+        &mut self,
+        mint_keypair: &Keypair,
+        mint_authority: &Pubkey,
+        decimals: u8,
+    ) {
         let mint_rent = self.rent.minimum_balance(spl_token::state::Mint::LEN);
 
         let instructions = [
@@ -140,7 +163,7 @@ impl ProgramTestBench {
                 &mint_keypair.pubkey(),
                 mint_authority,
                 None,
-                0,
+                decimals,
             )
             .unwrap(),
         ];
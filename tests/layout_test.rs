@@ -0,0 +1,268 @@
+// Golden vectors for the on-chain layouts the JS client mirrors by hand.
+// An intentional layout change must regenerate these in the same commit.
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::pubkey::Pubkey;
+
+use streamflow_timelock::events::StreamEvent;
+use streamflow_timelock::state::{
+    StreamExtension, StreamInstruction, StreamOptions, StreamType, TokenStreamData, EXTENSION_SIZE,
+    LEGACY_VERSION, METADATA_MIN_SIZE, PROGRAM_VERSION, STATE_SIZE, STREAM_INSTRUCTION_MIN_SIZE,
+    STRM_MAGIC, STRM_MAGIC_BYTES,
+};
+
+const METADATA_HEAD_HEX: &str = concat!(
+    "574f4c464d525453",                                                 // magic
+    "0100000000000000",                                                 // created_at
+    "0300000000000000",                                                 // withdrawn_amount
+    "0400000000000000",                                                 // canceled_at
+    "0500000000000000",                                                 // closable_at
+    "0600000000000000",                                                 // last_withdrawn_at
+    "0101010101010101010101010101010101010101010101010101010101010101", // sender
+    "0202020202020202020202020202020202020202020202020202020202020202", // sender_tokens
+    "0303030303030303030303030303030303030303030303030303030303030303", // recipient
+    "0404040404040404040404040404040404040404040404040404040404040404", // recipient_tokens
+    "0505050505050505050505050505050505050505050505050505050505050505", // mint
+    "0606060606060606060606060606060606060606060606060606060606060606", // escrow_tokens
+);
+
+const STREAM_INSTRUCTION_HEX: &str = concat!(
+    "0700000000000000",     // start_time
+    "0800000000000000",     // end_time
+    "0900000000000000",     // deposited_amount
+    "0a00000000000000",     // total_amount
+    "0b00000000000000",     // period
+    "0c00000000000000",     // cliff
+    "0d00000000000000",     // cliff_amount
+    "01",                   // cancelable_by_sender
+    "00",                   // cancelable_by_recipient
+    "01",                   // withdrawal_public
+    "00",                   // transferable_by_sender
+    "01",                   // transferable_by_recipient
+    "0e00000000000000",     // release_rate
+    "06000000676f6c64656e", // stream_name
+);
+
+const EXTENSION_HEX: &str = concat!(
+    "0300000000000000",                                                 // version
+    "0f00000000000000",                                                 // withdrawable_after
+    "01",                                                               // stream_type
+    "01",                                                               // sender_can_push
+    "00",                                                               // strict_ata
+    "01",                                                               // allow_external_topup
+    "06",                                                               // mint_decimals
+    "0808080808080808080808080808080808080808080808080808080808080808", // last_topup_by
+);
+
+const WITHDRAWN_EVENT_HEX: &str = concat!(
+    "00",                                                               // Withdrawn
+    "0707070707070707070707070707070707070707070707070707070707070707", // metadata
+    "0808080808080808080808080808080808080808080808080808080808080808", // initiator
+    "0900000000000000",                                                 // amount
+);
+
+const PERMISSIONS_EVENT_HEX: &str = concat!(
+    "01",                                                               // PermissionsUpdated
+    "0707070707070707070707070707070707070707070707070707070707070707", // metadata
+    "01",                                                               // cancelable_by_sender
+    "00",                                                               // cancelable_by_recipient
+    "00",                                                               // transferable_by_sender
+    "01",                                                               // transferable_by_recipient
+);
+
+const CREATED_EVENT_HEX: &str = concat!(
+    "02",                                                               // StreamCreated
+    "0707070707070707070707070707070707070707070707070707070707070707", // metadata
+    "0101010101010101010101010101010101010101010101010101010101010101", // sender
+    "0202020202020202020202020202020202020202020202020202020202020202", // recipient
+    "0303030303030303030303030303030303030303030303030303030303030303", // mint
+    "06",                                                               // mint_decimals
+    "0900000000000000",                                                 // deposited_amount
+);
+
+const TOPPED_UP_EVENT_HEX: &str = concat!(
+    "03",                                                               // ToppedUp
+    "0707070707070707070707070707070707070707070707070707070707070707", // metadata
+    "0808080808080808080808080808080808080808080808080808080808080808", // funder
+    "0900000000000000",                                                 // amount
+);
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn golden_instruction() -> StreamInstruction {
+    StreamInstruction {
+        start_time: 7,
+        end_time: 8,
+        deposited_amount: 9,
+        total_amount: 10,
+        period: 11,
+        cliff: 12,
+        cliff_amount: 13,
+        cancelable_by_sender: true,
+        cancelable_by_recipient: false,
+        withdrawal_public: true,
+        transferable_by_sender: false,
+        transferable_by_recipient: true,
+        release_rate: 14,
+        stream_name: "golden".to_string(),
+    }
+}
+
+fn golden_metadata() -> TokenStreamData {
+    TokenStreamData {
+        magic: STRM_MAGIC,
+        created_at: 1,
+        withdrawn_amount: 3,
+        canceled_at: 4,
+        closable_at: 5,
+        last_withdrawn_at: 6,
+        sender: Pubkey::new_from_array([1; 32]),
+        sender_tokens: Pubkey::new_from_array([2; 32]),
+        recipient: Pubkey::new_from_array([3; 32]),
+        recipient_tokens: Pubkey::new_from_array([4; 32]),
+        mint: Pubkey::new_from_array([5; 32]),
+        escrow_tokens: Pubkey::new_from_array([6; 32]),
+        ix: golden_instruction(),
+        ext: StreamExtension {
+            version: PROGRAM_VERSION,
+            options: StreamOptions {
+                withdrawable_after: 15,
+                stream_type: StreamType::Lock,
+                sender_can_push: true,
+                strict_ata: false,
+                allow_external_topup: true,
+            },
+            mint_decimals: 6,
+            last_topup_by: Pubkey::new_from_array([8; 32]),
+        },
+    }
+}
+
+#[test]
+fn stream_instruction_layout() {
+    let golden = unhex(STREAM_INSTRUCTION_HEX);
+
+    assert_eq!(golden_instruction().try_to_vec().unwrap(), golden);
+    assert_eq!(
+        StreamInstruction::try_from_slice(&golden).unwrap(),
+        golden_instruction()
+    );
+}
+
+#[test]
+fn metadata_layout() {
+    let golden = unhex(&format!(
+        "{}{}{}",
+        METADATA_HEAD_HEX, STREAM_INSTRUCTION_HEX, EXTENSION_HEX
+    ));
+    let metadata = golden_metadata();
+
+    assert_eq!(metadata.try_to_vec().unwrap(), golden);
+    assert_eq!(TokenStreamData::try_from_slice(&golden).unwrap(), metadata);
+    assert_eq!(metadata.state().try_to_vec().unwrap(), golden[..STATE_SIZE]);
+}
+
+#[test]
+fn legacy_metadata_layout() {
+    let golden = unhex(&format!("{}{}", METADATA_HEAD_HEX, STREAM_INSTRUCTION_HEX));
+    let metadata = TokenStreamData {
+        ext: StreamExtension::default(),
+        ..golden_metadata()
+    };
+
+    assert_eq!(metadata.try_to_vec().unwrap(), golden);
+    assert_eq!(TokenStreamData::try_from_slice(&golden).unwrap(), metadata);
+    assert_eq!(metadata.version(), LEGACY_VERSION);
+
+    // The padding of the account allocation reads as no extension too
+    let mut padded = golden.clone();
+    padded.resize(golden.len().next_multiple_of(8), 0);
+    assert_eq!(TokenStreamData::unpack(&padded).unwrap(), metadata);
+}
+
+#[test]
+fn event_layout() {
+    let golden = unhex(WITHDRAWN_EVENT_HEX);
+    let event = StreamEvent::Withdrawn {
+        metadata: Pubkey::new_from_array([7; 32]),
+        initiator: Pubkey::new_from_array([8; 32]),
+        amount: 9,
+    };
+
+    assert_eq!(event.try_to_vec().unwrap(), golden);
+    assert_eq!(StreamEvent::try_from_slice(&golden).unwrap(), event);
+
+    let golden = unhex(PERMISSIONS_EVENT_HEX);
+    let event = StreamEvent::PermissionsUpdated {
+        metadata: Pubkey::new_from_array([7; 32]),
+        permissions: StreamInstruction::default().permissions(),
+    };
+
+    assert_eq!(event.try_to_vec().unwrap(), golden);
+    assert_eq!(StreamEvent::try_from_slice(&golden).unwrap(), event);
+
+    let golden = unhex(CREATED_EVENT_HEX);
+    let event = StreamEvent::StreamCreated {
+        metadata: Pubkey::new_from_array([7; 32]),
+        sender: Pubkey::new_from_array([1; 32]),
+        recipient: Pubkey::new_from_array([2; 32]),
+        mint: Pubkey::new_from_array([3; 32]),
+        mint_decimals: 6,
+        deposited_amount: 9,
+    };
+
+    assert_eq!(event.try_to_vec().unwrap(), golden);
+    assert_eq!(StreamEvent::try_from_slice(&golden).unwrap(), event);
+
+    let golden = unhex(TOPPED_UP_EVENT_HEX);
+    let event = StreamEvent::ToppedUp {
+        metadata: Pubkey::new_from_array([7; 32]),
+        funder: Pubkey::new_from_array([8; 32]),
+        amount: 9,
+    };
+
+    assert_eq!(event.try_to_vec().unwrap(), golden);
+    assert_eq!(StreamEvent::try_from_slice(&golden).unwrap(), event);
+}
+
+#[test]
+fn magic_bytes() {
+    let metadata = TokenStreamData {
+        magic: STRM_MAGIC,
+        ..golden_metadata()
+    };
+
+    assert_eq!(metadata.try_to_vec().unwrap()[..8], STRM_MAGIC_BYTES);
+    assert_eq!(&STRM_MAGIC_BYTES, b"WOLFMRTS");
+    assert_ne!(STRM_MAGIC_BYTES, PROGRAM_VERSION.to_le_bytes());
+    assert_ne!(STRM_MAGIC_BYTES, LEGACY_VERSION.to_le_bytes());
+}
+
+#[test]
+fn layout_sizes() {
+    let mut metadata = golden_metadata();
+    let name_len = metadata.ix.stream_name.len();
+
+    assert_eq!(
+        metadata.ix.try_to_vec().unwrap().len(),
+        STREAM_INSTRUCTION_MIN_SIZE + name_len
+    );
+    assert_eq!(
+        metadata.try_to_vec().unwrap().len(),
+        METADATA_MIN_SIZE + name_len + EXTENSION_SIZE
+    );
+    assert_eq!(metadata.ext.try_to_vec().unwrap().len(), EXTENSION_SIZE);
+
+    metadata.ix.stream_name.clear();
+    metadata.ext = StreamExtension::default();
+    assert_eq!(
+        metadata.ix.try_to_vec().unwrap().len(),
+        STREAM_INSTRUCTION_MIN_SIZE
+    );
+    assert_eq!(metadata.try_to_vec().unwrap().len(), METADATA_MIN_SIZE);
+    assert_eq!(metadata.state().try_to_vec().unwrap().len(), STATE_SIZE);
+}
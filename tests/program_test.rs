@@ -3,8 +3,11 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::program_error::ProgramError;
 use solana_program_test::{processor, tokio};
 use solana_sdk::{
+    account::Account,
     clock::UnixTimestamp,
     instruction::{AccountMeta, Instruction},
+    native_token::sol_to_lamports,
+    program_option::COption,
     program_pack::Pack,
     pubkey::Pubkey,
     signature::Signer,
@@ -13,16 +16,26 @@ use solana_sdk::{
     sysvar::rent,
 };
 use spl_associated_token_account::get_associated_token_address;
+use spl_token::state::AccountState;
 use test_sdk::{tools::clone_keypair, ProgramTestBench, TestBenchProgram};
 
 use streamflow_timelock::entrypoint::process_instruction;
-use streamflow_timelock::state::{StreamInstruction, TokenStreamData, PROGRAM_VERSION};
-
-#[derive(BorshSerialize, BorshDeserialize, Clone)]
-struct CreateStreamIx {
-    ix: u8,
-    metadata: StreamInstruction,
-}
+use streamflow_timelock::error::StreamFlowError;
+use streamflow_timelock::instructions::{
+    append_memo, cancel_and_close_stream_ix, cancel_stream_ix, clear_labels_ix, collect_stream_ix,
+    create_lock_ix, create_stream_ix, create_stream_with_options_ix, extend_stream_ix,
+    set_labels_ix, split_stream_ix, topup_stream_ix, update_permissions_ix, withdraw_stream_ix,
+    CancelStreamKeys, CreateStreamKeys, SplitStreamKeys, WithdrawStreamKeys,
+};
+use streamflow_timelock::state::{
+    MintStats, StreamCounter, StreamInstruction, StreamLabels, StreamOptions, StreamPermissions,
+    StreamRegistry, TokenStreamData, LABELS_SIZE, MAX_DURATION, MEMO_MAX_SIZE, PROGRAM_VERSION,
+    STRM_MAGIC,
+};
+use streamflow_timelock::utils::{
+    find_labels_account, find_mint_stats_account, find_registry_account,
+    find_sender_counter_account,
+};
 
 #[derive(BorshSerialize, BorshDeserialize, Clone)]
 struct WithdrawStreamIx {
@@ -53,15 +66,17 @@ pub struct TimelockProgramTest {
 
 impl TimelockProgramTest {
     pub async fn start_new() -> Self {
-        let program_id = Keypair::new().pubkey();
+        Self::start_with_accounts(Keypair::new().pubkey(), vec![]).await
+    }
 
+    pub async fn start_with_accounts(program_id: Pubkey, accounts: Vec<(Pubkey, Account)>) -> Self {
         let program = TestBenchProgram {
             program_name: "streamflow_timelock",
             program_id,
             process_instruction: processor!(process_instruction),
         };
 
-        let bench = ProgramTestBench::start_new(&[program]).await;
+        let bench = ProgramTestBench::start_with_accounts(&[program], accounts).await;
 
         Self { bench, program_id }
     }
@@ -129,43 +144,38 @@ async fn timelock_program_test() -> Result<()> {
     let clock = tt.bench.get_clock().await;
     let now = clock.unix_timestamp as u64;
 
-    let create_stream_ix = CreateStreamIx {
-        ix: 0,
-        metadata: StreamInstruction {
-            start_time: now + 5,
-            end_time: now + 605,
-            deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
-            total_amount: spl_token::ui_amount_to_amount(20.0, 8),
-            period: 1,
-            cliff: 0,
-            cliff_amount: 0,
-            cancelable_by_sender: false,
-            cancelable_by_recipient: false,
-            withdrawal_public: false,
-            transferable_by_sender: false,
-            transferable_by_recipient: false,
-            release_rate: 0,
-            stream_name: "TheTestoooooooooor".to_string(),
-        },
+    let stream_params = StreamInstruction {
+        start_time: now + 5,
+        end_time: now + 605,
+        deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0,
+        stream_name: "TheTestoooooooooor".to_string(),
     };
 
-    let create_stream_ix_bytes = Instruction::new_with_bytes(
-        tt.program_id,
-        &create_stream_ix.try_to_vec()?,
-        vec![
-            AccountMeta::new(alice.pubkey(), true),
-            AccountMeta::new(alice_ass_token, false),
-            AccountMeta::new(bob.pubkey(), false),
-            AccountMeta::new(bob_ass_token, false),
-            AccountMeta::new(metadata_kp.pubkey(), true),
-            AccountMeta::new(escrow_tokens_pubkey, false),
-            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
-            AccountMeta::new_readonly(rent::id(), false),
-            AccountMeta::new_readonly(spl_token::id(), false),
-            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
-            AccountMeta::new_readonly(system_program::id(), false),
-        ],
-    );
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
 
     tt.bench
         .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
@@ -175,7 +185,7 @@ async fn timelock_program_test() -> Result<()> {
     let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
 
     assert_eq!(metadata_acc.owner, tt.program_id);
-    assert_eq!(metadata_data.magic, PROGRAM_VERSION);
+    assert_eq!(metadata_data.magic, STRM_MAGIC);
     assert_eq!(metadata_data.withdrawn_amount, 0);
     assert_eq!(metadata_data.canceled_at, 0);
     assert_eq!(metadata_data.closable_at, now + 605);
@@ -277,43 +287,38 @@ async fn timelock_program_test2() -> Result<()> {
     let clock = tt.bench.get_clock().await;
     let now = clock.unix_timestamp as u64;
 
-    let create_stream_ix = CreateStreamIx {
-        ix: 0,
-        metadata: StreamInstruction {
-            start_time: now + 10,
-            end_time: now + 1010,
-            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
-            total_amount: spl_token::ui_amount_to_amount(20.0, 8),
-            period: 1,
-            cliff: 0,
-            cliff_amount: 0,
-            cancelable_by_sender: false,
-            cancelable_by_recipient: false,
-            withdrawal_public: false,
-            transferable_by_sender: false,
-            transferable_by_recipient: false,
-            release_rate: 0, // Old contracts don't have it
-            stream_name: "Test2".to_string(),
-        },
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0, // Old contracts don't have it
+        stream_name: "Test2".to_string(),
     };
 
-    let create_stream_ix_bytes = Instruction::new_with_bytes(
-        tt.program_id,
-        &create_stream_ix.try_to_vec()?,
-        vec![
-            AccountMeta::new(alice.pubkey(), true),
-            AccountMeta::new(alice_ass_token, false),
-            AccountMeta::new(bob.pubkey(), false),
-            AccountMeta::new(bob_ass_token, false),
-            AccountMeta::new(metadata_kp.pubkey(), true),
-            AccountMeta::new(escrow_tokens_pubkey, false),
-            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
-            AccountMeta::new_readonly(rent::id(), false),
-            AccountMeta::new_readonly(spl_token::id(), false),
-            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
-            AccountMeta::new_readonly(system_program::id(), false),
-        ],
-    );
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
 
     tt.bench
         .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
@@ -525,43 +530,38 @@ async fn timelock_program_test_transfer() -> Result<()> {
     let clock = tt.bench.get_clock().await;
     let now = clock.unix_timestamp as u64;
 
-    let create_stream_ix = CreateStreamIx {
-        ix: 0,
-        metadata: StreamInstruction {
-            start_time: now + 10,
-            end_time: now + 1010,
-            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
-            total_amount: spl_token::ui_amount_to_amount(20.0, 8),
-            period: 1,
-            cliff: 0,
-            cliff_amount: 0,
-            cancelable_by_sender: false,
-            cancelable_by_recipient: false,
-            withdrawal_public: false,
-            transferable_by_sender: false,
-            transferable_by_recipient: true, // Should be possible to transfer stream
-            release_rate: 0,                 // Old contracts don't have it
-            stream_name: "TransferStream".to_string(),
-        },
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: true, // Should be possible to transfer stream
+        release_rate: 0,                 // Old contracts don't have it
+        stream_name: "TransferStream".to_string(),
     };
 
-    let create_stream_ix_bytes = Instruction::new_with_bytes(
-        tt.program_id,
-        &create_stream_ix.try_to_vec()?,
-        vec![
-            AccountMeta::new(alice.pubkey(), true),
-            AccountMeta::new(alice_ass_token, false),
-            AccountMeta::new(bob.pubkey(), false),
-            AccountMeta::new(bob_ass_token, false),
-            AccountMeta::new(metadata_kp.pubkey(), true),
-            AccountMeta::new(escrow_tokens_pubkey, false),
-            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
-            AccountMeta::new_readonly(rent::id(), false),
-            AccountMeta::new_readonly(spl_token::id(), false),
-            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
-            AccountMeta::new_readonly(system_program::id(), false),
-        ],
-    );
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
 
     tt.bench
         .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
@@ -647,43 +647,38 @@ async fn timelock_program_test_recurring() -> Result<()> {
     let clock = tt.bench.get_clock().await;
     let now = clock.unix_timestamp as u64;
 
-    let create_stream_ix = CreateStreamIx {
-        ix: 0,
-        metadata: StreamInstruction {
-            start_time: now + 10,
-            end_time: now + 1010,
-            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
-            total_amount: spl_token::ui_amount_to_amount(20.0, 8),
-            period: 200,
-            cliff: 0,
-            cliff_amount: 0,
-            cancelable_by_sender: false,
-            cancelable_by_recipient: false,
-            withdrawal_public: false,
-            transferable_by_sender: false,
-            transferable_by_recipient: false,
-            release_rate: spl_token::ui_amount_to_amount(1.0, 8),
-            stream_name: "Recurring".to_string(),
-        },
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        period: 200,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: spl_token::ui_amount_to_amount(1.0, 8),
+        stream_name: "Recurring".to_string(),
     };
 
-    let create_stream_ix_bytes = Instruction::new_with_bytes(
-        tt.program_id,
-        &create_stream_ix.try_to_vec()?,
-        vec![
-            AccountMeta::new(alice.pubkey(), true),
-            AccountMeta::new(alice_ass_token, false),
-            AccountMeta::new(bob.pubkey(), false),
-            AccountMeta::new(bob_ass_token, false),
-            AccountMeta::new(metadata_kp.pubkey(), true),
-            AccountMeta::new(escrow_tokens_pubkey, false),
-            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
-            AccountMeta::new_readonly(rent::id(), false),
-            AccountMeta::new_readonly(spl_token::id(), false),
-            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
-            AccountMeta::new_readonly(system_program::id(), false),
-        ],
-    );
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
 
     tt.bench
         .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
@@ -827,7 +822,11 @@ async fn timelock_program_test_recurring() -> Result<()> {
         metadata_data.withdrawn_amount,
         spl_token::ui_amount_to_amount(25.0, 8)
     );
-    assert_eq!(metadata_data.last_withdrawn_at, new_now);
+    // Period aligned, 200 seconds each, counted from start
+    assert_eq!(
+        metadata_data.last_withdrawn_at,
+        now + 10 + (new_now - now - 10) / 200 * 200
+    );
 
     // Try to topup, stream expired, shouldn't succeed
     let topup_ix = TopUpIx {
@@ -847,7 +846,8 @@ async fn timelock_program_test_recurring() -> Result<()> {
         ],
     );
 
-    let transaction_error = tt.bench
+    let transaction_error = tt
+        .bench
         .process_transaction(&[topupix_bytes], Some(&[&alice]))
         .await;
     // Stream closed, no topup
@@ -875,3 +875,3951 @@ async fn timelock_program_test_recurring() -> Result<()> {
         .await?;
     Ok(())
 }
+
+#[tokio::test]
+async fn timelock_program_test_zero_recipient() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+    let zero_recipient = Pubkey::default();
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let zero_ass_token = get_associated_token_address(&zero_recipient, &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 5,
+        end_time: now + 605,
+        deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        stream_name: "ZeroRecipient".to_string(),
+        ..Default::default()
+    };
+
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: zero_recipient,
+            recipient_tokens: zero_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidAccount as u32)
+    );
+    assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_lock() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata_kp.pubkey().as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let keys = CreateStreamKeys {
+        sender: alice.pubkey(),
+        sender_tokens: alice_ass_token,
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        metadata: metadata_kp.pubkey(),
+        mint: strm_token_mint.pubkey(),
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+    let amount = spl_token::ui_amount_to_amount(20.0, 8);
+
+    // A lock has to be fully funded up front
+    let underfunded = StreamInstruction {
+        deposited_amount: amount / 2,
+        ..StreamInstruction::lock(now + 5, now + 605, amount)
+    };
+    let ix =
+        create_stream_with_options_ix(&tt.program_id, &underfunded, &StreamOptions::lock(), &keys)?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::LockUnderfunded as u32)
+    );
+
+    let create_stream_ix_bytes = create_lock_ix(&tt.program_id, now + 5, now + 605, amount, &keys)?;
+
+    tt.bench
+        .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    assert!(metadata_data.is_lock());
+    assert_eq!(metadata_data.closable_at, now + 605);
+
+    // Past start, but still before the unlock
+    tt.advance_clock_past_timestamp(now as i64 + 300).await;
+
+    let withdraw_stream_ix = WithdrawStreamIx { ix: 1, amount: 0 };
+    let withdraw_stream_ix_bytes = Instruction::new_with_bytes(
+        tt.program_id,
+        &withdraw_stream_ix.try_to_vec()?,
+        vec![
+            AccountMeta::new(bob.pubkey(), true),
+            AccountMeta::new(alice.pubkey(), false),
+            AccountMeta::new(bob.pubkey(), false),
+            AccountMeta::new(bob_ass_token, false),
+            AccountMeta::new(metadata_kp.pubkey(), false),
+            AccountMeta::new(escrow_tokens_pubkey, false),
+            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::StreamNotStarted as u32)
+    );
+
+    // Cancel before the unlock, everything goes back to the sender
+    let cancel_ix = CancelIx { ix: 2 };
+    let cancel_ix_bytes = Instruction::new_with_bytes(
+        tt.program_id,
+        &cancel_ix.try_to_vec()?,
+        vec![
+            AccountMeta::new(alice.pubkey(), true),
+            AccountMeta::new(alice.pubkey(), false),
+            AccountMeta::new(alice_ass_token, false),
+            AccountMeta::new(bob.pubkey(), false),
+            AccountMeta::new(bob_ass_token, false),
+            AccountMeta::new(metadata_kp.pubkey(), false),
+            AccountMeta::new(escrow_tokens_pubkey, false),
+            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    );
+
+    tt.bench
+        .process_transaction(&[cancel_ix_bytes], Some(&[&alice]))
+        .await?;
+
+    let alice_ass_account = tt.bench.get_account(&alice_ass_token).await.unwrap();
+    let alice_token_data = spl_token::state::Account::unpack_from_slice(&alice_ass_account.data)?;
+    assert_eq!(
+        alice_token_data.amount,
+        spl_token::ui_amount_to_amount(100.0, 8)
+    );
+
+    let bob_ass_account = tt.bench.get_account(&bob_ass_token).await.unwrap();
+    let bob_token_data = spl_token::state::Account::unpack_from_slice(&bob_ass_account.data)?;
+    assert_eq!(bob_token_data.amount, 0);
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.withdrawn_amount, 0);
+    assert!(metadata_data.canceled_at > 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_withdraw_to_vault() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    // Some other program's vault, owned by its PDA which can't sign here
+    let (vault_authority, _) = Pubkey::find_program_address(&[b"vault"], &Keypair::new().pubkey());
+    let vault_kp = Keypair::new();
+    tt.bench
+        .create_empty_token_account(&vault_kp, &strm_token_mint.pubkey(), &vault_authority)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata_kp.pubkey().as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 5,
+        end_time: now + 605,
+        deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        period: 1,
+        stream_name: "TheVaultTest".to_string(),
+        ..Default::default()
+    };
+
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+
+    tt.bench
+        .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    tt.advance_clock_past_timestamp(now as i64 + 300).await;
+
+    // Bob signs, the vault owner doesn't
+    let withdraw_stream_ix = WithdrawStreamIx {
+        ix: 1,
+        amount: spl_token::ui_amount_to_amount(5.0, 8),
+    };
+    let withdraw_stream_ix_bytes = Instruction::new_with_bytes(
+        tt.program_id,
+        &withdraw_stream_ix.try_to_vec()?,
+        vec![
+            AccountMeta::new(bob.pubkey(), true),
+            AccountMeta::new(alice.pubkey(), false),
+            AccountMeta::new(bob.pubkey(), false),
+            AccountMeta::new(vault_kp.pubkey(), false),
+            AccountMeta::new(metadata_kp.pubkey(), false),
+            AccountMeta::new(escrow_tokens_pubkey, false),
+            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+        ],
+    );
+
+    tt.bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
+        .await?;
+
+    let vault_account = tt.bench.get_account(&vault_kp.pubkey()).await.unwrap();
+    let vault_token_data = spl_token::state::Account::unpack_from_slice(&vault_account.data)?;
+    assert_eq!(
+        vault_token_data.amount,
+        spl_token::ui_amount_to_amount(5.0, 8)
+    );
+    assert_eq!(vault_token_data.owner, vault_authority);
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    assert_eq!(
+        metadata_data.withdrawn_amount,
+        spl_token::ui_amount_to_amount(5.0, 8)
+    );
+    // Stored destination stays the recipient's own account
+    assert_eq!(metadata_data.recipient_tokens, bob_ass_token);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_invalid_period() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+    let bob = clone_keypair(&tt.bench.bob);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    // Zero would divide by zero, MAX would overflow the schedule math
+    for period in [0, u64::MAX] {
+        let metadata_kp = Keypair::new();
+
+        let stream_params = StreamInstruction {
+            start_time: now + 5,
+            end_time: now + 605,
+            deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+            period,
+            stream_name: "InvalidPeriod".to_string(),
+            ..Default::default()
+        };
+
+        let create_stream_ix_bytes = create_stream_ix(
+            &tt.program_id,
+            &stream_params,
+            &CreateStreamKeys {
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata: metadata_kp.pubkey(),
+                mint: strm_token_mint.pubkey(),
+                registry: None,
+                sender_counter: None,
+                mint_stats: None,
+            },
+        )?;
+
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::InvalidPeriod as u32)
+        );
+        assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_zero_deposit() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+    let bob = clone_keypair(&tt.bench.bob);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    // A client that forgot to set the amount, with or without the total
+    for total_amount in [0, spl_token::ui_amount_to_amount(20.0, 8)] {
+        let metadata_kp = Keypair::new();
+
+        let stream_params = StreamInstruction {
+            start_time: now + 5,
+            end_time: now + 605,
+            deposited_amount: 0,
+            total_amount,
+            period: 1,
+            stream_name: "ZeroDeposit".to_string(),
+            ..Default::default()
+        };
+
+        let create_stream_ix_bytes = create_stream_ix(
+            &tt.program_id,
+            &stream_params,
+            &CreateStreamKeys {
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata: metadata_kp.pubkey(),
+                mint: strm_token_mint.pubkey(),
+                registry: None,
+                sender_counter: None,
+                mint_stats: None,
+            },
+        )?;
+
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::ZeroDeposit as u32)
+        );
+        assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_registry() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+    let (registry_pubkey, _) = find_registry_account(&bob.pubkey(), &tt.program_id);
+    let (counter_pubkey, _) = find_sender_counter_account(&alice.pubkey(), &tt.program_id);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let mut streams = vec![];
+    for _ in 0..3 {
+        let metadata_kp = Keypair::new();
+        let (escrow_tokens_pubkey, _) =
+            Pubkey::find_program_address(&[metadata_kp.pubkey().as_ref()], &tt.program_id);
+
+        let stream_params = StreamInstruction {
+            start_time: now + 5,
+            end_time: now + 605,
+            deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+            stream_name: "Indexed".to_string(),
+            ..Default::default()
+        };
+
+        let create_stream_ix_bytes = create_stream_ix(
+            &tt.program_id,
+            &stream_params,
+            &CreateStreamKeys {
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata: metadata_kp.pubkey(),
+                mint: strm_token_mint.pubkey(),
+                registry: Some(registry_pubkey),
+                sender_counter: Some(counter_pubkey),
+                mint_stats: None,
+            },
+        )?;
+
+        tt.bench
+            .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+            .await?;
+
+        streams.push((metadata_kp.pubkey(), escrow_tokens_pubkey));
+    }
+
+    let registry_acc = tt.bench.get_account(&registry_pubkey).await.unwrap();
+    let registry = StreamRegistry::unpack(&registry_acc.data)?;
+    assert_eq!(registry_acc.owner, tt.program_id);
+    assert_eq!(
+        registry.streams,
+        streams.iter().map(|(m, _)| *m).collect::<Vec<Pubkey>>()
+    );
+
+    let counter_acc = tt.bench.get_account(&counter_pubkey).await.unwrap();
+    assert_eq!(StreamCounter::unpack(&counter_acc.data)?.count, 3);
+
+    // Cancel the second stream, it gets pruned from the registry and uncounted
+    let (metadata_pubkey, escrow_tokens_pubkey) = streams[1];
+    let cancel_ix = CancelIx { ix: 2 };
+    let cancel_ix_bytes = Instruction::new_with_bytes(
+        tt.program_id,
+        &cancel_ix.try_to_vec()?,
+        vec![
+            AccountMeta::new(alice.pubkey(), true),
+            AccountMeta::new(alice.pubkey(), false),
+            AccountMeta::new(alice_ass_token, false),
+            AccountMeta::new(bob.pubkey(), false),
+            AccountMeta::new(bob_ass_token, false),
+            AccountMeta::new(metadata_pubkey, false),
+            AccountMeta::new(escrow_tokens_pubkey, false),
+            AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(registry_pubkey, false),
+            AccountMeta::new(counter_pubkey, false),
+        ],
+    );
+
+    tt.bench
+        .process_transaction(&[cancel_ix_bytes], Some(&[&alice]))
+        .await?;
+
+    let registry_acc = tt.bench.get_account(&registry_pubkey).await.unwrap();
+    let registry = StreamRegistry::unpack(&registry_acc.data)?;
+    assert_eq!(registry.streams, vec![streams[0].0, streams[2].0]);
+
+    let counter_acc = tt.bench.get_account(&counter_pubkey).await.unwrap();
+    assert_eq!(StreamCounter::unpack(&counter_acc.data)?.count, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_insufficient_tokens() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+    let bob = clone_keypair(&tt.bench.bob);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata_kp.pubkey().as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 5,
+        end_time: now + 605,
+        // Alice only has 100
+        deposited_amount: spl_token::ui_amount_to_amount(100.5, 8),
+        total_amount: spl_token::ui_amount_to_amount(100.5, 8),
+        stream_name: "TooExpensive".to_string(),
+        ..Default::default()
+    };
+
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InsufficientFunds as u32)
+    );
+    assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+    assert!(tt.bench.get_account(&escrow_tokens_pubkey).await.is_none());
+
+    Ok(())
+}
+
+async fn create_transfer_test_stream(
+    tt: &mut TimelockProgramTest,
+    mint: &Pubkey,
+    transferable_by_sender: bool,
+    transferable_by_recipient: bool,
+) -> Result<Pubkey> {
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), mint);
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        transferable_by_sender,
+        transferable_by_recipient,
+        stream_name: "TransferStream".to_string(),
+        ..Default::default()
+    };
+
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: *mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+
+    tt.bench
+        .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    Ok(metadata_kp.pubkey())
+}
+
+fn transfer_test_ix(
+    tt: &TimelockProgramTest,
+    authority: &Pubkey,
+    new_recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+) -> Result<Instruction> {
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+
+    Ok(Instruction::new_with_bytes(
+        tt.program_id,
+        &TransferIx { ix: 3 }.try_to_vec()?,
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(*new_recipient, false),
+            AccountMeta::new(get_associated_token_address(new_recipient, mint), false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new(escrow_tokens_pubkey, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(rent::id(), false),
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    ))
+}
+
+#[tokio::test]
+async fn timelock_program_test_transfer_permissions() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let carol = Keypair::new().pubkey();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    // Only the recipient may transfer
+    let metadata = create_transfer_test_stream(&mut tt, &mint, false, true).await?;
+
+    let ix = transfer_test_ix(&tt, &alice.pubkey(), &carol, &metadata, &mint)?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::TransferNotAllowed as u32)
+    );
+
+    let ix = transfer_test_ix(&tt, &bob.pubkey(), &carol, &metadata, &mint)?;
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.recipient, carol);
+
+    // Only the sender may transfer
+    let metadata = create_transfer_test_stream(&mut tt, &mint, true, false).await?;
+
+    let ix = transfer_test_ix(&tt, &bob.pubkey(), &carol, &metadata, &mint)?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::TransferNotAllowed as u32)
+    );
+
+    let ix = transfer_test_ix(&tt, &alice.pubkey(), &carol, &metadata, &mint)?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.recipient, carol);
+    assert_eq!(
+        metadata_data.recipient_tokens,
+        get_associated_token_address(&carol, &mint)
+    );
+
+    Ok(())
+}
+
+/// Deterministic xorshift PRNG, keeps the fuzz runs reproducible
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+#[tokio::test]
+async fn timelock_program_test_malformed_ix() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let bob = clone_keypair(&tt.bench.bob);
+
+    // Empty, amount too short, amount too long. Accounts are read before
+    // the amount, so pass enough of them for withdraw and topup.
+    for data in [vec![], vec![1, 2, 3], vec![4; 10]] {
+        let ix = Instruction::new_with_bytes(
+            tt.program_id,
+            &data,
+            vec![AccountMeta::new(bob.pubkey(), true); 8],
+        );
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[&bob]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(transaction_error, ProgramError::InvalidInstructionData);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_fuzz() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+    let mut rng = XorShift(0x5eed_f00d_cafe_b0ba);
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata_kp.pubkey().as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 5,
+        end_time: now + 605,
+        deposited_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        transferable_by_sender: true,
+        stream_name: "Fuzzed".to_string(),
+        ..Default::default()
+    };
+
+    let create_stream_ix_bytes = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: strm_token_mint.pubkey(),
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+
+    tt.bench
+        .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    tt.advance_clock_past_timestamp(now as i64 + 300).await;
+
+    // Valid instructions to start mutating from. Cancel is left out, as it
+    // would legitimately close the stream on the first valid attempt.
+    let canonical = vec![
+        (
+            WithdrawStreamIx {
+                ix: 1,
+                amount: spl_token::ui_amount_to_amount(1.0, 8),
+            }
+            .try_to_vec()?,
+            vec![
+                AccountMeta::new(bob.pubkey(), true),
+                AccountMeta::new(alice.pubkey(), false),
+                AccountMeta::new(bob.pubkey(), false),
+                AccountMeta::new(bob_ass_token, false),
+                AccountMeta::new(metadata_kp.pubkey(), false),
+                AccountMeta::new(escrow_tokens_pubkey, false),
+                AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        ),
+        (
+            TopUpIx {
+                ix: 4,
+                amount: spl_token::ui_amount_to_amount(1.0, 8),
+            }
+            .try_to_vec()?,
+            vec![
+                AccountMeta::new(alice.pubkey(), true),
+                AccountMeta::new(alice_ass_token, false),
+                AccountMeta::new(metadata_kp.pubkey(), false),
+                AccountMeta::new(escrow_tokens_pubkey, false),
+                AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+            ],
+        ),
+        (
+            TransferIx { ix: 3 }.try_to_vec()?,
+            vec![
+                AccountMeta::new(alice.pubkey(), true),
+                AccountMeta::new(bob.pubkey(), false),
+                AccountMeta::new(bob_ass_token, false),
+                AccountMeta::new(metadata_kp.pubkey(), false),
+                AccountMeta::new(escrow_tokens_pubkey, false),
+                AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
+                AccountMeta::new_readonly(rent::id(), false),
+                AccountMeta::new_readonly(spl_token::id(), false),
+                AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+                AccountMeta::new_readonly(system_program::id(), false),
+            ],
+        ),
+    ];
+
+    for i in 0..500 {
+        let (data, accounts) = &canonical[rng.below(canonical.len())];
+
+        let mut data = data.clone();
+        match rng.below(4) {
+            0 => {}
+            1 => {
+                for _ in 0..=rng.below(4) {
+                    let at = rng.below(data.len());
+                    data[at] = rng.next() as u8;
+                }
+            }
+            2 => data.truncate(rng.below(data.len() + 1)),
+            _ => {
+                data = (0..rng.below(48)).map(|_| rng.next() as u8).collect();
+            }
+        }
+
+        let mut accounts = accounts.clone();
+        match rng.below(3) {
+            0 => {}
+            1 => {
+                for at in (1..accounts.len()).rev() {
+                    accounts.swap(at, rng.below(at + 1));
+                }
+            }
+            _ => accounts.retain(|_| rng.below(2) == 0),
+        }
+
+        let signers: Vec<&Keypair> = [&alice, &bob]
+            .iter()
+            .filter(|kp| {
+                accounts
+                    .iter()
+                    .any(|m| m.is_signer && m.pubkey == kp.pubkey())
+            })
+            .copied()
+            .collect();
+
+        // Only clean failures or legitimate successes are acceptable,
+        // a panic in the processor fails the test.
+        let ix = Instruction::new_with_bytes(tt.program_id, &data, accounts);
+        let _ = tt.bench.process_transaction(&[ix], Some(&signers)).await;
+
+        if i % 50 == 49 {
+            let metadata: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+            assert_eq!(metadata.check_invariants(), Ok(()));
+
+            if let Some(escrow) = tt.bench.get_account(&escrow_tokens_pubkey).await {
+                let escrow_data = spl_token::state::Account::unpack_from_slice(&escrow.data)?;
+                assert_eq!(
+                    escrow_data.amount,
+                    metadata.ix.deposited_amount - metadata.withdrawn_amount
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_labels() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata = create_transfer_test_stream(&mut tt, &mint, false, false).await?;
+    let (labels_pubkey, _) = find_labels_account(&metadata, &tt.program_id);
+
+    let mut labels = StreamLabels {
+        keys: vec!["invoice".to_string(), "department".to_string()],
+        values: vec!["INV-0042".to_string(), "Engineering".to_string()],
+    };
+
+    // Created on first set
+    let ix = set_labels_ix(&tt.program_id, &labels, &alice.pubkey(), &metadata)?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let labels_acc = tt.bench.get_account(&labels_pubkey).await.unwrap();
+    assert_eq!(labels_acc.owner, tt.program_id);
+    assert_eq!(labels_acc.data.len(), LABELS_SIZE);
+    assert_eq!(StreamLabels::unpack(&labels_acc.data)?, labels);
+
+    // Replaced on update
+    labels.keys.push("po".to_string());
+    labels.values.push("PO-7".to_string());
+    let ix = set_labels_ix(&tt.program_id, &labels, &alice.pubkey(), &metadata)?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let labels_acc = tt.bench.get_account(&labels_pubkey).await.unwrap();
+    assert_eq!(StreamLabels::unpack(&labels_acc.data)?, labels);
+
+    // Too many pairs
+    let overflow = StreamLabels {
+        keys: vec!["k".to_string(); 9],
+        values: vec!["v".to_string(); 9],
+    };
+    let ix = set_labels_ix(&tt.program_id, &overflow, &alice.pubkey(), &metadata)?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidLabels as u32)
+    );
+
+    // Only the sender can label
+    let ix = set_labels_ix(&tt.program_id, &labels, &bob.pubkey(), &metadata)?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    // Clearing returns the rent to the sender
+    let rent = labels_acc.lamports;
+    let alice_acc = tt.bench.get_account(&alice.pubkey()).await.unwrap();
+
+    let ix = clear_labels_ix(&tt.program_id, &alice.pubkey(), &metadata);
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    assert!(tt.bench.get_account(&labels_pubkey).await.is_none());
+    let alice_acc_after = tt.bench.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_acc_after.lamports, alice_acc.lamports + rent);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_lifecycle() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    // Not fully funded, 100 at the cliff and the rest over 8 periods
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 900,
+        total_amount: 1000,
+        period: 100,
+        cliff: now + 210,
+        cliff_amount: 100,
+        stream_name: "Lifecycle".to_string(),
+        ..Default::default()
+    };
+
+    let ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 1100);
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 0);
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 900);
+
+    let withdraw_keys = WithdrawStreamKeys {
+        withdraw_authority: bob.pubkey(),
+        sender: alice.pubkey(),
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    // Past the cliff, the cliff amount at least is unlocked
+    tt.advance_clock_past_timestamp(stream_params.cliff as i64)
+        .await;
+
+    let ix = withdraw_stream_ix(&tt.program_id, 60, &withdraw_keys);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.withdrawn_amount, 60);
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 60);
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 840);
+
+    // Fund the rest of the stream
+    let ix = topup_stream_ix(
+        &tt.program_id,
+        100,
+        &alice.pubkey(),
+        &alice_ass_token,
+        &metadata,
+        &mint,
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.ix.deposited_amount, 1000);
+    assert_eq!(metadata_data.closable_at, stream_params.end_time);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 1000);
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 940);
+
+    // Past the end everything is unlocked, withdraw it all
+    tt.advance_clock_past_timestamp(stream_params.end_time as i64)
+        .await;
+
+    let escrow_rent = tt
+        .bench
+        .get_account(&escrow_tokens_pubkey)
+        .await
+        .unwrap()
+        .lamports;
+    let alice_acc = tt.bench.get_account(&alice.pubkey()).await.unwrap();
+
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &withdraw_keys);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.withdrawn_amount, 1000);
+    assert_eq!(metadata_data.available(metadata_data.closable_at), 0);
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 1000);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 1000);
+
+    // The escrow is closed and its rent returned to the sender
+    assert!(tt.bench.get_account(&escrow_tokens_pubkey).await.is_none());
+    let alice_acc_after = tt.bench.get_account(&alice.pubkey()).await.unwrap();
+    assert_eq!(alice_acc_after.lamports, alice_acc.lamports + escrow_rent);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_withdrawable_after() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    // Vests from the start, but can only be claimed from halfway through
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "Lockup".to_string(),
+        ..Default::default()
+    };
+    let options = StreamOptions {
+        withdrawable_after: now + 510,
+        ..Default::default()
+    };
+
+    let ix = create_stream_with_options_ix(
+        &tt.program_id,
+        &stream_params,
+        &options,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.version(), PROGRAM_VERSION);
+    assert_eq!(metadata_data.ext.options, options);
+
+    let withdraw_keys = WithdrawStreamKeys {
+        withdraw_authority: bob.pubkey(),
+        sender: alice.pubkey(),
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    // Some of it has vested, but it's still locked up
+    tt.advance_clock_past_timestamp(now as i64 + 310).await;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    let clock = tt.bench.get_clock().await;
+    assert!(metadata_data.available(clock.unix_timestamp as u64) > 0);
+
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &withdraw_keys);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::WithdrawLocked as u32)
+    );
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 0);
+
+    // Once the lockup lifts, everything vested so far can be withdrawn
+    tt.advance_clock_past_timestamp(options.withdrawable_after as i64)
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let vested = metadata_data.available(clock.unix_timestamp as u64);
+    assert!(vested >= 500);
+
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &withdraw_keys);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.withdrawn_amount, vested);
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, vested);
+
+    Ok(())
+}
+
+/// Create a 1000 token stream from alice to bob with `options`, vesting a
+/// tenth every 100 seconds from 10 seconds from now.
+async fn create_options_test_stream(
+    tt: &mut TimelockProgramTest,
+    mint: &Pubkey,
+    options: &StreamOptions,
+) -> Result<Pubkey> {
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), mint);
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "OptionsStream".to_string(),
+        ..Default::default()
+    };
+
+    let ix = create_stream_with_options_ix(
+        &tt.program_id,
+        &stream_params,
+        options,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint: *mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    Ok(metadata_kp.pubkey())
+}
+
+#[tokio::test]
+async fn timelock_program_test_mint_decimals() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    for (decimals, deposited) in [(0, "1000"), (6, "0.001"), (9, "0.000001")] {
+        let strm_token_mint = Keypair::new();
+        let mint = strm_token_mint.pubkey();
+        let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+        tt.bench
+            .create_mint_with_decimals(&strm_token_mint, &tt.bench.payer.pubkey(), decimals)
+            .await;
+
+        tt.bench
+            .create_associated_token_account(&mint, &alice.pubkey())
+            .await;
+
+        tt.bench
+            .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+            .await;
+
+        let metadata =
+            create_options_test_stream(&mut tt, &mint, &StreamOptions::default()).await?;
+
+        // Renderable without fetching the mint
+        let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+        assert_eq!(metadata_data.ext.mint_decimals, decimals);
+        assert_eq!(
+            metadata_data.ui_amount_str(metadata_data.ix.deposited_amount),
+            deposited
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_external_topup() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+    let carol = Keypair::new();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let carol_ass_token = get_associated_token_address(&carol.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+    tt.bench
+        .create_associated_token_account(&mint, &carol.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2100)
+        .await;
+    tt.bench
+        .mint_tokens(&mint, &payer, &carol_ass_token, 500)
+        .await;
+
+    let open = create_options_test_stream(
+        &mut tt,
+        &mint,
+        &StreamOptions {
+            allow_external_topup: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let closed = create_options_test_stream(&mut tt, &mint, &StreamOptions::default()).await?;
+
+    // Only the sender tops up a stream that doesn't allow others to
+    let ix = topup_stream_ix(
+        &tt.program_id,
+        100,
+        &carol.pubkey(),
+        &carol_ass_token,
+        &closed,
+        &mint,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&carol]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ExternalTopupNotAllowed as u32)
+    );
+
+    let ix = topup_stream_ix(
+        &tt.program_id,
+        100,
+        &alice.pubkey(),
+        &alice_ass_token,
+        &closed,
+        &mint,
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&closed).await;
+    assert_eq!(metadata_data.ix.deposited_amount, 1100);
+    assert_eq!(metadata_data.ext.last_topup_by, alice.pubkey());
+
+    // Anyone funds one that does, from their own tokens
+    let ix = topup_stream_ix(
+        &tt.program_id,
+        100,
+        &carol.pubkey(),
+        &carol_ass_token,
+        &open,
+        &mint,
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&carol])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&open).await;
+    assert_eq!(metadata_data.ix.deposited_amount, 1100);
+    assert_eq!(metadata_data.ext.last_topup_by, carol.pubkey());
+    assert_eq!(token_balance(&mut tt, &carol_ass_token).await?, 400);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_sender_push() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2000)
+        .await;
+
+    let pushable = create_options_test_stream(
+        &mut tt,
+        &mint,
+        &StreamOptions {
+            sender_can_push: true,
+            ..Default::default()
+        },
+    )
+    .await?;
+    let not_pushable =
+        create_options_test_stream(&mut tt, &mint, &StreamOptions::default()).await?;
+
+    let push_keys = |metadata: Pubkey, recipient_tokens: Pubkey| WithdrawStreamKeys {
+        withdraw_authority: alice.pubkey(),
+        sender: alice.pubkey(),
+        recipient: bob.pubkey(),
+        recipient_tokens,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&pushable).await;
+    tt.advance_clock_past_timestamp(metadata_data.ix.start_time as i64 + 300)
+        .await;
+
+    // Without the flag, the sender can't withdraw
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &push_keys(not_pushable, bob_ass_token));
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    // With it, they can't redirect the funds either
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &push_keys(pushable, alice_ass_token));
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    // But can push what's available to the recipient
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &push_keys(pushable, bob_ass_token));
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&pushable).await;
+    let pushed = metadata_data.withdrawn_amount;
+    assert!(pushed >= 300);
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, pushed);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 0);
+
+    // What was pushed is no longer available to the recipient
+    let clock = tt.bench.get_clock().await;
+    assert!(metadata_data.available(clock.unix_timestamp as u64) < pushed);
+
+    let bob_keys = WithdrawStreamKeys {
+        withdraw_authority: bob.pubkey(),
+        ..push_keys(pushable, bob_ass_token)
+    };
+    let ix = withdraw_stream_ix(&tt.program_id, pushed, &bob_keys);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidArgument);
+
+    tt.advance_clock_past_timestamp(metadata_data.ix.end_time as i64)
+        .await;
+
+    let ix = withdraw_stream_ix(&tt.program_id, 0, &bob_keys);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 1000);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_strict_ata() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let other_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+    tt.bench
+        .create_mint(&other_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2000)
+        .await;
+
+    // Some other program's PDA, with a vault that isn't its associated account
+    let (recipient, _) = Pubkey::find_program_address(&[b"vault"], &Keypair::new().pubkey());
+    let recipient_ata = get_associated_token_address(&recipient, &mint);
+    let vault_kp = Keypair::new();
+    tt.bench
+        .create_empty_token_account(&vault_kp, &mint, &recipient)
+        .await;
+    let other_vault_kp = Keypair::new();
+    tt.bench
+        .create_empty_token_account(&other_vault_kp, &other_mint.pubkey(), &recipient)
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 500,
+        total_amount: 500,
+        period: 100,
+        stream_name: "ToPda".to_string(),
+        ..Default::default()
+    };
+
+    let create_ix = |strict_ata: bool, recipient_tokens: Pubkey, metadata: Pubkey| {
+        create_stream_with_options_ix(
+            &tt.program_id,
+            &stream_params,
+            &StreamOptions {
+                strict_ata,
+                ..Default::default()
+            },
+            &CreateStreamKeys {
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient,
+                recipient_tokens,
+                metadata,
+                mint,
+                registry: None,
+                sender_counter: None,
+                mint_stats: None,
+            },
+        )
+    };
+
+    // Strict, only the associated token account goes
+    let metadata_kp = Keypair::new();
+    let ix = create_ix(true, vault_kp.pubkey(), metadata_kp.pubkey())?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    let ix = create_ix(true, recipient_ata, metadata_kp.pubkey())?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.recipient_tokens, recipient_ata);
+    assert!(metadata_data.ext.options.strict_ata);
+    assert_eq!(token_balance(&mut tt, &recipient_ata).await?, 0);
+
+    // Relaxed, any token account of the mint goes
+    let metadata_kp = Keypair::new();
+    let ix = create_ix(false, other_vault_kp.pubkey(), metadata_kp.pubkey())?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::MintMismatch as u32)
+    );
+
+    let ix = create_ix(false, vault_kp.pubkey(), metadata_kp.pubkey())?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.recipient_tokens, vault_kp.pubkey());
+    assert!(!metadata_data.ext.options.strict_ata);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 1000);
+
+    Ok(())
+}
+
+async fn token_balance(tt: &mut TimelockProgramTest, token_account: &Pubkey) -> Result<u64> {
+    let account = tt.bench.get_account(token_account).await.unwrap();
+    Ok(spl_token::state::Account::unpack_from_slice(&account.data)?.amount)
+}
+
+#[tokio::test]
+async fn timelock_program_test_update_permissions() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata = create_transfer_test_stream(&mut tt, &mint, false, false).await?;
+    let stream: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    let mut permissions = stream.ix.permissions();
+
+    // Turn each flag on and off again, signed by the party it works against
+    for flag in 0..8 {
+        let (senders_right, on) = match flag % 4 {
+            0 => {
+                permissions.cancelable_by_sender ^= true;
+                (true, permissions.cancelable_by_sender)
+            }
+            1 => {
+                permissions.cancelable_by_recipient ^= true;
+                (false, permissions.cancelable_by_recipient)
+            }
+            2 => {
+                permissions.transferable_by_sender ^= true;
+                (true, permissions.transferable_by_sender)
+            }
+            _ => {
+                permissions.transferable_by_recipient ^= true;
+                (false, permissions.transferable_by_recipient)
+            }
+        };
+        // Granting a sender's right takes bob, giving it up alice, and the
+        // other way around for a recipient's right
+        let (signer, other, other_index) = if senders_right != on {
+            (&alice, &bob, 1)
+        } else {
+            (&bob, &alice, 0)
+        };
+
+        let mut ix = update_permissions_ix(
+            &tt.program_id,
+            &permissions,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata,
+        )?;
+        ix.accounts[1 - other_index].is_signer = false;
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix.clone()], Some(&[other]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(transaction_error, ProgramError::MissingRequiredSignature);
+
+        ix.accounts[1 - other_index].is_signer = true;
+        ix.accounts[other_index].is_signer = false;
+        tt.bench.process_transaction(&[ix], Some(&[signer])).await?;
+
+        let stream: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+        assert_eq!(stream.ix.permissions(), permissions);
+    }
+
+    // Setting the same permissions again changes nothing
+    let before = tt.bench.get_account(&metadata).await.unwrap();
+    let mut ix = update_permissions_ix(
+        &tt.program_id,
+        &permissions,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+    )?;
+    ix.accounts[1].is_signer = false;
+    tt.bench
+        .process_transaction(&[ix.clone()], Some(&[&alice]))
+        .await?;
+    let after = tt.bench.get_account(&metadata).await.unwrap();
+    assert_eq!(before.data, after.data);
+
+    // But it still takes one of them
+    ix.accounts[0].is_signer = false;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::MissingRequiredSignature);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_cancel_permissions() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata = create_transfer_test_stream(&mut tt, &mint, false, false).await?;
+    let cancel_ix = |cancel_authority: &Keypair| {
+        cancel_stream_ix(
+            &tt.program_id,
+            &CancelStreamKeys {
+                cancel_authority: cancel_authority.pubkey(),
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata,
+                mint,
+                registry: None,
+                sender_counter: None,
+                mint_stats: None,
+            },
+        )
+    };
+
+    // By default only the sender can cancel
+    let ix = cancel_ix(&bob);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    // Alice hands the right over to bob
+    let stream: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    let permissions = StreamPermissions {
+        cancelable_by_sender: false,
+        cancelable_by_recipient: true,
+        ..stream.ix.permissions()
+    };
+    let mut ix = update_permissions_ix(
+        &tt.program_id,
+        &permissions,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+    )?;
+    ix.accounts[1].is_signer = false;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let ix = cancel_ix(&alice);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    let ix = cancel_ix(&bob);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    let stream: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert!(stream.canceled_at > 0);
+    assert_eq!(
+        token_balance(&mut tt, &alice_ass_token).await?,
+        spl_token::ui_amount_to_amount(100.0, 8)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_memo() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "Memo".to_string(),
+        ..Default::default()
+    };
+
+    let mut ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    append_memo(&mut ix, "Stream for INV-0041".as_bytes())?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 1000);
+
+    let withdraw_keys = WithdrawStreamKeys {
+        withdraw_authority: bob.pubkey(),
+        sender: alice.pubkey(),
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    tt.advance_clock_past_timestamp(stream_params.start_time as i64 + 100)
+        .await;
+
+    // Oversized and non UTF-8 memos are rejected
+    for memo in [vec![b'a'; MEMO_MAX_SIZE + 1], vec![0xff, 0xfe]] {
+        let mut ix = withdraw_stream_ix(&tt.program_id, 10, &withdraw_keys);
+        append_memo(&mut ix, &memo)?;
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[&bob]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::InvalidMemo as u32)
+        );
+    }
+
+    // So is anything else passed as the memo program
+    let mut ix = withdraw_stream_ix(&tt.program_id, 10, &withdraw_keys);
+    append_memo(&mut ix, b"INV-0042")?;
+    ix.accounts.last_mut().unwrap().pubkey = Pubkey::new_unique();
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::IncorrectProgramId);
+
+    let mut ix = withdraw_stream_ix(&tt.program_id, 10, &withdraw_keys);
+    append_memo(&mut ix, b"INV-0042")?;
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 10);
+
+    let mut ix = topup_stream_ix(
+        &tt.program_id,
+        100,
+        &alice.pubkey(),
+        &alice_ass_token,
+        &metadata,
+        &mint,
+    );
+    append_memo(&mut ix, b"INV-0043")?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.ix.deposited_amount, 1100);
+
+    let mut ix = cancel_stream_ix(
+        &tt.program_id,
+        &CancelStreamKeys {
+            cancel_authority: alice.pubkey(),
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    append_memo(&mut ix, b"INV-0044")?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_ne!(metadata_data.canceled_at, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_extend() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    // 100 per period
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "Extend".to_string(),
+        ..Default::default()
+    };
+
+    let ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let ix = extend_stream_ix(
+        &tt.program_id,
+        stream_params.end_time - 1,
+        &alice.pubkey(),
+        &alice_ass_token,
+        &metadata,
+        &mint,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::CannotShortenStream as u32)
+    );
+
+    // Only the sender can change the schedule
+    let ix = extend_stream_ix(
+        &tt.program_id,
+        stream_params.end_time + 500,
+        &bob.pubkey(),
+        &alice_ass_token,
+        &metadata,
+        &mint,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    // Five more periods at the same rate
+    let end_time = stream_params.end_time + 500;
+    let ix = extend_stream_ix(
+        &tt.program_id,
+        end_time,
+        &alice.pubkey(),
+        &alice_ass_token,
+        &metadata,
+        &mint,
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.ix.end_time, end_time);
+    assert_eq!(metadata_data.ix.deposited_amount, 1500);
+    assert_eq!(metadata_data.ix.total_amount, 1500);
+    assert_eq!(metadata_data.closable_at, end_time);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 500);
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 1500);
+
+    // The recipient gets all of it by the new end
+    tt.advance_clock_past_timestamp(end_time as i64).await;
+
+    let ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &WithdrawStreamKeys {
+            withdraw_authority: bob.pubkey(),
+            sender: alice.pubkey(),
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 1500);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_compromised_escrow() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+
+    let pristine = |mint, escrow| spl_token::state::Account {
+        mint,
+        owner: escrow,
+        state: AccountState::Initialized,
+        ..Default::default()
+    };
+    type EscrowData = fn(Pubkey, Pubkey) -> spl_token::state::Account;
+    let hostile_escrows: [(EscrowData, u64, _); 3] = [
+        // Delegate that could drain the escrow
+        (
+            |mint, escrow| spl_token::state::Account {
+                mint,
+                owner: escrow,
+                delegate: COption::Some(Pubkey::new_unique()),
+                delegated_amount: u64::MAX,
+                state: AccountState::Initialized,
+                ..Default::default()
+            },
+            sol_to_lamports(1.0),
+            StreamFlowError::EscrowAccountCompromised,
+        ),
+        // Some other mint
+        (
+            |_, escrow| spl_token::state::Account {
+                mint: Pubkey::new_unique(),
+                owner: escrow,
+                state: AccountState::Initialized,
+                ..Default::default()
+            },
+            sol_to_lamports(1.0),
+            StreamFlowError::EscrowAccountCompromised,
+        ),
+        // Fine, but would be garbage collected mid-stream
+        (pristine, 1, StreamFlowError::NotRentExempt),
+    ];
+
+    for (hostile_escrow, lamports, error) in hostile_escrows {
+        let strm_token_mint = Keypair::new();
+        let mint = strm_token_mint.pubkey();
+        let metadata_kp = Keypair::new();
+        let (escrow_tokens_pubkey, _) =
+            Pubkey::find_program_address(&[metadata_kp.pubkey().as_ref()], &program_id);
+
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        hostile_escrow(mint, escrow_tokens_pubkey).pack_into_slice(&mut data);
+        let escrow_account = Account {
+            lamports,
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        };
+
+        let mut tt = TimelockProgramTest::start_with_accounts(
+            program_id,
+            vec![(escrow_tokens_pubkey, escrow_account)],
+        )
+        .await;
+
+        let alice = clone_keypair(&tt.bench.alice);
+        let bob = clone_keypair(&tt.bench.bob);
+        let payer = clone_keypair(&tt.bench.payer);
+        let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+        let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+        tt.bench
+            .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+            .await;
+
+        tt.bench
+            .create_associated_token_account(&mint, &alice.pubkey())
+            .await;
+
+        tt.bench
+            .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+            .await;
+
+        let clock = tt.bench.get_clock().await;
+        let now = clock.unix_timestamp as u64;
+
+        let stream_params = StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            period: 100,
+            stream_name: "Compromised".to_string(),
+            ..Default::default()
+        };
+
+        let ix = create_stream_ix(
+            &tt.program_id,
+            &stream_params,
+            &CreateStreamKeys {
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata: metadata_kp.pubkey(),
+                mint,
+                registry: None,
+                sender_counter: None,
+                mint_stats: None,
+            },
+        )?;
+
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(transaction_error, ProgramError::Custom(error as u32));
+        assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+        assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 1000);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_past_start() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 2000)
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let keys = |metadata| CreateStreamKeys {
+        sender: alice.pubkey(),
+        sender_tokens: alice_ass_token,
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    // Ten minutes late is more than clock skew
+    let metadata_kp = Keypair::new();
+    let stream_params = StreamInstruction {
+        start_time: now - 600,
+        end_time: now + 1000,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "PastStart".to_string(),
+        ..Default::default()
+    };
+    let ix = create_stream_ix(&tt.program_id, &stream_params, &keys(metadata_kp.pubkey()))?;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidArgument);
+    assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+
+    // Ten seconds late is accepted, vesting from creation
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let stream_params = StreamInstruction {
+        start_time: now - 10,
+        ..stream_params
+    };
+    let ix = create_stream_ix(&tt.program_id, &stream_params, &keys(metadata))?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.ix.start_time, now - 10);
+    assert!(metadata_data.created_at >= now);
+    assert_eq!(metadata_data.effective_start(), metadata_data.created_at);
+    assert_eq!(metadata_data.available(metadata_data.created_at), 0);
+
+    // The first period ends a whole period after creation, not after the start
+    let first_unlock = metadata_data.created_at + 100;
+    assert_eq!(
+        metadata_data.next_unlock_timestamp(metadata_data.created_at),
+        Some(first_unlock)
+    );
+    tt.advance_clock_past_timestamp(first_unlock as i64).await;
+
+    let ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &WithdrawStreamKeys {
+            withdraw_authority: bob.pubkey(),
+            sender: alice.pubkey(),
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    let now = tt.bench.get_clock().await.unix_timestamp as u64;
+    let withdrawn = token_balance(&mut tt, &bob_ass_token).await?;
+    assert!(withdrawn >= 100);
+    assert_eq!(withdrawn, metadata_data.available(now));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_split() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let carol = Keypair::new();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+    let carol_ass_token = get_associated_token_address(&carol.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+    let (registry_pubkey, _) = find_registry_account(&bob.pubkey(), &tt.program_id);
+    let (new_registry_pubkey, _) = find_registry_account(&carol.pubkey(), &tt.program_id);
+    let (counter_pubkey, _) = find_sender_counter_account(&alice.pubkey(), &tt.program_id);
+    let (stats_pubkey, _) = find_mint_stats_account(&mint, &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        cliff: now + 110,
+        cliff_amount: 100,
+        transferable_by_recipient: true,
+        stream_name: "Split".to_string(),
+        ..Default::default()
+    };
+
+    let ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: Some(registry_pubkey),
+            sender_counter: Some(counter_pubkey),
+            mint_stats: Some(stats_pubkey),
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+    let original: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+
+    let new_metadata_kp = Keypair::new();
+    let new_metadata = new_metadata_kp.pubkey();
+    let (new_escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[new_metadata.as_ref()], &tt.program_id);
+    let split_keys = |recipient| SplitStreamKeys {
+        recipient,
+        metadata,
+        new_recipient: carol.pubkey(),
+        new_metadata,
+        mint,
+        registry: Some(new_registry_pubkey),
+        sender_counter: Some(counter_pubkey),
+        mint_stats: Some(stats_pubkey),
+    };
+
+    // Only the recipient can split
+    let ix = split_stream_ix(&tt.program_id, 300, &split_keys(alice.pubkey()));
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice, &new_metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+
+    // 70/30
+    let ix = split_stream_ix(&tt.program_id, 300, &split_keys(bob.pubkey()));
+    tt.bench
+        .process_transaction(&[ix], Some(&[&bob, &new_metadata_kp]))
+        .await?;
+
+    let kept: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    let split: TokenStreamData = tt.bench.get_borsh_account(&new_metadata).await;
+    assert_eq!(kept.ix.deposited_amount, 700);
+    assert_eq!(split.ix.deposited_amount, 300);
+    assert_eq!(split.recipient, carol.pubkey());
+    assert_eq!(split.recipient_tokens, carol_ass_token);
+    assert_eq!(split.sender, alice.pubkey());
+    assert_eq!(split.ix.cliff_amount, 30);
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 700);
+    assert_eq!(
+        token_balance(&mut tt, &new_escrow_tokens_pubkey).await?,
+        300
+    );
+    assert_eq!(kept.closable_at, kept.closable());
+    assert_eq!(split.closable_at, split.closable());
+
+    // The new stream is indexed and counted, its deposit was already
+    let registry_acc = tt.bench.get_account(&new_registry_pubkey).await.unwrap();
+    assert_eq!(
+        StreamRegistry::unpack(&registry_acc.data)?.streams,
+        vec![new_metadata]
+    );
+    let counter_acc = tt.bench.get_account(&counter_pubkey).await.unwrap();
+    assert_eq!(StreamCounter::unpack(&counter_acc.data)?.count, 2);
+    let stats_acc = tt.bench.get_account(&stats_pubkey).await.unwrap();
+    assert_eq!(
+        MintStats::unpack(&stats_acc.data)?,
+        MintStats {
+            total_net_deposited: 1000,
+            total_withdrawn: 0,
+            active_streams: 2,
+        }
+    );
+
+    let withdraw_keys = |recipient: &Keypair, recipient_tokens, metadata| WithdrawStreamKeys {
+        withdraw_authority: recipient.pubkey(),
+        sender: alice.pubkey(),
+        recipient: recipient.pubkey(),
+        recipient_tokens,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    // Together they unlock what the original would have, halfway and at the end
+    for ts in [now + 510, stream_params.end_time] {
+        tt.advance_clock_past_timestamp(ts as i64).await;
+
+        let ix = withdraw_stream_ix(
+            &tt.program_id,
+            0,
+            &withdraw_keys(&bob, bob_ass_token, metadata),
+        );
+        tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+        let ix = withdraw_stream_ix(
+            &tt.program_id,
+            0,
+            &withdraw_keys(&carol, carol_ass_token, new_metadata),
+        );
+        tt.bench.process_transaction(&[ix], Some(&[&carol])).await?;
+
+        let now = tt.bench.get_clock().await.unix_timestamp as u64;
+        assert_eq!(
+            token_balance(&mut tt, &bob_ass_token).await?
+                + token_balance(&mut tt, &carol_ass_token).await?,
+            original.available(now)
+        );
+    }
+
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 700);
+    assert_eq!(token_balance(&mut tt, &carol_ass_token).await?, 300);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_split_vested() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let carol = Keypair::new();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+    let carol_ass_token = get_associated_token_address(&carol.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        transferable_by_recipient: true,
+        stream_name: "SplitVested".to_string(),
+        ..Default::default()
+    };
+
+    let ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let new_metadata_kp = Keypair::new();
+    let new_metadata = new_metadata_kp.pubkey();
+    let (new_escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[new_metadata.as_ref()], &tt.program_id);
+    let split_keys = SplitStreamKeys {
+        recipient: bob.pubkey(),
+        metadata,
+        new_recipient: carol.pubkey(),
+        new_metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+    let withdraw_keys = |recipient: &Keypair, recipient_tokens, metadata| WithdrawStreamKeys {
+        withdraw_authority: recipient.pubkey(),
+        sender: alice.pubkey(),
+        recipient: recipient.pubkey(),
+        recipient_tokens,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    // 400 vested, which has to be withdrawn before splitting
+    tt.advance_clock_past_timestamp((now + 410) as i64).await;
+
+    let ix = split_stream_ix(&tt.program_id, 300, &split_keys);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&bob, &new_metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::VestedNotWithdrawn as u32)
+    );
+
+    let ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &withdraw_keys(&bob, bob_ass_token, metadata),
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 400);
+
+    let ix = split_stream_ix(&tt.program_id, 300, &split_keys);
+    tt.bench
+        .process_transaction(&[ix], Some(&[&bob, &new_metadata_kp]))
+        .await?;
+
+    // Only the unvested 180 of the 300 moved, nothing vested changed hands
+    let kept: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    let split: TokenStreamData = tt.bench.get_borsh_account(&new_metadata).await;
+    assert_eq!(kept.ix.deposited_amount + split.ix.deposited_amount, 1000);
+    assert_eq!(kept.withdrawn_amount, 280);
+    assert_eq!(split.withdrawn_amount, 120);
+    assert_eq!(token_balance(&mut tt, &escrow_tokens_pubkey).await?, 420);
+    assert_eq!(
+        token_balance(&mut tt, &new_escrow_tokens_pubkey).await?,
+        180
+    );
+
+    let now = tt.bench.get_clock().await.unix_timestamp as u64;
+    assert_eq!(kept.available(now), 0);
+    assert_eq!(split.available(now), 0);
+
+    // Both can be withdrawn to completion on their own
+    tt.advance_clock_past_timestamp(stream_params.end_time as i64)
+        .await;
+
+    let ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &withdraw_keys(&carol, carol_ass_token, new_metadata),
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&carol])).await?;
+    let ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &withdraw_keys(&bob, bob_ass_token, metadata),
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 820);
+    assert_eq!(token_balance(&mut tt, &carol_ass_token).await?, 180);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_cancel_and_close() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+        .await;
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "CancelAndClose".to_string(),
+        ..Default::default()
+    };
+
+    let ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+    tt.bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_rent = tt.bench.get_account(&metadata).await.unwrap().lamports;
+    let escrow_rent = tt
+        .bench
+        .get_account(&escrow_tokens_pubkey)
+        .await
+        .unwrap()
+        .lamports;
+    let alice_lamports = tt
+        .bench
+        .get_account(&alice.pubkey())
+        .await
+        .unwrap()
+        .lamports;
+
+    tt.advance_clock_past_timestamp((now + 310) as i64).await;
+
+    let ix = cancel_and_close_stream_ix(
+        &tt.program_id,
+        &CancelStreamKeys {
+            cancel_authority: alice.pubkey(),
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    // Settled like a cancel, with both rents back in the same transaction
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 300);
+    assert_eq!(token_balance(&mut tt, &alice_ass_token).await?, 700);
+    assert!(tt.bench.get_account(&escrow_tokens_pubkey).await.is_none());
+    assert!(tt.bench.get_account(&metadata).await.is_none());
+    assert_eq!(
+        tt.bench
+            .get_account(&alice.pubkey())
+            .await
+            .unwrap()
+            .lamports,
+        alice_lamports + metadata_rent + escrow_rent
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_cancel_and_close_labels() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata = create_transfer_test_stream(&mut tt, &mint, false, false).await?;
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+    let (labels_pubkey, _) = find_labels_account(&metadata, &tt.program_id);
+
+    let labels = StreamLabels {
+        keys: vec!["invoice".to_string()],
+        values: vec!["INV-0042".to_string()],
+    };
+    let ix = set_labels_ix(&tt.program_id, &labels, &alice.pubkey(), &metadata)?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let labels_rent = tt.bench.get_account(&labels_pubkey).await.unwrap().lamports;
+    let metadata_rent = tt.bench.get_account(&metadata).await.unwrap().lamports;
+    let escrow_rent = tt
+        .bench
+        .get_account(&escrow_tokens_pubkey)
+        .await
+        .unwrap()
+        .lamports;
+    let alice_lamports = tt
+        .bench
+        .get_account(&alice.pubkey())
+        .await
+        .unwrap()
+        .lamports;
+
+    let ix = cancel_and_close_stream_ix(
+        &tt.program_id,
+        &CancelStreamKeys {
+            cancel_authority: alice.pubkey(),
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    // The labels go with the metadata, their rent back to the sender too
+    assert!(tt.bench.get_account(&metadata).await.is_none());
+    assert!(tt.bench.get_account(&labels_pubkey).await.is_none());
+    assert_eq!(
+        tt.bench
+            .get_account(&alice.pubkey())
+            .await
+            .unwrap()
+            .lamports,
+        alice_lamports + labels_rent + metadata_rent + escrow_rent
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_collect() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 1000)
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let metadata = create_options_test_stream(&mut tt, &mint, &StreamOptions::default()).await?;
+    let (escrow_tokens_pubkey, _) =
+        Pubkey::find_program_address(&[metadata.as_ref()], &tt.program_id);
+    let (labels_pubkey, _) = find_labels_account(&metadata, &tt.program_id);
+
+    let labels = StreamLabels {
+        keys: vec!["invoice".to_string()],
+        values: vec!["INV-0042".to_string()],
+    };
+    let ix = set_labels_ix(&tt.program_id, &labels, &alice.pubkey(), &metadata)?;
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+
+    let keys = WithdrawStreamKeys {
+        withdraw_authority: bob.pubkey(),
+        sender: alice.pubkey(),
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        metadata,
+        mint,
+        registry: None,
+        sender_counter: None,
+        mint_stats: None,
+    };
+
+    // Midway, collecting only withdraws what's vested
+    tt.advance_clock_past_timestamp((now + 510) as i64).await;
+
+    let ix = collect_stream_ix(&tt.program_id, &keys);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    let collected = token_balance(&mut tt, &bob_ass_token).await?;
+    assert!(collected > 0 && collected < 1000);
+    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata).await;
+    assert_eq!(metadata_data.withdrawn_amount, collected);
+    assert!(tt.bench.get_account(&escrow_tokens_pubkey).await.is_some());
+    assert!(tt.bench.get_account(&labels_pubkey).await.is_some());
+
+    // Once complete, the rest arrives and everything closes at once
+    tt.advance_clock_past_timestamp((now + 1100) as i64).await;
+
+    let labels_rent = tt.bench.get_account(&labels_pubkey).await.unwrap().lamports;
+    let metadata_rent = tt.bench.get_account(&metadata).await.unwrap().lamports;
+    let escrow_rent = tt
+        .bench
+        .get_account(&escrow_tokens_pubkey)
+        .await
+        .unwrap()
+        .lamports;
+    let alice_lamports = tt
+        .bench
+        .get_account(&alice.pubkey())
+        .await
+        .unwrap()
+        .lamports;
+
+    let ix = collect_stream_ix(&tt.program_id, &keys);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+
+    assert_eq!(token_balance(&mut tt, &bob_ass_token).await?, 1000);
+    assert!(tt.bench.get_account(&escrow_tokens_pubkey).await.is_none());
+    assert!(tt.bench.get_account(&metadata).await.is_none());
+    assert!(tt.bench.get_account(&labels_pubkey).await.is_none());
+    assert_eq!(
+        tt.bench
+            .get_account(&alice.pubkey())
+            .await
+            .unwrap()
+            .lamports,
+        alice_lamports + labels_rent + metadata_rent + escrow_rent
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_frozen_escrow() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let metadata = Keypair::new().pubkey();
+    let (escrow_tokens_pubkey, _) = Pubkey::find_program_address(&[metadata.as_ref()], &program_id);
+    let (alice, bob) = (Keypair::new(), Keypair::new());
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    // A stream whose escrow the mint's freeze authority has frozen
+    let stream = TokenStreamData {
+        magic: STRM_MAGIC,
+        closable_at: u64::MAX,
+        sender: alice.pubkey(),
+        sender_tokens: alice_ass_token,
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        mint,
+        escrow_tokens: escrow_tokens_pubkey,
+        ix: StreamInstruction {
+            start_time: 1,
+            end_time: u64::MAX / 2,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            stream_name: "Frozen".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let metadata_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data: stream.try_to_vec()?,
+        owner: program_id,
+        ..Account::default()
+    };
+
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint,
+        owner: escrow_tokens_pubkey,
+        amount: 1000,
+        state: AccountState::Frozen,
+        ..Default::default()
+    }
+    .pack_into_slice(&mut data);
+    let escrow_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data,
+        owner: spl_token::id(),
+        ..Account::default()
+    };
+
+    let mut tt = TimelockProgramTest::start_with_accounts(
+        program_id,
+        vec![
+            (metadata, metadata_account),
+            (escrow_tokens_pubkey, escrow_account),
+        ],
+    )
+    .await;
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    let withdraw_ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &WithdrawStreamKeys {
+            withdraw_authority: bob.pubkey(),
+            sender: alice.pubkey(),
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    let cancel_ix = cancel_stream_ix(
+        &tt.program_id,
+        &CancelStreamKeys {
+            cancel_authority: alice.pubkey(),
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+
+    for (ix, signer) in [(withdraw_ix, &bob), (cancel_ix, &alice)] {
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[signer]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::EscrowFrozen as u32)
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_escrow_mismatch() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let metadata = Keypair::new().pubkey();
+    let (escrow_tokens_pubkey, _) = Pubkey::find_program_address(&[metadata.as_ref()], &program_id);
+    let (alice, bob) = (Keypair::new(), Keypair::new());
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    // A stream pointing at another escrow than the one derived for it
+    let stream = TokenStreamData {
+        magic: STRM_MAGIC,
+        closable_at: u64::MAX,
+        sender: alice.pubkey(),
+        sender_tokens: alice_ass_token,
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        mint,
+        escrow_tokens: Pubkey::new_unique(),
+        ix: StreamInstruction {
+            start_time: 1,
+            end_time: u64::MAX / 2,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            stream_name: "Mismatched".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let metadata_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data: stream.try_to_vec()?,
+        owner: program_id,
+        ..Account::default()
+    };
+
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint,
+        owner: escrow_tokens_pubkey,
+        amount: 1000,
+        state: AccountState::Initialized,
+        ..Default::default()
+    }
+    .pack_into_slice(&mut data);
+    let escrow_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data,
+        owner: spl_token::id(),
+        ..Account::default()
+    };
+
+    let mut tt = TimelockProgramTest::start_with_accounts(
+        program_id,
+        vec![
+            (metadata, metadata_account),
+            (escrow_tokens_pubkey, escrow_account),
+        ],
+    )
+    .await;
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    let withdraw_ix = withdraw_stream_ix(
+        &tt.program_id,
+        0,
+        &WithdrawStreamKeys {
+            withdraw_authority: bob.pubkey(),
+            sender: alice.pubkey(),
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+    let cancel_ix = cancel_stream_ix(
+        &tt.program_id,
+        &CancelStreamKeys {
+            cancel_authority: alice.pubkey(),
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+
+    for (ix, signer) in [(withdraw_ix, &bob), (cancel_ix, &alice)] {
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[signer]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::EscrowMismatch as u32)
+        );
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_cancel_sender_tokens_mismatch() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let metadata = Keypair::new().pubkey();
+    let (escrow_tokens_pubkey, _) = Pubkey::find_program_address(&[metadata.as_ref()], &program_id);
+    let (alice, bob) = (Keypair::new(), Keypair::new());
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    // A stream refunding another token account than the one given on cancel
+    let stream = TokenStreamData {
+        magic: STRM_MAGIC,
+        closable_at: u64::MAX,
+        sender: alice.pubkey(),
+        sender_tokens: Pubkey::new_unique(),
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        mint,
+        escrow_tokens: escrow_tokens_pubkey,
+        ix: StreamInstruction {
+            start_time: 1,
+            end_time: u64::MAX / 2,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            stream_name: "Mismatched".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let metadata_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data: stream.try_to_vec()?,
+        owner: program_id,
+        ..Account::default()
+    };
+
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint,
+        owner: escrow_tokens_pubkey,
+        amount: 1000,
+        state: AccountState::Initialized,
+        ..Default::default()
+    }
+    .pack_into_slice(&mut data);
+    let escrow_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data,
+        owner: spl_token::id(),
+        ..Account::default()
+    };
+
+    let mut tt = TimelockProgramTest::start_with_accounts(
+        program_id,
+        vec![
+            (metadata, metadata_account),
+            (escrow_tokens_pubkey, escrow_account),
+        ],
+    )
+    .await;
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    let cancel_ix = cancel_stream_ix(
+        &tt.program_id,
+        &CancelStreamKeys {
+            cancel_authority: alice.pubkey(),
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata,
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::SenderTokensMismatch as u32)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_topup_past_max_duration() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let metadata = Keypair::new().pubkey();
+    let (escrow_tokens_pubkey, _) = Pubkey::find_program_address(&[metadata.as_ref()], &program_id);
+    let alice = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+    // A recurring stream releasing a token a second, far in the future
+    let start_time = 4_000_000_000;
+    let stream = TokenStreamData {
+        magic: STRM_MAGIC,
+        closable_at: start_time + 1001,
+        sender: alice.pubkey(),
+        sender_tokens: alice_ass_token,
+        mint,
+        escrow_tokens: escrow_tokens_pubkey,
+        ix: StreamInstruction {
+            start_time,
+            end_time: start_time + 1000,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            release_rate: 1,
+            stream_name: "Recurring".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let metadata_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data: stream.try_to_vec()?,
+        owner: program_id,
+        ..Account::default()
+    };
+
+    let mut data = vec![0; spl_token::state::Account::LEN];
+    spl_token::state::Account {
+        mint,
+        owner: escrow_tokens_pubkey,
+        amount: 1000,
+        state: AccountState::Initialized,
+        ..Default::default()
+    }
+    .pack_into_slice(&mut data);
+    let escrow_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data,
+        owner: spl_token::id(),
+        ..Account::default()
+    };
+
+    let mut tt = TimelockProgramTest::start_with_accounts(
+        program_id,
+        vec![
+            (metadata, metadata_account),
+            (escrow_tokens_pubkey, escrow_account),
+        ],
+    )
+    .await;
+
+    let payer = clone_keypair(&tt.bench.payer);
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, MAX_DURATION)
+        .await;
+
+    let ix = topup_stream_ix(
+        &tt.program_id,
+        MAX_DURATION,
+        &alice.pubkey(),
+        &alice_ass_token,
+        &metadata,
+        &mint,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::DurationTooLong as u32)
+    );
+    let account = tt.bench.get_account(&metadata).await.unwrap();
+    assert_eq!(TokenStreamData::unpack(&account.data)?, stream);
+    assert_eq!(
+        token_balance(&mut tt, &alice_ass_token).await?,
+        MAX_DURATION
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_create_twice() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let metadata_kp = Keypair::new();
+    let (alice, bob) = (Keypair::new(), Keypair::new());
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    // The stream a previous create already wrote
+    let existing = TokenStreamData {
+        magic: STRM_MAGIC,
+        sender: alice.pubkey(),
+        sender_tokens: alice_ass_token,
+        recipient: bob.pubkey(),
+        recipient_tokens: bob_ass_token,
+        mint,
+        ix: StreamInstruction {
+            start_time: 1,
+            end_time: 1001,
+            deposited_amount: 1000,
+            total_amount: 1000,
+            period: 100,
+            stream_name: "Existing".to_string(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let metadata_account = Account {
+        lamports: sol_to_lamports(1.0),
+        data: existing.try_to_vec()?,
+        owner: program_id,
+        ..Account::default()
+    };
+
+    let mut tt = TimelockProgramTest::start_with_accounts(
+        program_id,
+        vec![(metadata_kp.pubkey(), metadata_account)],
+    )
+    .await;
+
+    let now = tt.bench.get_clock().await.unix_timestamp as u64;
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        ..existing.ix.clone()
+    };
+
+    let ix = create_stream_ix(
+        &tt.program_id,
+        &stream_params,
+        &CreateStreamKeys {
+            sender: alice.pubkey(),
+            sender_tokens: alice_ass_token,
+            recipient: bob.pubkey(),
+            recipient_tokens: bob_ass_token,
+            metadata: metadata_kp.pubkey(),
+            mint,
+            registry: None,
+            sender_counter: None,
+            mint_stats: None,
+        },
+    )?;
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(transaction_error, ProgramError::AccountAlreadyInitialized);
+    let account = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
+    assert_eq!(TokenStreamData::unpack(&account.data)?, existing);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_create_from_frozen_or_delegated() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let bob = Keypair::new();
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    // Senders with enough tokens, but frozen or with a delegate on them
+    let senders = [
+        (Keypair::new(), AccountState::Frozen, COption::None, 0),
+        (
+            Keypair::new(),
+            AccountState::Initialized,
+            COption::Some(Pubkey::new_unique()),
+            1,
+        ),
+    ];
+    let mut accounts = vec![];
+    for (sender, state, delegate, delegated_amount) in &senders {
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account {
+            mint,
+            owner: sender.pubkey(),
+            amount: 1000,
+            state: *state,
+            delegate: *delegate,
+            delegated_amount: *delegated_amount,
+            ..Default::default()
+        }
+        .pack_into_slice(&mut data);
+        let account = Account {
+            lamports: sol_to_lamports(1.0),
+            data,
+            owner: spl_token::id(),
+            ..Account::default()
+        };
+        accounts.push((
+            get_associated_token_address(&sender.pubkey(), &mint),
+            account,
+        ));
+    }
+
+    let mut tt = TimelockProgramTest::start_with_accounts(program_id, accounts).await;
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    let now = tt.bench.get_clock().await.unix_timestamp as u64;
+    let stream_params = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: 1000,
+        total_amount: 1000,
+        period: 100,
+        stream_name: "Frozen sender".to_string(),
+        ..Default::default()
+    };
+
+    for (sender, ..) in &senders {
+        let metadata_kp = Keypair::new();
+        let ix = create_stream_ix(
+            &tt.program_id,
+            &stream_params,
+            &CreateStreamKeys {
+                sender: sender.pubkey(),
+                sender_tokens: get_associated_token_address(&sender.pubkey(), &mint),
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata: metadata_kp.pubkey(),
+                mint,
+                registry: None,
+                sender_counter: None,
+                mint_stats: None,
+            },
+        )?;
+
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[sender, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::InvalidSenderAccount as u32)
+        );
+        assert!(tt.bench.get_account(&metadata_kp.pubkey()).await.is_none());
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_mint_stats() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+    let (registry_pubkey, _) = find_registry_account(&bob.pubkey(), &tt.program_id);
+    let (counter_pubkey, _) = find_sender_counter_account(&alice.pubkey(), &tt.program_id);
+    let (stats_pubkey, _) = find_mint_stats_account(&mint, &tt.program_id);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(&mint, &payer, &alice_ass_token, 3000)
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let mut streams = vec![];
+    for deposited_amount in [1000, 2000] {
+        let metadata_kp = Keypair::new();
+        let stream_params = StreamInstruction {
+            start_time: now + 5,
+            end_time: now + 1005,
+            deposited_amount,
+            total_amount: deposited_amount,
+            period: 100,
+            stream_name: "Counted".to_string(),
+            ..Default::default()
+        };
+
+        let ix = create_stream_ix(
+            &tt.program_id,
+            &stream_params,
+            &CreateStreamKeys {
+                sender: alice.pubkey(),
+                sender_tokens: alice_ass_token,
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata: metadata_kp.pubkey(),
+                mint,
+                registry: Some(registry_pubkey),
+                sender_counter: Some(counter_pubkey),
+                mint_stats: Some(stats_pubkey),
+            },
+        )?;
+        tt.bench
+            .process_transaction(&[ix], Some(&[&alice, &metadata_kp]))
+            .await?;
+
+        streams.push(metadata_kp.pubkey());
+    }
+
+    let stats_acc = tt.bench.get_account(&stats_pubkey).await.unwrap();
+    assert_eq!(stats_acc.owner, tt.program_id);
+    assert_eq!(
+        MintStats::unpack(&stats_acc.data)?,
+        MintStats {
+            total_net_deposited: 3000,
+            total_withdrawn: 0,
+            active_streams: 2,
+        }
+    );
+
+    tt.advance_clock_past_timestamp(now as i64 + 305).await;
+
+    // Counted when the stats are passed, and nothing breaks without them
+    for (metadata, mint_stats) in [(streams[0], Some(stats_pubkey)), (streams[1], None)] {
+        let ix = withdraw_stream_ix(
+            &tt.program_id,
+            0,
+            &WithdrawStreamKeys {
+                withdraw_authority: bob.pubkey(),
+                sender: alice.pubkey(),
+                recipient: bob.pubkey(),
+                recipient_tokens: bob_ass_token,
+                metadata,
+                mint,
+                registry: Some(registry_pubkey),
+                sender_counter: Some(counter_pubkey),
+                mint_stats,
+            },
+        );
+        tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    }
+
+    let withdrawn = token_balance(&mut tt, &bob_ass_token).await?;
+    let stats_acc = tt.bench.get_account(&stats_pubkey).await.unwrap();
+    let stats = MintStats::unpack(&stats_acc.data)?;
+    assert_eq!(stats.total_withdrawn, withdrawn / 3);
+    assert_eq!(stats.locked(), 3000 - withdrawn / 3);
+    assert_eq!(stats.active_streams, 2);
+
+    Ok(())
+}
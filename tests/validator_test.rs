@@ -0,0 +1,37 @@
+// The examples/full_lifecycle flow against a running `solana-test-validator`
+// with the program deployed, e.g.
+//
+//   solana-test-validator --bpf-program <PROGRAM_ID> target/deploy/streamflow_timelock.so
+//   TIMELOCK_PROGRAM_ID=<PROGRAM_ID> cargo test --features test-validator --test validator_test
+//
+// TIMELOCK_RPC_URL and TIMELOCK_PAYER override the local validator URL and
+// the default CLI keypair, which the test validator funds.
+#![cfg(feature = "test-validator")]
+use std::{env, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use solana_sdk::{pubkey::Pubkey, signer::keypair::read_keypair_file};
+
+#[allow(dead_code)]
+#[path = "../examples/full_lifecycle.rs"]
+mod full_lifecycle;
+
+#[test]
+fn full_lifecycle_on_test_validator() -> Result<()> {
+    let rpc_url =
+        env::var("TIMELOCK_RPC_URL").unwrap_or_else(|_| "http://localhost:8899".to_string());
+    let program_id = Pubkey::from_str(&env::var("TIMELOCK_PROGRAM_ID")?)?;
+    let payer_path = env::var("TIMELOCK_PAYER")
+        .unwrap_or_else(|_| format!("{}/.config/solana/id.json", env::var("HOME").unwrap()));
+    let payer = read_keypair_file(payer_path).map_err(|e| anyhow!("{}", e))?;
+
+    let reconciliation = full_lifecycle::run(&rpc_url, &payer, &program_id)?;
+    reconciliation.print();
+
+    assert!(reconciliation.withdrawn[0] > 0);
+    assert!(reconciliation.withdrawn[1] > 0);
+    assert!(reconciliation.refunded > 0);
+    assert!(reconciliation.balances());
+
+    Ok(())
+}